@@ -1,5 +1,4 @@
 extern crate drain_rs;
-use grok;
 use std::env;
 use std::fs;
 use std::io::{BufRead, BufReader};
@@ -27,12 +26,10 @@ pub fn main() {
         .nth(1)
         .expect("Missing required argument file name");
     let reader: Box<dyn BufRead> = Box::new(BufReader::new(fs::File::open(filename).unwrap()));
-    for line_result in reader.lines() {
-        if let Ok(line) = line_result {
-            if let Some(log_cluster) = drain.add_log_line(line.as_str()) {
-                // println!("log_line    => {}", line);
-                // println!("log_cluster => {}", log_cluster);
-            }
+    for line in reader.lines().map_while(Result::ok) {
+        if let Some(_log_cluster) = drain.add_log_line(line.as_str()) {
+            // println!("log_line    => {}", line);
+            // println!("log_cluster => {}", log_cluster);
         }
     }
     // drain.log_groups().iter().for_each(|f| println!("{}", *f));
@@ -1,6 +1,5 @@
 extern crate drain_rs;
 use drain_rs::grok_generator::GrokGenerator;
-use grok;
 use std::env;
 use std::fs;
 use std::io::{BufRead, BufReader};
@@ -28,13 +27,11 @@ pub fn main() {
         .nth(1)
         .expect("Missing required argument file name");
     let reader: Box<dyn BufRead> = Box::new(BufReader::new(fs::File::open(filename).unwrap()));
-    for line_result in reader.lines() {
-        if let Ok(line) = line_result {
-            if let Some(_log_cluster) = drain.add_log_line(line.as_str()) {
-                // println!("log_line    => {}", line);
-                // println!("log_cluster => {}", log_cluster);
-                // log_cluster.extract_variables()
-            }
+    for line in reader.lines().map_while(Result::ok) {
+        if let Some(_log_cluster) = drain.add_log_line(line.as_str()) {
+            // println!("log_line    => {}", line);
+            // println!("log_cluster => {}", log_cluster);
+            // log_cluster.extract_variables()
         }
     }
     // drain.log_groups().iter().for_each(|f| println!("{}", *f));
@@ -49,6 +46,8 @@ pub fn main() {
 
     drain.log_groups().iter().for_each(|log_cluster| {
         // println!("~~ {}", log_cluster.as_detailed_string());
-        log_cluster.detect_best_grok(&grok_generator);
+        if let Some(pattern) = log_cluster.detect_best_grok(&grok_generator) {
+            println!("{}", pattern);
+        }
     });
 }
@@ -1,9 +1,4 @@
 extern crate drain_rs;
-use grok;
-use std::env;
-use std::fs;
-use std::io::{BufRead, BufReader};
-use std::{fmt, fmt::Display, fmt::Formatter};
 
 // impl<'a> grok::Matches<'a> {
 //     pub fn as_string(self) {}
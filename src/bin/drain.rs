@@ -0,0 +1,519 @@
+//! Cluster the log lines of files, or of stdin, with the drain algorithm
+use drain_rs::{evaluate, DrainTree, Evaluation, GroundTruth, LogCluster, MultilineAggregator};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::process;
+
+const USAGE: &str = "Usage: drain [OPTIONS] [FILE]...
+       drain evaluate --truth <CSV> [OPTIONS] [FILE]...
+
+Cluster the log lines of the given files, or of stdin when no file or `-` is given,
+and print the clusters sorted by their number of log lines.
+
+With `evaluate`, score the clusters against the labeled events of a Loghub structured
+CSV file, which has an EventId and an EventTemplate column and a row per log line.
+
+Options:
+      --max-depth <N>          Depth of the prefix tree [default: 5]
+      --max-children <N>       Maximum number of children of a prefix node [default: 100]
+      --min-similarity <F>     Minimum similarity for a line to join a cluster [default: 0.5]
+      --filter-pattern <GROK>  Pattern whose matches are variable tokens, can be repeated
+      --mask <LABEL>=<GROK>    Replace the matches of the pattern by <LABEL> before splitting
+                               the text into tokens, e.g. IP=%{IP}, can be repeated, the
+                               masks are applied in order
+      --exact-numeric-prefixes Give the prefix tokens containing a digit their own branches
+                               of the tree, instead of a shared wildcard branch
+      --log-pattern <GROK>     Pattern of the whole log line, requires --field
+      --field <NAME>           Field of --log-pattern holding the text to cluster
+      --json-message <POINTER> Read the lines as JSON and cluster the field at the JSON
+                               pointer, e.g. /msg, the other fields become cluster metadata
+      --partition-field <FIELD>
+                               Field of --log-pattern, or JSON pointer with --json-message,
+                               whose values get their own clusters, e.g. level or
+                               /service, can be repeated
+      --timestamp-field <FIELD>
+                               Field of --log-pattern, or JSON pointer with --json-message,
+                               holding the time of the log lines, which is otherwise the
+                               time they are read at
+      --timestamp-format <FORMAT>
+                               chrono format of --timestamp-field, e.g. '%Y-%m-%d %H:%M:%S'
+                               [default: rfc3339]
+      --variable-stats         Keep the type and the distribution of the values of every
+                               wildcard, shown by the JSON format
+      --start-pattern <GROK>   Pattern of the lines starting a multi-line event, the other
+                               lines continue the current event
+      --indentation            Indented lines, and unindented stack trace lines such as
+                               `Caused by: ...`, continue the current multi-line event
+      --signature              Cluster multi-line events on their first line followed by
+                               the exception types they name
+      --format <FORMAT>        Output format: text, json or csv [default: text]
+      --load <PATH>            Resume from a tree snapshot saved with --save, or a JSON
+                               tree, the tree options cannot be given along with it
+      --save <PATH>            Save a snapshot of the tree once every line is processed
+      --truth <CSV>            Structured CSV labeling every log line, for `evaluate`
+  -h, --help                   Print this help";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(Debug)]
+struct Options {
+    evaluate: bool,
+    truth: Option<String>,
+    files: Vec<String>,
+    max_depth: Option<u16>,
+    max_children: Option<u16>,
+    min_similarity: Option<f32>,
+    filter_patterns: Vec<String>,
+    masks: Vec<(String, String)>,
+    exact_numeric_prefixes: bool,
+    log_pattern: Option<String>,
+    field: Option<String>,
+    json_message: Option<String>,
+    partition_fields: Vec<String>,
+    timestamp_field: Option<String>,
+    timestamp_format: Option<String>,
+    variable_stats: bool,
+    start_pattern: Option<String>,
+    indentation: bool,
+    signature: bool,
+    format: Format,
+    load: Option<String>,
+    save: Option<String>,
+}
+
+impl Options {
+    fn has_tree_options(&self) -> bool {
+        self.max_depth.is_some()
+            || self.max_children.is_some()
+            || self.min_similarity.is_some()
+            || !self.filter_patterns.is_empty()
+            || !self.masks.is_empty()
+            || self.exact_numeric_prefixes
+            || self.log_pattern.is_some()
+            || self.json_message.is_some()
+            || !self.partition_fields.is_empty()
+            || self.timestamp_field.is_some()
+            || self.variable_stats
+    }
+
+    fn is_multiline(&self) -> bool {
+        self.start_pattern.is_some() || self.indentation || self.signature
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value [{}] for {}", value, option))
+}
+
+/// Parse the command line arguments, `None` when the help is requested
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options {
+        evaluate: false,
+        truth: None,
+        files: vec![],
+        max_depth: None,
+        max_children: None,
+        min_similarity: None,
+        filter_patterns: vec![],
+        masks: vec![],
+        exact_numeric_prefixes: false,
+        log_pattern: None,
+        field: None,
+        json_message: None,
+        partition_fields: vec![],
+        timestamp_field: None,
+        timestamp_format: None,
+        variable_stats: false,
+        start_pattern: None,
+        indentation: false,
+        signature: false,
+        format: Format::Text,
+        load: None,
+        save: None,
+    };
+    let mut args = args.peekable();
+    if args.peek().map(|a| a.as_str()) == Some("evaluate") {
+        args.next();
+        options.evaluate = true;
+    }
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(None);
+        }
+        if !arg.starts_with("--") {
+            options.files.push(arg);
+            continue;
+        }
+        // both `--option value` and `--option=value` are accepted
+        let (option, inline_value) = match arg.find('=') {
+            Some(i) => (arg[..i].to_string(), Some(arg[i + 1..].to_string())),
+            None => (arg.clone(), None),
+        };
+        let mut value = || match &inline_value {
+            Some(v) => Ok(v.clone()),
+            None => args
+                .next()
+                .ok_or_else(|| format!("missing value for {}", option)),
+        };
+        match option.as_str() {
+            "--max-depth" => options.max_depth = Some(parse_value(&option, value()?)?),
+            "--max-children" => options.max_children = Some(parse_value(&option, value()?)?),
+            "--min-similarity" => options.min_similarity = Some(parse_value(&option, value()?)?),
+            "--filter-pattern" => options.filter_patterns.push(value()?),
+            "--mask" => {
+                let mask = value()?;
+                match mask.split_once('=') {
+                    Some((label, pattern)) if !label.is_empty() && !pattern.is_empty() => {
+                        options.masks.push((label.to_string(), pattern.to_string()))
+                    }
+                    _ => return Err(format!("invalid value [{}] for --mask", mask)),
+                }
+            }
+            "--exact-numeric-prefixes" => options.exact_numeric_prefixes = true,
+            "--log-pattern" => options.log_pattern = Some(value()?),
+            "--field" => options.field = Some(value()?),
+            "--json-message" => options.json_message = Some(value()?),
+            "--partition-field" => options.partition_fields.push(value()?),
+            "--timestamp-field" => options.timestamp_field = Some(value()?),
+            "--timestamp-format" => options.timestamp_format = Some(value()?),
+            "--variable-stats" => options.variable_stats = true,
+            "--start-pattern" => options.start_pattern = Some(value()?),
+            "--indentation" => options.indentation = true,
+            "--signature" => options.signature = true,
+            "--format" => {
+                options.format = match value()?.as_str() {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    "csv" => Format::Csv,
+                    other => return Err(format!("unknown format [{}]", other)),
+                }
+            }
+            "--load" => options.load = Some(value()?),
+            "--save" => options.save = Some(value()?),
+            "--truth" => options.truth = Some(value()?),
+            _ => return Err(format!("unknown option [{}]", option)),
+        }
+    }
+    if options.log_pattern.is_some() != options.field.is_some() {
+        return Err("--log-pattern and --field must be given together".to_string());
+    }
+    if !options.partition_fields.is_empty()
+        && options.json_message.is_none()
+        && options.log_pattern.is_none()
+    {
+        return Err("--partition-field requires --log-pattern or --json-message".to_string());
+    }
+    if options.timestamp_format.is_some() && options.timestamp_field.is_none() {
+        return Err("--timestamp-format requires --timestamp-field".to_string());
+    }
+    if options.evaluate != options.truth.is_some() {
+        return Err("--truth is required by, and only accepted by, evaluate".to_string());
+    }
+    if options.evaluate && options.is_multiline() {
+        return Err("evaluate scores single log lines, not multi-line events".to_string());
+    }
+    if options.load.is_some() && options.has_tree_options() {
+        return Err("the tree options come from the state given to --load".to_string());
+    }
+    Ok(Some(options))
+}
+
+fn build_tree(options: &Options) -> Result<DrainTree, Box<dyn Error>> {
+    let mut drain = match &options.load {
+        Some(path) => DrainTree::load_from(BufReader::new(
+            File::open(path).map_err(|e| format!("cannot open [{}]: {}", path, e))?,
+        ))
+        .map_err(|e| format!("cannot load [{}]: {}", path, e))?,
+        None => {
+            let mut drain = DrainTree::new()
+                .filter_patterns(options.filter_patterns.iter().map(|p| p.as_str()).collect());
+            if options.exact_numeric_prefixes {
+                drain = drain.masking_rules(vec![]);
+            }
+            for (label, pattern) in &options.masks {
+                drain = drain.mask(pattern, label);
+            }
+            if let Some(max_depth) = options.max_depth {
+                drain = drain.max_depth(max_depth);
+            }
+            if let Some(max_children) = options.max_children {
+                drain = drain.max_children(max_children);
+            }
+            if let Some(min_similarity) = options.min_similarity {
+                drain = drain.min_similarity(min_similarity);
+            }
+            if let (Some(log_pattern), Some(field)) = (&options.log_pattern, &options.field) {
+                drain = drain.log_pattern(log_pattern, field);
+            }
+            if let Some(json_message) = &options.json_message {
+                drain = drain.json_message(json_message);
+            }
+            if let Some(field) = &options.timestamp_field {
+                let format = options.timestamp_format.as_deref().unwrap_or("rfc3339");
+                drain = drain.timestamp_field(field, format);
+            }
+            if options.variable_stats {
+                drain = drain.variable_statistics();
+            }
+            drain.partition_fields(
+                options
+                    .partition_fields
+                    .iter()
+                    .map(|f| f.as_str())
+                    .collect(),
+            )
+        }
+    };
+    drain.try_build_patterns(&mut grok::Grok::with_patterns())?;
+    Ok(drain)
+}
+
+/// Quote a CSV field when it holds a separator, a quote or a line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// The statistics of the wildcards of the cluster, as JSON objects
+fn variables(cluster: &LogCluster) -> Vec<serde_json::Value> {
+    cluster
+        .variable_stats()
+        .into_iter()
+        .map(|(position, stats)| {
+            serde_json::json!({
+                "position": position,
+                "type": stats.variable_type(),
+                "count": stats.count(),
+                "min": stats.min(),
+                "max": stats.max(),
+                "mean": stats.mean(),
+                "p50": stats.percentile(50.0),
+                "p90": stats.percentile(90.0),
+                "p99": stats.percentile(99.0),
+            })
+        })
+        .collect()
+}
+
+/// The partition values of the cluster as `name=value` pairs separated by spaces
+fn partition(cluster: &LogCluster) -> String {
+    cluster
+        .partition()
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn print_clusters(
+    drain: &DrainTree,
+    format: Format,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut clusters: Vec<&LogCluster> = drain.log_groups();
+    clusters.sort_by(|a, b| {
+        b.num_matched()
+            .cmp(&a.num_matched())
+            .then(a.id().cmp(&b.id()))
+    });
+    match format {
+        Format::Text => {
+            for cluster in clusters {
+                let partition = partition(cluster);
+                writeln!(
+                    out,
+                    "{}\t[{}] {}{}",
+                    cluster.num_matched(),
+                    cluster.id(),
+                    if partition.is_empty() {
+                        String::new()
+                    } else {
+                        format!("{{{}}} ", partition)
+                    },
+                    drain.template(cluster)
+                )?;
+            }
+        }
+        Format::Json => {
+            let clusters = clusters
+                .iter()
+                .map(|cluster| {
+                    serde_json::json!({
+                        "id": cluster.id(),
+                        "count": cluster.num_matched(),
+                        "template": drain.template(cluster),
+                        "partition": cluster.partition(),
+                        "metadata": cluster.metadata(),
+                        "first_seen": cluster.first_seen().map(|t| t.to_rfc3339()),
+                        "last_seen": cluster.last_seen().map(|t| t.to_rfc3339()),
+                        "events_per_second": cluster.events_per_second(),
+                        "variables": variables(cluster),
+                    })
+                })
+                .collect::<Vec<serde_json::Value>>();
+            serde_json::to_writer_pretty(&mut *out, &clusters)?;
+            writeln!(out)?;
+        }
+        Format::Csv => {
+            // the partition column is only there when the clusters are partitioned
+            let partitioned = clusters.iter().any(|c| !c.partition().is_empty());
+            writeln!(
+                out,
+                "id,count,{}template",
+                if partitioned { "partition," } else { "" }
+            )?;
+            for cluster in clusters {
+                let partition = if partitioned {
+                    format!("{},", csv_field(&partition(cluster)))
+                } else {
+                    String::new()
+                };
+                writeln!(
+                    out,
+                    "{},{},{}{}",
+                    cluster.id(),
+                    cluster.num_matched(),
+                    partition,
+                    csv_field(&drain.template(cluster))
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_evaluation(
+    evaluation: &Evaluation,
+    format: Format,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        Format::Text => writeln!(out, "{}", evaluation)?,
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *out, evaluation)?;
+            writeln!(out)?;
+        }
+        Format::Csv => {
+            writeln!(out, "lines,clusters,events,grouping_accuracy,parsing_accuracy,precision,recall,f_measure,template_precision,template_recall")?;
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{},{}",
+                evaluation.lines,
+                evaluation.clusters,
+                evaluation.events,
+                evaluation.grouping_accuracy,
+                evaluation.parsing_accuracy,
+                evaluation.precision,
+                evaluation.recall,
+                evaluation.f_measure,
+                evaluation.template_precision,
+                evaluation.template_recall
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Call `f` with every line of the files, `-` being stdin
+fn for_each_line(files: &[String], mut f: impl FnMut(String)) -> Result<(), Box<dyn Error>> {
+    for file in files.iter() {
+        let reader: Box<dyn BufRead> = if file == "-" {
+            Box::new(BufReader::new(io::stdin()))
+        } else {
+            Box::new(BufReader::new(
+                File::open(file).map_err(|e| format!("cannot open [{}]: {}", file, e))?,
+            ))
+        };
+        for line in reader.lines() {
+            f(line?);
+        }
+    }
+    Ok(())
+}
+
+fn run(options: Options) -> Result<(), Box<dyn Error>> {
+    let mut drain = build_tree(&options)?;
+    let files = if options.files.is_empty() {
+        vec!["-".to_string()]
+    } else {
+        options.files.clone()
+    };
+    let evaluation = match &options.truth {
+        Some(path) => {
+            let truth = std::fs::read_to_string(path)
+                .map_err(|e| format!("cannot open [{}]: {}", path, e))?;
+            let truth = GroundTruth::from_structured_csv(&truth)?;
+            let mut lines = vec![];
+            for_each_line(&files, |line| lines.push(line))?;
+            Some(evaluate(
+                &mut drain,
+                lines.iter().map(|l| l.as_str()),
+                &truth,
+            )?)
+        }
+        None if options.is_multiline() => {
+            let mut aggregator = MultilineAggregator::new()
+                .indentation(options.indentation)
+                .signature(options.signature);
+            if let Some(start_pattern) = &options.start_pattern {
+                aggregator = aggregator.start_pattern(start_pattern);
+            }
+            aggregator.try_build_patterns(&mut grok::Grok::with_patterns())?;
+            for_each_line(&files, |line| {
+                if let Some(event) = aggregator.push(line.as_str()) {
+                    drain.add_event(&event);
+                }
+            })?;
+            if let Some(event) = aggregator.flush() {
+                drain.add_event(&event);
+            }
+            None
+        }
+        None => {
+            for_each_line(&files, |line| {
+                drain.add_log_line(line.as_str());
+            })?;
+            None
+        }
+    };
+    if let Some(path) = &options.save {
+        drain.save_to(BufWriter::new(File::create(path)?))?;
+    }
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    match evaluation {
+        Some(evaluation) => print_evaluation(&evaluation, options.format, &mut out)?,
+        None => print_clusters(&drain, options.format, &mut out)?,
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("drain: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(options) {
+        eprintln!("drain: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,434 @@
+use crate::log_cluster::{ClusterId, LogCluster};
+use crate::observer::Observer;
+use crate::{DrainTree, Error, Fields, Insertion, NextId, Node, Partition};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+type Bucket = Arc<RwLock<Node>>;
+
+// a panic while holding a lock leaves a consistent tree, as nodes are only ever added to
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A `DrainTree` that many threads can add log lines to at once, e.g. behind an `Arc`
+///
+/// Every length bucket of every partition has its own lock: log lines of different lengths
+/// are clustered in parallel, and lookups only take read locks. The patterns are matched
+/// and the lines are tokenized without any lock.
+///
+/// Trees with a memory budget or automatic merges are refused, as both need the whole tree.
+/// The tree returned by `into_tree` can be serialized, or configured with them again.
+///
+/// The observer is called while the length bucket of the log line is locked, so the events of
+/// a cluster arrive in the order its log lines were added, while the events of clusters of
+/// different lengths may interleave. The observer must not add log lines to the tree.
+/// ```
+/// use drain_rs::{ConcurrentDrainTree, DrainTree};
+/// use std::sync::Arc;
+/// let drain = Arc::new(ConcurrentDrainTree::new(DrainTree::new().max_depth(2)));
+/// let threads = (0..4)
+///     .map(|thread| {
+///         let drain = drain.clone();
+///         std::thread::spawn(move || {
+///             for i in 0..100 {
+///                 drain.add_log_line(&format!("user {} logged in", thread * 100 + i));
+///                 drain.add_log_line(&format!("disk sda{} is full", i));
+///             }
+///         })
+///     })
+///     .collect::<Vec<_>>();
+/// for thread in threads {
+///     thread.join().unwrap();
+/// }
+/// let drain = Arc::try_unwrap(drain).unwrap().into_tree();
+/// assert_eq!(drain.cluster_count(), 2);
+/// ```
+#[derive(Debug)]
+pub struct ConcurrentDrainTree {
+    // the settings and patterns, its clusters are moved to the buckets
+    tree: DrainTree,
+    buckets: RwLock<HashMap<Fields, HashMap<usize, Bucket>>>,
+    next_cluster_id: AtomicU64,
+    tick: AtomicU64,
+    // whether there is an observer, to only lock it for the template events
+    observe: bool,
+    observer: Mutex<Option<Observer>>,
+}
+
+impl ConcurrentDrainTree {
+    /// Share the tree between threads, keeping its settings, patterns and clusters
+    ///
+    /// # Panics
+    /// If the patterns of the tree are not built, or if it has a memory budget or automatic
+    /// merges, see `try_new` for a fallible version.
+    pub fn new(tree: DrainTree) -> Self {
+        ConcurrentDrainTree::try_new(tree).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Share the tree between threads, keeping its settings, patterns and clusters
+    ///
+    /// The patterns must already be built, see `DrainTree::build_patterns`, and the tree must
+    /// have neither a memory budget nor automatic merges.
+    /// ```
+    /// let drain = drain_rs::DrainTree::new().filter_patterns(vec!["%{IPV4:ip_address}"]);
+    /// let err = drain_rs::ConcurrentDrainTree::try_new(drain).unwrap_err();
+    /// assert!(matches!(err, drain_rs::Error::PatternsNotBuilt));
+    /// ```
+    pub fn try_new(mut tree: DrainTree) -> Result<Self, Error> {
+        if !tree.is_compiled() {
+            return Err(Error::PatternsNotBuilt);
+        }
+        if tree.eviction.is_some() {
+            return Err(Error::NotConcurrent("memory_budget".to_string()));
+        }
+        if tree.auto_merge.is_some() {
+            return Err(Error::NotConcurrent("auto_merge".to_string()));
+        }
+        let mut buckets: HashMap<Fields, HashMap<usize, Bucket>> = HashMap::new();
+        let roots = std::iter::once((Fields::new(), std::mem::take(&mut tree.root))).chain(
+            std::mem::take(&mut tree.partitions)
+                .into_iter()
+                .map(|p| (p.key, p.root)),
+        );
+        for (key, root) in roots {
+            let partition = buckets.entry(key).or_default();
+            for (len, node) in root {
+                partition.insert(len, Arc::new(RwLock::new(node)));
+            }
+        }
+        Ok(ConcurrentDrainTree {
+            next_cluster_id: AtomicU64::new(tree.next_cluster_id),
+            tick: AtomicU64::new(tree.tick),
+            observe: tree.observer.is_some(),
+            observer: Mutex::new(tree.observer.take()),
+            buckets: RwLock::new(buckets),
+            tree,
+        })
+    }
+
+    /// Get back the tree once the threads are done, with every cluster
+    pub fn into_tree(self) -> DrainTree {
+        let mut tree = self.tree;
+        tree.next_cluster_id = self.next_cluster_id.into_inner();
+        tree.tick = self.tick.into_inner();
+        tree.observer = self
+            .observer
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner());
+        let buckets = self.buckets.into_inner().unwrap_or_else(|e| e.into_inner());
+        for (key, partition) in buckets {
+            let mut root = HashMap::new();
+            for (len, bucket) in partition {
+                let node = match Arc::try_unwrap(bucket) {
+                    Ok(node) => node.into_inner().unwrap_or_else(|e| e.into_inner()),
+                    Err(_) => unreachable!("buckets are only shared during a call"),
+                };
+                if !node.is_empty() {
+                    root.insert(len, node);
+                }
+            }
+            if key.is_empty() {
+                tree.root = root;
+            } else if !root.is_empty() {
+                tree.partitions.push(Partition { key, root });
+            }
+        }
+        tree.partitions.sort_by(|a, b| a.key.cmp(&b.key));
+        tree
+    }
+
+    fn bucket(&self, partition: &Fields, len: usize) -> Option<Bucket> {
+        read(&self.buckets).get(partition)?.get(&len).cloned()
+    }
+
+    fn bucket_or_insert(&self, partition: &Fields, len: usize) -> Bucket {
+        if let Some(bucket) = self.bucket(partition, len) {
+            return bucket;
+        }
+        write(&self.buckets)
+            .entry(partition.clone())
+            .or_default()
+            .entry(len)
+            .or_insert_with(|| Arc::new(RwLock::new(Node::inner(0))))
+            .clone()
+    }
+
+    /// Add a log line to the tree and return the identifier of the created/modified
+    /// log cluster, see `DrainTree::add_log_line_id`
+    pub fn add_log_line(&self, log_line: &str) -> Option<ClusterId> {
+        let (processed, message) = self.tree.process_line(log_line).ok()?;
+        let tokens = processed.tokens;
+        if tokens.is_empty() {
+            return None;
+        }
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed) + 1;
+        let bucket = self.bucket_or_insert(&message.partition, tokens.len());
+        let mut node = write(&bucket);
+        let mut insertion = Insertion {
+            max_depth: self.tree.max_depth,
+            max_children: self.tree.max_children,
+            min_similarity: self.tree.min_similarity,
+            numeric_prefixes: self.tree.numeric_prefixes(),
+            similarity: self.tree.similarity.as_ref(),
+            next_id: NextId::Shared(&self.next_cluster_id),
+            partition: &message.partition,
+            metadata: &message.metadata,
+            timestamp: message.timestamp(),
+            rate_window: self.tree.rate_window,
+            sampling: self.tree.sampling,
+            tick,
+            track_size: false,
+            observe: self.observe,
+            path: None,
+            created: false,
+            changed_from: None,
+            size_delta: 0,
+        };
+        let cluster = node.add_log(tokens.as_slice(), &mut insertion)?;
+        let id = cluster.id();
+        // sent before releasing the bucket, for the events of a cluster to stay in order
+        if self.observe && (insertion.created || insertion.changed_from.is_some()) {
+            let changed_from = insertion.changed_from.take();
+            let event = self.tree.template_event(cluster, changed_from, log_line);
+            let mut observer = self.observer.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(observer) = observer.as_mut() {
+                observer.on_event(&event);
+            }
+        }
+        Some(id)
+    }
+
+    /// A copy of the log cluster the log line belongs to, if it exists,
+    /// see `DrainTree::log_group`
+    pub fn log_group(&self, log_line: &str) -> Option<LogCluster> {
+        let (processed, message) = self.tree.process_line(log_line).ok()?;
+        let bucket = self.bucket(&message.partition, processed.tokens.len())?;
+        let node = read(&bucket);
+        let tokens = processed.tokens.as_slice();
+        let leaf = node.find_leaf(
+            tokens,
+            self.tree.max_depth,
+            self.tree.max_children,
+            self.tree.numeric_prefixes(),
+        )?;
+        let similarity = self.tree.similarity.as_ref();
+        if let Some((_, target, index)) =
+            node.redirect(leaf, tokens, similarity, self.tree.min_similarity)
+        {
+            return Some(target.log_groups[index].clone());
+        }
+        let gas = leaf.best_group(tokens, similarity)?;
+        Some(leaf.log_groups[gas.group_index].clone())
+    }
+
+    /// A copy of the log cluster with the given identifier, if it exists
+    pub fn cluster_by_id(&self, id: ClusterId) -> Option<LogCluster> {
+        self.buckets().iter().find_map(|bucket| {
+            read(bucket)
+                .log_groups()
+                .into_iter()
+                .find(|c| c.has_id(id))
+                .cloned()
+        })
+    }
+
+    /// A copy of every log cluster, each length bucket being copied at a different time
+    pub fn log_groups(&self) -> Vec<LogCluster> {
+        self.buckets()
+            .iter()
+            .flat_map(|bucket| {
+                read(bucket)
+                    .log_groups()
+                    .into_iter()
+                    .cloned()
+                    .collect::<Vec<LogCluster>>()
+            })
+            .collect()
+    }
+
+    /// Number of log clusters in the tree
+    pub fn cluster_count(&self) -> usize {
+        self.buckets()
+            .iter()
+            .map(|bucket| read(bucket).log_groups().len())
+            .sum()
+    }
+
+    /// Render the template of the log cluster with the configured tokenizer
+    pub fn template(&self, log_cluster: &LogCluster) -> String {
+        self.tree.template(log_cluster)
+    }
+
+    fn buckets(&self) -> Vec<Bucket> {
+        read(&self.buckets)
+            .values()
+            .flat_map(|partition| partition.values().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    fn lines() -> Vec<String> {
+        (0..400)
+            .map(|i| match i % 4 {
+                0 => format!("INFO user {} logged in", i),
+                1 => format!("INFO disk sda{} is full", i),
+                2 => format!("WARN disk sda{} is full", i),
+                _ => format!("INFO request took {} ms to serve", i),
+            })
+            .collect()
+    }
+
+    fn base() -> DrainTree {
+        DrainTree::new()
+            .max_depth(2)
+            .log_pattern("%{LOGLEVEL:level} %{GREEDYDATA:content}", "content")
+            .partition_fields(vec!["level"])
+            .build_patterns(&mut grok::Grok::with_patterns())
+    }
+
+    // add the lines from four threads
+    fn ingest(tree: DrainTree) -> ConcurrentDrainTree {
+        let concurrent = Arc::new(ConcurrentDrainTree::new(tree));
+        let threads = lines()
+            .chunks(100)
+            .map(|chunk| {
+                let chunk = chunk.to_vec();
+                let concurrent = concurrent.clone();
+                std::thread::spawn(move || {
+                    for line in chunk.iter() {
+                        assert!(concurrent.add_log_line(line).is_some());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        Arc::try_unwrap(concurrent).ok().unwrap()
+    }
+
+    #[test]
+    fn clusters_are_the_sequential_ones() {
+        let mut sequential = base();
+        for line in lines().iter() {
+            sequential.add_log_line(line);
+        }
+        let concurrent = ingest(base()).into_tree();
+        let templates = |drain: &DrainTree| {
+            let mut templates = drain
+                .log_groups()
+                .iter()
+                .map(|c| (c.partition().clone(), drain.template(c), c.num_matched()))
+                .collect::<Vec<_>>();
+            templates.sort();
+            templates
+        };
+        assert_eq!(templates(&concurrent), templates(&sequential));
+        assert_eq!(concurrent.partitions.len(), 2);
+    }
+
+    #[test]
+    fn cluster_ids_are_dense() {
+        let concurrent = ingest(base()).into_tree();
+        let ids = concurrent
+            .log_groups()
+            .iter()
+            .map(|c| c.id())
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(ids.into_iter().collect::<Vec<_>>(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn lookups_see_the_lines_of_every_thread() {
+        let concurrent = ingest(base());
+        assert_eq!(concurrent.cluster_count(), 4);
+        assert_eq!(concurrent.log_groups().len(), 4);
+        let cluster = concurrent.log_group("WARN disk sda999 is full").unwrap();
+        assert_eq!(concurrent.template(&cluster), "disk <*> is full");
+        assert_eq!(cluster.partition()["level"], "WARN");
+        assert_eq!(cluster.num_matched(), 100);
+        assert_eq!(
+            concurrent
+                .cluster_by_id(cluster.id())
+                .unwrap()
+                .num_matched(),
+            100
+        );
+    }
+
+    #[test]
+    fn new_clusters_are_observed_once() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        ingest(base().observer(sender));
+        let new_clusters = receiver
+            .try_iter()
+            .filter(|e| e.kind == crate::TemplateEventKind::NewCluster)
+            .count();
+        assert_eq!(new_clusters, 4);
+    }
+
+    #[test]
+    fn callbacks_do_not_need_to_be_sync() {
+        // a `Cell` is `Send` but not `Sync`
+        let evicted = Cell::new(0);
+        let events = Cell::new(0);
+        let tree = DrainTree::new()
+            .on_eviction(move |_| evicted.set(evicted.get() + 1))
+            .observer(move |_: &crate::TemplateEvent| events.set(events.get() + 1));
+        assert_send_sync(&tree);
+        assert_send_sync(&ConcurrentDrainTree::new(tree));
+    }
+
+    #[test]
+    fn patterns_must_be_built() {
+        let tree = DrainTree::new().filter_patterns(vec!["%{IPV4:ip_address}"]);
+        assert!(matches!(
+            ConcurrentDrainTree::try_new(tree).unwrap_err(),
+            Error::PatternsNotBuilt
+        ));
+
+        let mut g = grok::Grok::with_patterns();
+        let tree = DrainTree::new()
+            .filter_patterns(vec!["%{IPV4:ip_address}"])
+            .build_patterns(&mut g);
+        let concurrent = ConcurrentDrainTree::try_new(tree).unwrap();
+        assert!(concurrent.add_log_line("call from 10.0.0.1").is_some());
+    }
+
+    #[test]
+    fn memory_budgets_and_auto_merges_are_refused() {
+        let tree = DrainTree::new().memory_budget(
+            crate::MemoryBudget::MaxClusters(2),
+            crate::EvictionPolicy::LeastRecentlyUsed,
+        );
+        assert!(matches!(
+            ConcurrentDrainTree::try_new(tree).unwrap_err(),
+            Error::NotConcurrent(setting) if setting == "memory_budget"
+        ));
+
+        let tree = DrainTree::new().auto_merge(0.5, 100);
+        assert!(matches!(
+            ConcurrentDrainTree::try_new(tree).unwrap_err(),
+            Error::NotConcurrent(setting) if setting == "auto_merge"
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "build_patterns")]
+    fn new_panics_without_built_patterns() {
+        ConcurrentDrainTree::new(DrainTree::new().mask("0x[0-9a-f]+", "HEX"));
+    }
+}
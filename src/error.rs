@@ -0,0 +1,110 @@
+use std::fmt;
+
+/// Which of the configured patterns a [`PatternError`] refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternKind {
+    /// The overall pattern set with `DrainTree::log_pattern`
+    LogPattern,
+    /// One of the patterns set with `DrainTree::filter_patterns`
+    FilterPattern,
+    /// One of the rules set with `DrainTree::mask`
+    MaskingRule,
+    /// The pattern set with `MultilineAggregator::start_pattern`
+    StartPattern,
+}
+
+impl fmt::Display for PatternKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatternKind::LogPattern => write!(f, "log pattern"),
+            PatternKind::FilterPattern => write!(f, "filter pattern"),
+            PatternKind::MaskingRule => write!(f, "masking rule"),
+            PatternKind::StartPattern => write!(f, "start pattern"),
+        }
+    }
+}
+
+/// A grok pattern that could not be compiled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternError {
+    /// Where the pattern was configured
+    pub kind: PatternKind,
+    /// The pattern as supplied by the user
+    pub pattern: String,
+    /// Why grok refused to compile it
+    pub reason: String,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}]: {}", self.kind, self.pattern, self.reason)
+    }
+}
+
+/// Errors returned while building patterns, ingesting log lines, evaluating and tuning
+/// clusters, or saving and loading snapshots
+#[derive(Debug)]
+pub enum Error {
+    /// At least one grok pattern failed to compile, every failing pattern is listed
+    InvalidPatterns(Vec<PatternError>),
+    /// An overall log pattern is set without a field to cluster on
+    MissingDrainField,
+    /// Patterns were configured (or deserialized) but `build_patterns` was not called since
+    PatternsNotBuilt,
+    /// The labeled log lines given to `evaluate` cannot be read or do not match the log lines
+    InvalidGroundTruth(String),
+    /// One of the parameters given to the `Tuner` has no candidate value
+    EmptySearchSpace,
+    /// A snapshot could not be read or written
+    Io(std::io::Error),
+    /// The data given to `DrainTree::load_from` is not a snapshot, or is corrupted
+    InvalidSnapshot(String),
+    /// The snapshot was written by a newer version of the library
+    UnsupportedSnapshotVersion(u16),
+    /// The tree given to `ConcurrentDrainTree` uses a setting it cannot apply, which is named
+    NotConcurrent(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidPatterns(errors) => write!(
+                f,
+                "failed to compile patterns: {}",
+                errors
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<String>>()
+                    .join("; ")
+            ),
+            Error::MissingDrainField => {
+                write!(
+                    f,
+                    "illegal state. [overall_pattern] set without [drain_field] set"
+                )
+            }
+            Error::PatternsNotBuilt => {
+                write!(f, "patterns are not compiled, call [build_patterns] first")
+            }
+            Error::InvalidGroundTruth(reason) => write!(f, "invalid ground truth: {}", reason),
+            Error::EmptySearchSpace => write!(f, "no candidate parameter value to try"),
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::InvalidSnapshot(reason) => write!(f, "invalid snapshot: {}", reason),
+            Error::UnsupportedSnapshotVersion(version) => {
+                write!(f, "unsupported snapshot version [{}]", version)
+            }
+            Error::NotConcurrent(setting) => {
+                write!(f, "[{}] is not supported by [ConcurrentDrainTree]", setting)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
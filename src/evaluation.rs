@@ -0,0 +1,383 @@
+use crate::error::Error;
+use crate::log_cluster::ClusterId;
+use crate::DrainTree;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Formatter;
+
+/// The expected event of every log line, as labeled in a Loghub `*_structured.csv` file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroundTruth {
+    event_ids: Vec<String>,
+    templates: Vec<String>,
+}
+
+/// Split CSV text into records, fields may be quoted and hold separators, quotes and line breaks
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(format!(
+            "unterminated quoted field in record {}",
+            records.len() + 1
+        ));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+impl GroundTruth {
+    /// Read the `EventId` and `EventTemplate` columns of a structured CSV, one row per log line
+    /// ```
+    /// let csv = "LineId,Content,EventId,EventTemplate\n\
+    ///            1,\"user 3, logged in\",E1,\"user <*>, logged in\"\n";
+    /// let truth = drain_rs::GroundTruth::from_structured_csv(csv).unwrap();
+    /// assert_eq!(truth.len(), 1);
+    /// ```
+    pub fn from_structured_csv(text: &str) -> Result<Self, Error> {
+        let records = parse_csv(text).map_err(Error::InvalidGroundTruth)?;
+        let mut records = records.into_iter();
+        let header = records
+            .next()
+            .ok_or_else(|| Error::InvalidGroundTruth("missing header".to_string()))?;
+        let column = |name: &str| {
+            header
+                .iter()
+                .position(|h| h == name)
+                .ok_or_else(|| Error::InvalidGroundTruth(format!("missing [{}] column", name)))
+        };
+        let (id_column, template_column) = (column("EventId")?, column("EventTemplate")?);
+        let mut truth = GroundTruth {
+            event_ids: vec![],
+            templates: vec![],
+        };
+        for (row, record) in records.enumerate() {
+            if record.len() == 1 && record[0].is_empty() {
+                continue;
+            }
+            if record.len() != header.len() {
+                return Err(Error::InvalidGroundTruth(format!(
+                    "row {} has {} fields, expected {}",
+                    row + 1,
+                    record.len(),
+                    header.len()
+                )));
+            }
+            truth.event_ids.push(record[id_column].clone());
+            truth.templates.push(record[template_column].clone());
+        }
+        Ok(truth)
+    }
+
+    /// Build the ground truth from the event identifier and template of every log line
+    pub fn new(events: Vec<(String, String)>) -> Self {
+        let (event_ids, templates) = events.into_iter().unzip();
+        GroundTruth {
+            event_ids,
+            templates,
+        }
+    }
+
+    /// Number of labeled log lines
+    pub fn len(&self) -> usize {
+        self.event_ids.len()
+    }
+
+    /// Whether no log line is labeled
+    pub fn is_empty(&self) -> bool {
+        self.event_ids.is_empty()
+    }
+}
+
+/// How well the clusters of a tree match the labeled events, see `evaluate`
+///
+/// Every metric is between 0 and 1, and is 0 when there is nothing to measure it on.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Evaluation {
+    /// Number of evaluated log lines
+    pub lines: usize,
+    /// Number of clusters the log lines ended up in, the lines that were not clustered count
+    /// as wrongly grouped and parsed
+    pub clusters: usize,
+    /// Number of distinct labeled events
+    pub events: usize,
+    /// Fraction of log lines whose cluster holds exactly the log lines of their event
+    pub grouping_accuracy: f64,
+    /// Fraction of log lines whose cluster template is their event template
+    pub parsing_accuracy: f64,
+    /// Fraction of the pairs of log lines clustered together that belong to the same event
+    pub precision: f64,
+    /// Fraction of the pairs of log lines of the same event that are clustered together
+    pub recall: f64,
+    /// Harmonic mean of `precision` and `recall`
+    pub f_measure: f64,
+    /// Fraction of the clusters that are correct, holding exactly the log lines of an event
+    /// and rendering its template
+    pub template_precision: f64,
+    /// Fraction of the events that are found by a correct cluster
+    pub template_recall: f64,
+}
+
+impl fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "lines: {} clusters: {} events: {}",
+            self.lines, self.clusters, self.events
+        )?;
+        writeln!(f, "grouping accuracy: {:.4}", self.grouping_accuracy)?;
+        writeln!(f, "parsing accuracy: {:.4}", self.parsing_accuracy)?;
+        writeln!(
+            f,
+            "precision: {:.4} recall: {:.4} f-measure: {:.4}",
+            self.precision, self.recall, self.f_measure
+        )?;
+        write!(
+            f,
+            "template precision: {:.4} template recall: {:.4}",
+            self.template_precision, self.template_recall
+        )
+    }
+}
+
+fn ratio(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 / total as f64
+    }
+}
+
+fn pairs(count: usize) -> usize {
+    count * count.saturating_sub(1) / 2
+}
+
+/// Compare templates token by token, named filter tokens such as `<ip>` count as `<*>`
+fn normalize_template(template: &str) -> Vec<&str> {
+    template
+        .split_whitespace()
+        .map(|token| {
+            if token.len() >= 2
+                && token.starts_with('<')
+                && token.ends_with('>')
+                && !token[1..token.len() - 1].contains(['<', '>'])
+            {
+                "<*>"
+            } else {
+                token
+            }
+        })
+        .collect()
+}
+
+/// Score the cluster assigned to every log line, `None` for the lines that were not clustered
+pub(crate) fn score(
+    predictions: &[Option<(ClusterId, String)>],
+    truth: &GroundTruth,
+) -> Evaluation {
+    // unclustered lines are alone in their group and have no template
+    let groups = predictions
+        .iter()
+        .enumerate()
+        .map(|(line, prediction)| match prediction {
+            Some((id, _)) => (true, *id as usize),
+            None => (false, line),
+        })
+        .collect::<Vec<(bool, usize)>>();
+    let mut group_sizes: HashMap<(bool, usize), usize> = HashMap::new();
+    let mut event_sizes: HashMap<&str, usize> = HashMap::new();
+    let mut cells: HashMap<((bool, usize), &str), usize> = HashMap::new();
+    let mut group_templates: HashMap<(bool, usize), &str> = HashMap::new();
+    for ((group, event), prediction) in groups
+        .iter()
+        .zip(truth.event_ids.iter())
+        .zip(predictions.iter())
+    {
+        if let Some((_, template)) = prediction {
+            group_templates.insert(*group, template);
+        }
+        *group_sizes.entry(*group).or_insert(0) += 1;
+        *event_sizes.entry(event.as_str()).or_insert(0) += 1;
+        *cells.entry((*group, event.as_str())).or_insert(0) += 1;
+    }
+
+    let mut grouped_lines = 0;
+    let mut correct_templates = 0;
+    let mut correct_clusters = 0;
+    let mut event_templates: HashMap<&str, &str> = HashMap::new();
+    for (event, template) in truth.event_ids.iter().zip(truth.templates.iter()) {
+        event_templates.entry(event).or_insert(template);
+    }
+    for (&(group, event), &count) in cells.iter() {
+        let (clustered, _) = group;
+        if clustered && count == group_sizes[&group] && count == event_sizes[event] {
+            grouped_lines += count;
+            if let Some(template) = group_templates.get(&group) {
+                if normalize_template(template) == normalize_template(event_templates[event]) {
+                    correct_clusters += 1;
+                }
+            }
+        }
+    }
+    for (prediction, template) in predictions.iter().zip(truth.templates.iter()) {
+        if let Some((_, predicted)) = prediction {
+            if normalize_template(predicted) == normalize_template(template) {
+                correct_templates += 1;
+            }
+        }
+    }
+
+    let clusters = group_sizes
+        .keys()
+        .filter(|(clustered, _)| *clustered)
+        .count();
+    let true_pairs: usize = event_sizes.values().map(|&n| pairs(n)).sum();
+    let predicted_pairs: usize = group_sizes.values().map(|&n| pairs(n)).sum();
+    let correct_pairs: usize = cells.values().map(|&n| pairs(n)).sum();
+    let precision = ratio(correct_pairs, predicted_pairs);
+    let recall = ratio(correct_pairs, true_pairs);
+    let f_measure = if precision + recall > 0.0 {
+        2.0 * precision * recall / (precision + recall)
+    } else {
+        0.0
+    };
+    Evaluation {
+        lines: predictions.len(),
+        clusters,
+        events: event_sizes.len(),
+        grouping_accuracy: ratio(grouped_lines, predictions.len()),
+        parsing_accuracy: ratio(correct_templates, predictions.len()),
+        precision,
+        recall,
+        f_measure,
+        template_precision: ratio(correct_clusters, clusters),
+        template_recall: ratio(correct_clusters, event_sizes.len()),
+    }
+}
+
+/// Add the log lines to the tree and score the resulting clusters against the ground truth
+///
+/// The clusters are taken once every line is added, so the templates are the final ones.
+/// The ground truth must label every log line, in the same order.
+/// ```
+/// use drain_rs::{evaluate, DrainTree, GroundTruth};
+/// let lines = ["user 3 logged in", "user 4 logged in", "disk full"];
+/// let truth = GroundTruth::new(vec![
+///     ("E1".to_string(), "user <*> logged in".to_string()),
+///     ("E1".to_string(), "user <*> logged in".to_string()),
+///     ("E2".to_string(), "disk full".to_string()),
+/// ]);
+/// let evaluation = evaluate(&mut DrainTree::new(), lines.iter().copied(), &truth).unwrap();
+/// assert_eq!(evaluation.grouping_accuracy, 1.0);
+/// assert_eq!(evaluation.parsing_accuracy, 1.0);
+/// ```
+pub fn evaluate<'a>(
+    drain: &mut DrainTree,
+    lines: impl IntoIterator<Item = &'a str>,
+    truth: &GroundTruth,
+) -> Result<Evaluation, Error> {
+    let lines = lines.into_iter().collect::<Vec<&str>>();
+    let ids = drain.add_log_lines_parallel(&lines);
+    if ids.len() != truth.len() {
+        return Err(Error::InvalidGroundTruth(format!(
+            "{} log lines but {} labeled rows",
+            ids.len(),
+            truth.len()
+        )));
+    }
+    let predictions = ids
+        .into_iter()
+        .map(|id| {
+            let cluster = drain.cluster_by_id(id?)?;
+            Some((cluster.id(), drain.template(cluster)))
+        })
+        .collect::<Vec<Option<(ClusterId, String)>>>();
+    Ok(score(&predictions, truth))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use float_cmp::approx_eq;
+
+    fn truth() -> GroundTruth {
+        GroundTruth::from_structured_csv(
+            "LineId,EventId,EventTemplate\n1,A,open <*>\n2,A,open <*>\n3,B,close <*>\n4,B,close <*>\n5,C,\"stop, now\"\n",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn overgrouping_is_scored_low() {
+        let predictions = vec![
+            Some((0, "<*> <*>".to_string())),
+            Some((0, "<*> <*>".to_string())),
+            Some((0, "<*> <*>".to_string())),
+            Some((1, "close <file>".to_string())),
+            None,
+        ];
+        let evaluation = score(&predictions, &truth());
+        assert_eq!((evaluation.clusters, evaluation.events), (2, 3));
+        assert!(approx_eq!(f64, evaluation.grouping_accuracy, 0.0));
+        assert!(approx_eq!(f64, evaluation.parsing_accuracy, 0.2));
+        assert!(approx_eq!(f64, evaluation.precision, 1.0 / 3.0));
+        assert!(approx_eq!(f64, evaluation.recall, 0.5));
+        assert!(approx_eq!(f64, evaluation.f_measure, 0.4));
+        assert!(approx_eq!(f64, evaluation.template_recall, 0.0));
+    }
+
+    #[test]
+    fn templates_are_scored_apart_from_groups() {
+        let predictions = vec![
+            Some((7, "open <*>".to_string())),
+            Some((7, "open <*>".to_string())),
+            Some((8, "close <*>".to_string())),
+            Some((8, "close <*>".to_string())),
+            Some((9, "stop, later".to_string())),
+        ];
+        let evaluation = score(&predictions, &truth());
+        assert!(approx_eq!(f64, evaluation.grouping_accuracy, 1.0));
+        assert!(approx_eq!(f64, evaluation.parsing_accuracy, 0.8));
+        assert!(approx_eq!(f64, evaluation.f_measure, 1.0));
+        assert!(approx_eq!(f64, evaluation.template_precision, 2.0 / 3.0));
+    }
+
+    #[test]
+    fn ground_truth_needs_templates() {
+        let invalid = GroundTruth::from_structured_csv("LineId,EventId\n1,A\n");
+        assert!(matches!(
+            invalid,
+            Err(Error::InvalidGroundTruth(reason)) if reason == "missing [EventTemplate] column"
+        ));
+    }
+}
@@ -0,0 +1,276 @@
+use crate::log_cluster::{ClusterId, LogCluster};
+use crate::token::Token;
+use crate::Fields;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fmt::Formatter;
+use std::sync::{Mutex, PoisonError};
+
+/// Upper bound on what the log clusters of a tree may hold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemoryBudget {
+    /// Maximum number of log clusters
+    MaxClusters(usize),
+    /// Maximum number of bytes used by the log clusters, see `LogCluster::approximate_size`
+    MaxBytes(usize),
+}
+
+/// Which log cluster is evicted first once the memory budget is exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EvictionPolicy {
+    /// The cluster that went the longest without matching a log line
+    LeastRecentlyUsed,
+    /// The cluster that matched the fewest log lines, the least recently used one on ties
+    LeastFrequentlyMatched,
+}
+
+impl EvictionPolicy {
+    /// The cluster with the smallest key is evicted first
+    pub(crate) fn key(&self, cluster: &LogCluster) -> (u64, u64) {
+        match self {
+            EvictionPolicy::LeastRecentlyUsed => (cluster.last_matched(), 0),
+            EvictionPolicy::LeastFrequentlyMatched => {
+                (cluster.num_matched(), cluster.last_matched())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct Eviction {
+    pub budget: MemoryBudget,
+    pub policy: EvictionPolicy,
+}
+
+/// Called with every evicted log cluster
+///
+/// The mutex keeps the tree `Sync` without requiring a `Sync` callback, it is never locked
+/// as the callback is only called through `&mut`.
+pub(crate) struct EvictionCallback(Mutex<Box<dyn FnMut(LogCluster) + Send>>);
+
+impl EvictionCallback {
+    pub fn new(callback: Box<dyn FnMut(LogCluster) + Send>) -> Self {
+        EvictionCallback(Mutex::new(callback))
+    }
+
+    pub fn call(&mut self, cluster: LogCluster) {
+        let callback = self.0.get_mut().unwrap_or_else(PoisonError::into_inner);
+        callback(cluster)
+    }
+}
+
+impl fmt::Debug for EvictionCallback {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "EvictionCallback")
+    }
+}
+
+/// Where a log cluster is in the tree
+#[derive(Debug, Clone)]
+pub(crate) struct Location {
+    pub partition: Fields,
+    pub len: usize,
+    // the keys followed from the length bucket to the leaf of the cluster
+    pub path: Vec<Token>,
+}
+
+/// The log clusters ordered by the key of the eviction policy, along with their location, for
+/// evictions to take a logarithmic time
+#[derive(Debug, Default)]
+pub(crate) struct EvictionIndex {
+    order: BTreeSet<((u64, u64), ClusterId)>,
+    clusters: HashMap<ClusterId, ((u64, u64), Location)>,
+}
+
+impl EvictionIndex {
+    pub fn insert(&mut self, id: ClusterId, key: (u64, u64), location: Location) {
+        if let Some((previous, _)) = self.clusters.insert(id, (key, location)) {
+            self.order.remove(&(previous, id));
+        }
+        self.order.insert((key, id));
+    }
+
+    /// Set the key of a cluster after a log line matched it
+    pub fn update(&mut self, id: ClusterId, key: (u64, u64)) {
+        if let Some((previous, _)) = self.clusters.get_mut(&id) {
+            self.order.remove(&(*previous, id));
+            *previous = key;
+            self.order.insert((key, id));
+        }
+    }
+
+    /// Take the cluster to evict first, other than `keep`
+    pub fn pop(&mut self, keep: ClusterId) -> Option<(ClusterId, Location)> {
+        let first = *self.order.iter().find(|(_, id)| *id != keep)?;
+        self.order.remove(&first);
+        let (_, location) = self.clusters.remove(&first.1)?;
+        Some((first.1, location))
+    }
+}
+
+/// Running totals of what the log clusters of a tree hold
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Usage {
+    pub clusters: usize,
+    pub bytes: usize,
+}
+
+impl Usage {
+    pub fn exceeds(&self, budget: &MemoryBudget) -> bool {
+        match budget {
+            MemoryBudget::MaxClusters(max) => self.clusters > *max,
+            MemoryBudget::MaxBytes(max) => self.bytes > *max,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Decision, DrainTree};
+
+    fn location() -> Location {
+        Location {
+            partition: Fields::new(),
+            len: 1,
+            path: vec![],
+        }
+    }
+
+    #[test]
+    fn index_pops_the_smallest_key() {
+        let mut index = EvictionIndex::default();
+        index.insert(0, (3, 0), location());
+        index.insert(1, (1, 0), location());
+        index.insert(2, (2, 0), location());
+        index.update(1, (4, 0));
+
+        assert_eq!(index.pop(2).map(|(id, _)| id), Some(0));
+        assert_eq!(index.pop(2).map(|(id, _)| id), Some(1));
+        assert!(index.pop(2).is_none());
+    }
+
+    #[test]
+    fn least_frequently_matched_clusters_are_evicted() {
+        let mut drain = DrainTree::new().memory_budget(
+            MemoryBudget::MaxClusters(2),
+            EvictionPolicy::LeastFrequentlyMatched,
+        );
+        let frequent = drain.add_log_line_id("alpha one").unwrap();
+        drain.add_log_line("alpha one");
+        let rare = drain.add_log_line_id("beta").unwrap();
+        let newest = drain.add_log_line_id("gamma three four").unwrap();
+
+        assert!(drain.cluster_by_id(rare).is_none());
+        assert!(drain.cluster_by_id(frequent).is_some());
+        assert!(drain.cluster_by_id(newest).is_some());
+        assert!(!drain.root.contains_key(&1));
+    }
+
+    #[test]
+    fn evicted_clusters_are_handed_to_the_callback() {
+        use std::sync::{Arc, Mutex};
+
+        let evicted = Arc::new(Mutex::new(vec![]));
+        let sink = evicted.clone();
+        let mut drain = DrainTree::new()
+            .memory_budget(
+                MemoryBudget::MaxClusters(1),
+                EvictionPolicy::LeastRecentlyUsed,
+            )
+            .on_eviction(move |c| sink.lock().unwrap().push(c.id()));
+        let first = drain.add_log_line_id("first line").unwrap();
+        drain.add_log_line("second kind of line");
+
+        assert_eq!(*evicted.lock().unwrap(), vec![first]);
+    }
+
+    #[test]
+    fn byte_budgets_bound_the_memory_usage() {
+        let mut measure = DrainTree::new();
+        measure.add_log_line("first line");
+        let budget = measure.memory_usage() * 3 / 2;
+        let mut drain = DrainTree::new().memory_budget(
+            MemoryBudget::MaxBytes(budget),
+            EvictionPolicy::LeastRecentlyUsed,
+        );
+        let first = drain.add_log_line_id("first line").unwrap();
+        let other = drain.add_log_line_id("other line").unwrap();
+
+        assert!(drain.cluster_by_id(first).is_none());
+        assert!(drain.cluster_by_id(other).is_some());
+        assert!(drain.memory_usage() <= budget);
+    }
+
+    #[test]
+    fn matched_clusters_are_evicted_last() {
+        let mut drain = DrainTree::new().memory_budget(
+            MemoryBudget::MaxClusters(2),
+            EvictionPolicy::LeastRecentlyUsed,
+        );
+        let first = drain.add_log_line_id("first line").unwrap();
+        let second = drain.add_log_line_id("second kind of line").unwrap();
+        drain.add_log_line("first line");
+        drain.add_log_line("third");
+
+        assert!(drain.cluster_by_id(first).is_some());
+        assert!(drain.cluster_by_id(second).is_none());
+        assert_eq!(drain.cluster_count(), 2);
+    }
+
+    #[test]
+    fn evicting_the_last_cluster_of_a_partition_removes_it() {
+        let mut drain = DrainTree::new()
+            .json_message("/msg")
+            .partition_fields(vec!["/service"])
+            .memory_budget(
+                MemoryBudget::MaxClusters(1),
+                EvictionPolicy::LeastRecentlyUsed,
+            );
+        drain.add_log_line(r#"{"service":"api","msg":"user 3 logged in"}"#);
+        let db = drain
+            .add_log_line_id(r#"{"service":"db","msg":"disk full"}"#)
+            .unwrap();
+
+        assert_eq!(drain.cluster_count(), 1);
+        assert_eq!(drain.partitions.len(), 1);
+        assert_eq!(drain.log_groups()[0].id(), db);
+    }
+
+    #[test]
+    fn clusters_moved_by_a_merge_can_be_evicted() {
+        let mut drain = DrainTree::new().max_depth(3).memory_budget(
+            MemoryBudget::MaxClusters(3),
+            EvictionPolicy::LeastRecentlyUsed,
+        );
+        let alpha = drain.add_log_line_id("alpha task done now").unwrap();
+        drain.add_log_line("beta task done now");
+        assert_eq!(drain.merge_clusters(0.7).len(), 1);
+
+        drain.add_log_line("one");
+        drain.add_log_line("two words");
+        drain.add_log_line("three more words");
+        assert_eq!(drain.cluster_count(), 3);
+        assert!(drain.cluster_by_id(alpha).is_none());
+    }
+
+    #[test]
+    fn redirects_to_evicted_clusters_are_dropped() {
+        let mut drain = DrainTree::new().max_depth(3).memory_budget(
+            MemoryBudget::MaxClusters(2),
+            EvictionPolicy::LeastRecentlyUsed,
+        );
+        drain.add_log_line("alpha task done now");
+        drain.add_log_line("beta task done now");
+        assert_eq!(drain.merge_clusters(0.7), vec![(1, 0)]);
+        drain.add_log_line("gamma job failed badly");
+        drain.add_log_line("delta job failed badly");
+        assert!(drain.cluster_by_id(0).is_none());
+
+        // the leaves left by the merge only held redirects to the evicted cluster
+        let explanation = drain.explain("beta task done now").unwrap();
+        assert!(!explanation.steps[0].exists);
+        assert_eq!(explanation.decision, Decision::NewCluster);
+    }
+}
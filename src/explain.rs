@@ -0,0 +1,192 @@
+use crate::log_cluster::{ClusterId, GroupSimilarity};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Formatter;
+
+/// Why a prefix node was picked for a token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchReason {
+    /// The token is used as is
+    Exact,
+    /// The token contains a number and is assumed to be variable, it is sent to the `<NUM>`
+    /// branch
+    Numeric,
+    /// The token was already a wildcard after filtering
+    Wildcard,
+    /// The node already has `max_children` children, so the token is sent to the `<*>` branch
+    MaxChildren,
+}
+
+/// One level of the prefix tree traversed by a log line
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefixStep {
+    /// Depth of the prefix node, starting at 0 under the length bucket
+    pub depth: usize,
+    /// The token of the log line at this depth
+    pub token: String,
+    /// The key of the child that is followed
+    pub branch: String,
+    /// Why that child was picked
+    pub reason: BranchReason,
+    /// Whether the child already exists or would be created
+    pub exists: bool,
+}
+
+/// A cluster of the reached leaf compared against the log line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    /// The identifier of the cluster
+    pub cluster_id: ClusterId,
+    /// The current template of the cluster
+    pub template: String,
+    /// How similar the log line is to the template
+    pub similarity: GroupSimilarity,
+}
+
+/// Where the log line ends up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    /// The line is added to an existing cluster
+    Matched(ClusterId),
+    /// The reached leaf has no cluster yet, a new one is created
+    NewCluster,
+    /// The best candidate is not similar enough, a new cluster is created
+    BelowMinSimilarity {
+        /// The exact similarity of the best candidate
+        best: f32,
+    },
+}
+
+/// The decision path of the drain tree for a single log line, see `DrainTree::explain`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explanation {
+    /// The text that is clustered, after applying the log pattern
+    pub message: String,
+    /// The values of the partition fields, empty when the tree is not partitioned
+    pub partition: BTreeMap<String, String>,
+    /// The tokens of the message, after applying the filter patterns
+    pub tokens: Vec<String>,
+    /// The length bucket picked from the root of the tree
+    pub length: usize,
+    /// Whether the length bucket already exists
+    pub length_exists: bool,
+    /// The prefix nodes followed from the length bucket down to the leaf
+    pub steps: Vec<PrefixStep>,
+    /// Every cluster of the reached leaf, in the order they are compared
+    pub candidates: Vec<Candidate>,
+    /// The configured minimum similarity
+    pub min_similarity: f32,
+    /// The outcome
+    pub decision: Decision,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "message: {}", self.message)?;
+        if !self.partition.is_empty() {
+            let partition = self
+                .partition
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<String>>();
+            writeln!(f, "partition: [{}]", partition.join(", "))?;
+        }
+        writeln!(f, "tokens: [{}]", self.tokens.join(", "))?;
+        writeln!(
+            f,
+            "length: {} ({})",
+            self.length,
+            if self.length_exists {
+                "existing"
+            } else {
+                "new"
+            }
+        )?;
+        for step in self.steps.iter() {
+            let reason = match step.reason {
+                BranchReason::Exact => "exact token",
+                BranchReason::Numeric => "contains a number",
+                BranchReason::Wildcard => "wildcard token",
+                BranchReason::MaxChildren => "max_children reached",
+            };
+            writeln!(
+                f,
+                "{}depth {}: {} -> {} ({}, {})",
+                " ".repeat(step.depth),
+                step.depth,
+                step.token,
+                step.branch,
+                reason,
+                if step.exists { "existing" } else { "new" }
+            )?;
+        }
+        for candidate in self.candidates.iter() {
+            writeln!(
+                f,
+                "candidate [{}] {}: exact [{}] approximate [{}]",
+                candidate.cluster_id,
+                candidate.template,
+                candidate.similarity.exact_similarity,
+                candidate.similarity.approximate_similarity
+            )?;
+        }
+        match self.decision {
+            Decision::Matched(id) => write!(f, "decision: matched cluster [{}]", id),
+            Decision::NewCluster => write!(f, "decision: new cluster, leaf has no cluster"),
+            Decision::BelowMinSimilarity { best } => write!(
+                f,
+                "decision: new cluster, best similarity [{}] below min_similarity [{}]",
+                best, self.min_similarity
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BranchReason, Decision, DrainTree};
+    use float_cmp::approx_eq;
+
+    #[test]
+    fn full_nodes_send_lines_down_the_wildcard_branch() {
+        let mut drain = DrainTree::new().max_depth(3).max_children(1);
+        drain.add_log_line("open file a now");
+
+        let explanation = drain.explain("close file b now").unwrap();
+        assert!(explanation.length_exists);
+        assert_eq!(explanation.steps[0].branch, "<*>");
+        assert_eq!(explanation.steps[0].reason, BranchReason::MaxChildren);
+        assert!(!explanation.steps[0].exists);
+        assert_eq!(explanation.decision, Decision::NewCluster);
+    }
+
+    #[test]
+    fn added_lines_are_matched() {
+        let mut drain = DrainTree::new().max_depth(3).max_children(1);
+        drain.add_log_line("open file a now");
+        let id = drain.add_log_line_id("close file b now").unwrap();
+
+        let explanation = drain.explain("close file b now").unwrap();
+        assert!(explanation.steps.iter().all(|s| s.exists));
+        assert_eq!(explanation.decision, Decision::Matched(id));
+    }
+
+    #[test]
+    fn candidates_below_the_min_similarity_are_reported() {
+        let mut drain = DrainTree::new().max_depth(1);
+        drain.add_log_line("a b c d e");
+
+        let explanation = drain.explain("a b x y z").unwrap();
+        assert_eq!(explanation.steps.len(), 2);
+        assert_eq!(explanation.candidates.len(), 1);
+        assert!(approx_eq!(
+            f32,
+            explanation.candidates[0].similarity.exact_similarity,
+            0.4
+        ));
+        assert_eq!(
+            explanation.decision,
+            Decision::BelowMinSimilarity { best: 0.4 }
+        );
+    }
+}
@@ -1,70 +1,231 @@
+//! Detection of GROK base patterns for the variable parts of log clusters
 use crate::log_cluster::LogCluster;
-use grok;
-use grok::Pattern;
+use crate::statistics::VariableType;
+use crate::token::Token;
+use crate::tokenizer::{SpaceTokenizer, Tokenizer};
+use grok::{Grok, Pattern};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock, PoisonError};
 // a build.rs generates patterns.rs at compilation time
 // it contains all GROK base patterns loaded here
 include!(concat!(env!("OUT_DIR"), "/patterns.rs"));
 
 /// Return the default GROK base patterns.
-pub fn base_patterns<'a>() -> &'a [(&'a str, &'a str)] {
+pub fn base_patterns() -> &'static [(&'static str, &'static str)] {
     PATTERNS
 }
 
+// the patterns we can trust, the most specific ones first
+const GOOD_PATTERNS: &[&str] = &[
+    "SYSLOGTIMESTAMP",
+    "DATE",
+    "IP",
+    "MAC",
+    "HOSTPORT",
+    "URI",
+    "PATH",
+    "QS",
+    "INT",
+    "NUMBER",
+    "BASE16NUM",
+    "USERNAME",
+    "HOSTNAME",
+];
+
+// the patterns telling the type of a variable, the most specific ones first
+const TYPE_PATTERNS: &[(&str, VariableType)] = &[
+    ("INT", VariableType::Int),
+    ("NUMBER", VariableType::Float),
+    ("UUID", VariableType::Uuid),
+    ("IP", VariableType::Ip),
+    ("TIMESTAMP_ISO8601", VariableType::Timestamp),
+    ("SYSLOGTIMESTAMP", VariableType::Timestamp),
+    ("HTTPDATE", VariableType::Timestamp),
+    ("DATESTAMP", VariableType::Timestamp),
+    ("DATE", VariableType::Timestamp),
+    ("BASE16NUM", VariableType::Hex),
+    ("PATH", VariableType::Path),
+];
+
+/// The type of a variable, the first of `TYPE_PATTERNS` matching the whole value
+pub(crate) fn variable_type(value: &str) -> VariableType {
+    static PATTERNS: OnceLock<Vec<(Pattern, VariableType)>> = OnceLock::new();
+    let patterns = PATTERNS.get_or_init(|| {
+        let mut grok = base_grok();
+        TYPE_PATTERNS
+            .iter()
+            .filter_map(|&(name, variable_type)| {
+                let pattern = grok.compile(&format!("^%{{{}}}$", name), false).ok()?;
+                Some((pattern, variable_type))
+            })
+            .collect()
+    });
+    patterns
+        .iter()
+        .find(|(pattern, variable_type)| {
+            // words made of the letters a to f are not hexadecimal numbers
+            pattern.match_against(value).is_some()
+                && (*variable_type != VariableType::Hex
+                    || value.chars().any(|c| c.is_ascii_digit()))
+        })
+        .map_or(VariableType::Text, |&(_, variable_type)| variable_type)
+}
+
 /// Check if the pattern is a good one that we can trust
-pub fn is_a_good_pattern<'a>(pattern: &'a str) -> bool {
-    let good_patterns = vec![
-        "PATH",
-        "URI",
-        "DATE",
-        "MAC",
-        "IP",
-        "HOSTPORT",
-        "USERNAME",
-        "BASE16NUM",
-        "SYSLOGTIMESTAMP",
-        "QS",
-        "HOSTNAME",
-    ];
-    if good_patterns.contains(&pattern) {
-        true
+pub fn is_a_good_pattern(pattern: &str) -> bool {
+    GOOD_PATTERNS.contains(&pattern)
+}
+
+/// Escape the regex and GROK special characters of a constant token
+fn escape(constant: &str) -> String {
+    let mut escaped = String::with_capacity(constant.len());
+    for c in constant.chars() {
+        if "\\.^$|?*+()[]{}%".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// How the text left in the templates by a filter pattern or a masking rule, e.g. `<ip>`,
+/// is matched by the generated GROK patterns
+#[derive(Debug, Clone)]
+pub(crate) struct Placeholder {
+    text: String,
+    expression: Expression,
+}
+
+#[derive(Debug, Clone)]
+enum Expression {
+    // a GROK pattern captured in a field, e.g. `%{IP:ip}`
+    Grok { pattern: String, field: String },
+    // a regex captured in a field, e.g. `(?<hex>0x[0-9a-f]+)`
+    Regex { regex: String, field: String },
+    // a GROK expression capturing its own fields, used as is
+    Verbatim(String),
+}
+
+// the name and alias of a `%{NAME:alias}` GROK pattern, or of `%{NAME}` with an empty alias
+fn grok_reference(expression: &str) -> Option<(&str, &str)> {
+    let inner = expression.strip_prefix("%{")?.strip_suffix('}')?;
+    if inner.contains(['{', '}']) {
+        return None;
+    }
+    let mut parts = inner.split(':');
+    let name = parts.next()?;
+    Some((name, parts.next().unwrap_or("")))
+}
+
+impl Placeholder {
+    /// The placeholders of the filter pattern, one for every field it captures, as the name of
+    /// the capture replaces the whole token
+    pub fn for_filter_pattern(filter_pattern: &str) -> Vec<Placeholder> {
+        if let Some((pattern, field)) = grok_reference(filter_pattern) {
+            return vec![Placeholder {
+                text: format!("<{}>", field),
+                expression: Expression::Grok {
+                    pattern: pattern.to_string(),
+                    field: field.to_string(),
+                },
+            }];
+        }
+        filter_pattern
+            .split("%{")
+            .skip(1)
+            .filter_map(|reference| {
+                let reference = &reference[..reference.find('}')?];
+                let field = reference.split(':').nth(1)?;
+                Some(Placeholder {
+                    text: format!("<{}>", field),
+                    expression: Expression::Verbatim(format!("(?:{})", filter_pattern)),
+                })
+            })
+            .collect()
+    }
+
+    /// The placeholder of a masking rule, captured in a field named after the label
+    pub fn for_mask(pattern: &str, label: &str) -> Placeholder {
+        let field = label.to_lowercase();
+        let expression = match grok_reference(pattern) {
+            Some((name, "")) => Expression::Grok {
+                pattern: name.to_string(),
+                field,
+            },
+            _ => Expression::Regex {
+                regex: pattern.to_string(),
+                field,
+            },
+        };
+        Placeholder {
+            text: format!("<{}>", label),
+            expression,
+        }
+    }
+}
+
+// the field name, suffixed by its number of occurrences from the second one, e.g. `ip_2`
+fn unique_field(field_counts: &mut HashMap<String, usize>, field: &str) -> String {
+    let count = field_counts.entry(field.to_string()).or_insert(0);
+    *count += 1;
+    if *count == 1 {
+        field.to_string()
     } else {
-        false
+        format!("{}_{}", field, count)
+    }
+}
+
+fn base_grok() -> Grok {
+    base_patterns_into(Grok::empty())
+}
+
+// the base patterns added to the given ones
+fn base_patterns_into(mut grok: Grok) -> Grok {
+    for &(name, pattern_definition) in base_patterns() {
+        grok.insert_definition(name, pattern_definition);
     }
+    grok
 }
 
 /// stores all compiled patterns at one place
 #[derive(Debug)]
 pub struct GrokGenerator {
-    grok: grok::Grok,                                  // the grok processor
-    compiled_patterns: Vec<(String, String, Pattern)>, // (pattern_name, grok_pattern, compiled_pattern)
+    compiled_patterns: Vec<(String, String, Pattern, Pattern)>, // (pattern_name, grok_pattern, compiled_pattern, anchored_pattern)
+    // the default and base patterns, to check that the generated patterns compile
+    // (grok compiles through `&mut`)
+    grok: Mutex<Grok>,
 }
 
 impl GrokGenerator {
     /// generates a GrokGenerator initialized with the base patterns from the patterns folder
     pub fn new_with_base_patterns() -> Self {
-        let mut grok = grok::Grok::empty();
+        let mut grok = base_grok();
         let mut compiled_patterns = Vec::new();
 
-        // load base patterns
-        for &(name, pattern_definition) in base_patterns() {
-            grok.insert_definition(name, pattern_definition);
-        }
-
-        // compile base patterns
-        for &(pattern_name, _) in base_patterns() {
-            // compiled_patterns.push(grok.compile("%{USERNAME}", false).unwrap());
-            if is_a_good_pattern(pattern_name) {
+        // compile base patterns, the most specific ones first
+        for &pattern_name in GOOD_PATTERNS {
+            if base_patterns()
+                .iter()
+                .any(|&(name, _)| name == pattern_name)
+            {
                 let grok_pattern = format!("%{{{}}}", pattern_name);
                 let compiled_pattern = grok
                     .compile(&format!("^{}.?$", grok_pattern), false)
                     .unwrap();
-                compiled_patterns.push((pattern_name.to_owned(), grok_pattern, compiled_pattern));
+                let anchored_pattern = grok.compile(&format!("^{}$", grok_pattern), false).unwrap();
+                compiled_patterns.push((
+                    pattern_name.to_owned(),
+                    grok_pattern,
+                    compiled_pattern,
+                    anchored_pattern,
+                ));
             }
         }
 
         GrokGenerator {
-            grok,
             compiled_patterns,
+            // the placeholders may use the default GROK patterns
+            grok: Mutex::new(base_patterns_into(Grok::with_patterns())),
         }
     }
 
@@ -72,48 +233,156 @@ impl GrokGenerator {
     /// the score is the count of string matching this pattern
     fn pattern_score_against_string_vector(
         &self,
-        pattern_name: &String,
         compiled_pattern: &Pattern,
-        strings: &Vec<String>,
+        strings: &[String],
     ) -> usize {
         strings
             .iter()
             .filter(|string| match compiled_pattern.match_against(string) {
-                Some(matches) => {
-                    // println!(
-                    //     "{} matches => {:?}",
-                    //     pattern_name,
-                    //     matches.get(pattern_name)
-                    // );
-                    matches.len() > 0
-                }
+                Some(matches) => !matches.is_empty(),
                 None => false,
             })
             .count()
     }
 
     /// takes as input a list of strings and detect the best base GROK pattern for it
-    pub fn detect_grok_for_a_list_of_string(&self, strings: &Vec<String>) -> Option<String> {
+    pub fn detect_grok_for_a_list_of_string(&self, strings: &[String]) -> Option<String> {
         let mut best_match = None;
         let mut best_match_score = 0;
-        for (pattern_name, grok_pattern, compiled_patterns) in self.compiled_patterns.iter() {
+        for (_pattern_name, grok_pattern, compiled_patterns, _) in self.compiled_patterns.iter() {
             let current_score =
-                self.pattern_score_against_string_vector(pattern_name, compiled_patterns, strings);
+                self.pattern_score_against_string_vector(compiled_patterns, strings);
             if current_score > best_match_score {
                 best_match_score = current_score;
                 best_match = Some(grok_pattern.to_owned());
-                // println!(
-                //     "[New Match score] {:?} => {} => {:?}",
-                //     strings, best_match_score, pattern_name
-                // );
             }
         }
         best_match
     }
 
+    /// the base pattern name for a wildcard, the first pattern matching every sampled value
+    /// falls back to NOTSPACE, or DATA when a value contains spaces or nothing was sampled
+    fn pattern_for_values(&self, values: &[String]) -> &str {
+        if !values.is_empty() {
+            if let Some((pattern_name, _, _, _)) =
+                self.compiled_patterns.iter().find(|(_, _, _, anchored)| {
+                    self.pattern_score_against_string_vector(anchored, values) == values.len()
+                })
+            {
+                return pattern_name;
+            }
+        }
+        if values.is_empty()
+            || values
+                .iter()
+                .any(|v| v.is_empty() || v.contains(char::is_whitespace))
+        {
+            "DATA"
+        } else {
+            "NOTSPACE"
+        }
+    }
+
     /// takes a log cluster a generate a GROK line pattern from it
-    #[allow(dead_code)]
-    pub fn generate_grok_from_logcluster(_logcluster: LogCluster) -> Option<String> {
-        Some("test".to_string())
+    ///
+    /// Constant tokens are escaped and every wildcard is captured by the base pattern matching
+    /// all of its sampled values, in a field named after the pattern, e.g. `ip`, `ip_2`.
+    /// The pattern is anchored and assumes the tokens are separated by single spaces,
+    /// see `generate_grok_with_tokenizer` for other tokenizers.
+    /// Returns `None` if the resulting pattern does not compile.
+    /// ```
+    /// use drain_rs::grok_generator::GrokGenerator;
+    /// let mut drain = drain_rs::DrainTree::new();
+    /// drain.add_log_line("connection from 10.0.0.1 closed (after 12 ms)");
+    /// let cluster = drain.add_log_line("connection from 10.0.0.2 closed (after 7 ms)").unwrap();
+    ///
+    /// let generator = GrokGenerator::new_with_base_patterns();
+    /// assert_eq!(
+    ///     generator.generate_grok_from_logcluster(cluster).unwrap(),
+    ///     "^connection from %{IP:ip} closed \\(after %{INT:int} ms\\)$"
+    /// );
+    /// ```
+    pub fn generate_grok_from_logcluster(&self, logcluster: &LogCluster) -> Option<String> {
+        self.generate_grok_with_tokenizer(logcluster, &SpaceTokenizer)
+    }
+
+    /// takes a log cluster a generate a GROK line pattern from it, the tokens are joined
+    /// the way the given tokenizer renders templates
+    ///
+    /// The tokens replaced by filter patterns or masking rules are escaped as constants,
+    /// see `DrainTree::grok_pattern` to match them with their patterns.
+    pub fn generate_grok_with_tokenizer(
+        &self,
+        logcluster: &LogCluster,
+        tokenizer: &dyn Tokenizer,
+    ) -> Option<String> {
+        self.generate_grok_with_placeholders(logcluster, tokenizer, &[])
+    }
+
+    /// Like `generate_grok_with_tokenizer`, the placeholders left by the filter patterns and
+    /// masking rules being matched by their patterns
+    pub(crate) fn generate_grok_with_placeholders(
+        &self,
+        logcluster: &LogCluster,
+        tokenizer: &dyn Tokenizer,
+        placeholders: &[Placeholder],
+    ) -> Option<String> {
+        let mut field_counts: HashMap<String, usize> = HashMap::new();
+        let tokens = logcluster
+            .tokens()
+            .iter()
+            .map(|token| match token {
+                Token::Val(constant) => {
+                    self.constant_pattern(constant, placeholders, &mut field_counts)
+                }
+                Token::WildCard(_) => {
+                    let values = token.as_string_vector().unwrap_or_default();
+                    let pattern_name = self.pattern_for_values(&values);
+                    let field = unique_field(&mut field_counts, &pattern_name.to_lowercase());
+                    format!("%{{{}:{}}}", pattern_name, field)
+                }
+            })
+            .collect::<Vec<String>>();
+        let pattern = format!("^{}$", tokenizer.join(&tokens));
+        self.grok
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .compile(&pattern, true)
+            .ok()?;
+        Some(pattern)
+    }
+
+    /// The escaped constant, the placeholders it contains being replaced by their patterns
+    fn constant_pattern(
+        &self,
+        constant: &str,
+        placeholders: &[Placeholder],
+        field_counts: &mut HashMap<String, usize>,
+    ) -> String {
+        let mut pattern = String::new();
+        let mut rest = constant;
+        loop {
+            let next = placeholders
+                .iter()
+                .filter_map(|p| rest.find(p.text.as_str()).map(|start| (start, p)))
+                .min_by_key(|&(start, p)| (start, std::cmp::Reverse(p.text.len())));
+            let (start, placeholder) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            pattern.push_str(&escape(&rest[..start]));
+            pattern.push_str(&match &placeholder.expression {
+                Expression::Grok { pattern, field } => {
+                    format!("%{{{}:{}}}", pattern, unique_field(field_counts, field))
+                }
+                Expression::Regex { regex, field } => {
+                    format!("(?<{}>{})", unique_field(field_counts, field), regex)
+                }
+                Expression::Verbatim(expression) => expression.clone(),
+            });
+            rest = &rest[start + placeholder.text.len()..];
+        }
+        pattern.push_str(&escape(rest));
+        pattern
     }
 }
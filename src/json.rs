@@ -0,0 +1,139 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Escape a JSON object key as a JSON pointer reference token
+fn escape_key(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// Collect every scalar of the value, keyed by its JSON pointer
+fn flatten(value: &Value, pointer: &mut String, fields: &mut BTreeMap<String, String>) {
+    let len = pointer.len();
+    match value {
+        Value::Object(object) => {
+            for (key, child) in object {
+                pointer.push('/');
+                pointer.push_str(&escape_key(key));
+                flatten(child, pointer, fields);
+                pointer.truncate(len);
+            }
+        }
+        Value::Array(array) => {
+            for (index, child) in array.iter().enumerate() {
+                pointer.push('/');
+                pointer.push_str(&index.to_string());
+                flatten(child, pointer, fields);
+                pointer.truncate(len);
+            }
+        }
+        Value::String(s) => {
+            fields.insert(pointer.clone(), s.clone());
+        }
+        Value::Null => {}
+        other => {
+            fields.insert(pointer.clone(), other.to_string());
+        }
+    }
+}
+
+/// The message at the JSON pointer and every other scalar field of a JSON log line
+///
+/// Returns `None` if the line is not JSON or has no message at the pointer.
+pub(crate) fn extract(
+    log_line: &str,
+    message_pointer: &str,
+) -> Option<(String, BTreeMap<String, String>)> {
+    let value: Value = serde_json::from_str(log_line).ok()?;
+    let message = match value.pointer(message_pointer)? {
+        Value::String(s) => s.clone(),
+        Value::Null => return None,
+        other => other.to_string(),
+    };
+    let mut fields = BTreeMap::new();
+    flatten(&value, &mut String::new(), &mut fields);
+    let message_children = format!("{}/", message_pointer);
+    fields
+        .retain(|pointer, _| pointer != message_pointer && !pointer.starts_with(&message_children));
+    Some((message, fields))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Decision, DrainTree};
+
+    const INFO: &str =
+        r#"{"service":"api","level":"INFO","log":{"message":"login of user bob"},"pid":1}"#;
+    const WARN: &str = r#"{"service":"api","level":"WARN","log":{"message":"login of user bob"}}"#;
+
+    fn drain() -> DrainTree {
+        DrainTree::new()
+            .max_depth(2)
+            .json_message("/log/message")
+            .partition_fields(vec!["/service", "/level"])
+    }
+
+    #[test]
+    fn messages_are_clustered_by_partition() {
+        let mut drain = drain();
+        let info = drain.add_log_line_id(INFO).unwrap();
+        let same = drain.add_log_line_id(
+            r#"{"service":"api","level":"INFO","log":{"message":"login of user eve"},"pid":2}"#,
+        );
+        assert_eq!(same, Some(info));
+        assert_ne!(drain.add_log_line_id(WARN).unwrap(), info);
+        assert_ne!(drain.add_log_line_id("login of user bob").unwrap(), info);
+        assert_eq!(drain.partitions.len(), 2);
+    }
+
+    #[test]
+    fn clusters_hold_their_partition_and_metadata() {
+        let mut drain = drain();
+        let info = drain.add_log_line_id(INFO).unwrap();
+        drain.add_log_line(
+            r#"{"service":"api","level":"INFO","log":{"message":"login of user eve"},"pid":2}"#,
+        );
+        let plain = drain.add_log_line_id("login of user bob").unwrap();
+
+        let cluster = drain.cluster_by_id(info).unwrap();
+        assert_eq!(cluster.as_string(), "login of user <*>");
+        assert_eq!(cluster.partition()["/level"], "INFO");
+        assert_eq!(
+            cluster.metadata()["/pid"].iter().collect::<Vec<&String>>(),
+            ["1", "2"]
+        );
+        assert!(drain.cluster_by_id(plain).unwrap().partition().is_empty());
+    }
+
+    #[test]
+    fn lookups_stay_in_the_partition() {
+        let mut drain = drain();
+        drain.add_log_line(INFO);
+        let warn = drain.add_log_line_id(WARN).unwrap();
+
+        let line = r#"{"service":"api","level":"WARN","log":{"message":"login of user joe"}}"#;
+        assert_eq!(drain.log_group(line).unwrap().id(), warn);
+        let explanation = drain.explain(line).unwrap();
+        assert_eq!(explanation.message, "login of user joe");
+        assert_eq!(explanation.partition["/level"], "WARN");
+        assert_eq!(explanation.decision, Decision::Matched(warn));
+    }
+
+    #[test]
+    fn clusters_are_only_merged_within_their_partition() {
+        let mut drain = drain();
+        drain.add_log_line(INFO);
+        drain.add_log_line(WARN);
+        assert_eq!(drain.merge_clusters(0.5), vec![]);
+    }
+
+    #[test]
+    fn partitions_are_serialized() {
+        let mut drain = drain();
+        drain.add_log_line(INFO);
+        let warn = drain.add_log_line_id(WARN).unwrap();
+
+        let json = serde_json::to_string(&drain).unwrap();
+        let restored: DrainTree = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.log_group(WARN).unwrap().id(), warn);
+    }
+}
@@ -23,23 +23,86 @@
 //! [Node * is online] [Node * going offline] //the individual text templates for this simple case
 #![warn(missing_debug_implementations, rust_2018_idioms, missing_docs)]
 
+mod concurrent;
+mod error;
+mod evaluation;
+mod eviction;
+mod explain;
 pub mod grok_generator;
+mod json;
 mod log_cluster;
+mod masking;
+mod merge;
+mod multiline;
+mod observer;
+mod parse;
+mod sampling;
+mod similarity;
+mod snapshot;
+mod statistics;
+mod timestamp;
 mod token;
+mod tokenizer;
+mod tuner;
 
 // use crate::wildcard;
-use log_cluster::{GroupSimilarity, LogCluster};
-use token::Token;
+pub use concurrent::ConcurrentDrainTree;
+pub use error::{Error, PatternError, PatternKind};
+pub use evaluation::{evaluate, Evaluation, GroundTruth};
+pub use eviction::{EvictionPolicy, MemoryBudget};
+pub use explain::{BranchReason, Candidate, Decision, Explanation, PrefixStep};
+pub use log_cluster::{ClusterId, GroupSimilarity, LogCluster};
+pub use masking::MaskingRule;
+pub use multiline::{Event, MultilineAggregator};
+pub use observer::{TemplateEvent, TemplateEventKind, TemplateObserver};
+pub use parse::{ParsedLine, Variable};
+pub use sampling::SamplingStrategy;
+pub use similarity::{Lcs, SeqDist, Similarity, TokenRef, TypedMatch, Weighted};
+pub use statistics::{VariableStats, VariableType};
+pub use tokenizer::{
+    DelimiterTokenizer, PunctuationTokenizer, SpaceTokenizer, Tokenizer, WhitespaceTokenizer,
+};
+pub use tuner::{Objective, Trial, Tuner, Tuning};
 
+use eviction::{Eviction, EvictionCallback, EvictionIndex, Location, Usage};
+use masking::{Mask, MaskedLine};
+use merge::AutoMerge;
+use observer::Observer;
+use parse::ProcessedLine;
+use sampling::Sampling;
+use timestamp::TimestampField;
+
+/// Number of log lines processed by every thread at once by `add_log_lines_parallel`
+const PARALLEL_BATCH_LINES: usize = 4096;
+
+/// Named fields extracted from a log line by the overall log pattern or from a JSON log line
+type Fields = BTreeMap<String, String>;
+
+use token::{Token, WildcardKind};
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Leaf {
     log_groups: Vec<LogCluster>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    redirects: Vec<Redirect>,
+}
+
+/// A cluster merged away from a leaf, whose log lines now go to the leaf of the cluster it
+/// was merged into
+#[derive(Debug, Serialize, Deserialize)]
+struct Redirect {
+    id: ClusterId,
+    // the keys followed from the length bucket to the leaf of the surviving cluster
+    #[serde(with = "token::prefix_path")]
+    path: Vec<Token>,
 }
 
 struct GroupAndSimilarity {
@@ -47,15 +110,69 @@ struct GroupAndSimilarity {
     similarity: GroupSimilarity,
 }
 
+/// Where the identifiers of new clusters come from
+enum NextId<'a> {
+    Counter(&'a mut ClusterId),
+    Shared(&'a AtomicU64),
+}
+
+impl NextId<'_> {
+    fn take(&mut self) -> ClusterId {
+        match self {
+            NextId::Counter(next_id) => {
+                let id = **next_id;
+                **next_id += 1;
+                id
+            }
+            NextId::Shared(next_id) => next_id.fetch_add(1, AtomicOrdering::Relaxed),
+        }
+    }
+}
+
+/// Settings of the tree along with the bookkeeping of a single log line insertion
+struct Insertion<'a> {
+    max_depth: u16,
+    max_children: u16,
+    min_similarity: f32,
+    numeric_prefixes: bool,
+    similarity: &'a dyn Similarity,
+    next_id: NextId<'a>,
+    partition: &'a Fields,
+    metadata: &'a Fields,
+    // milliseconds since the Unix epoch
+    timestamp: i64,
+    rate_window: f64,
+    sampling: Sampling,
+    tick: u64,
+    // whether size_delta should be computed, as it walks the touched cluster twice
+    track_size: bool,
+    // whether changed_from should be set, as it copies the template of the touched cluster
+    observe: bool,
+    // the keys followed to the leaf of the touched cluster, recorded when set
+    path: Option<Vec<Token>>,
+    created: bool,
+    // the template tokens before the log line turned some of them into wildcards
+    changed_from: Option<Vec<String>>,
+    size_delta: isize,
+}
+
 impl Leaf {
-    fn best_group(&self, log_tokens: &[Token]) -> Option<GroupAndSimilarity> {
+    fn best_group(
+        &self,
+        log_tokens: &[Token],
+        similarity: &dyn Similarity,
+    ) -> Option<GroupAndSimilarity> {
         // find the best group for the currently analyzed log_tokens
         // no cache implemented here
-        let mut max_similarity = self.log_groups.get(0)?.similarity(log_tokens);
+        let log = TokenRef::all(log_tokens).collect::<Vec<TokenRef<'_>>>();
+        let mut template = TokenRef::all(self.log_groups.first()?.tokens()).collect::<Vec<_>>();
+        let mut max_similarity = similarity.similarity(&template, &log);
         let mut group_index: usize = 0;
         for i in 1..self.log_groups.len() {
             let group = self.log_groups.get(i).unwrap();
-            let similarity = group.similarity(log_tokens);
+            template.clear();
+            template.extend(TokenRef::all(group.tokens()));
+            let similarity = similarity.similarity(&template, &log);
             if similarity > max_similarity {
                 max_similarity = similarity;
                 group_index = i;
@@ -70,38 +187,98 @@ impl Leaf {
     fn add_to_group(
         &mut self,
         group: Option<GroupAndSimilarity>,
-        min_similarity: &f32,
         log_tokens: &[Token],
+        insertion: &mut Insertion<'_>,
     ) -> Option<&LogCluster> {
         match group {
             Some(gas) => {
-                if gas.similarity.exact_similarity < *min_similarity {
-                    let cluster = LogCluster::new(log_tokens.to_vec());
-                    self.log_groups.push(cluster);
-                    self.log_groups.last()
+                if gas.similarity.exact_similarity < insertion.min_similarity {
+                    self.new_group(log_tokens, insertion)
                 } else {
-                    self.log_groups
+                    let group = self
+                        .log_groups
                         .get_mut(gas.group_index)
-                        .unwrap_or_else(|| panic!("bad log group index [{}]", gas.group_index))
-                        .add_log(log_tokens);
+                        .unwrap_or_else(|| panic!("bad log group index [{}]", gas.group_index));
+                    let size_before = if insertion.track_size {
+                        group.approximate_size()
+                    } else {
+                        0
+                    };
+                    let template_before = if insertion.observe {
+                        Some(group.template_tokens())
+                    } else {
+                        None
+                    };
+                    group.add_log(log_tokens, &insertion.sampling);
+                    insertion.changed_from =
+                        template_before.filter(|before| *before != group.template_tokens());
+                    group.add_metadata(insertion.metadata);
+                    group.touch(insertion.tick);
+                    group.observe(insertion.timestamp, insertion.rate_window);
+                    if insertion.track_size {
+                        insertion.size_delta =
+                            group.approximate_size() as isize - size_before as isize;
+                    }
                     self.log_groups.get(gas.group_index)
                 }
             }
-            None => {
-                let cluster = LogCluster::new(log_tokens.to_vec());
-                self.log_groups.push(cluster);
-                self.log_groups.last()
-            }
+            None => self.new_group(log_tokens, insertion),
+        }
+    }
+
+    fn new_group(
+        &mut self,
+        log_tokens: &[Token],
+        insertion: &mut Insertion<'_>,
+    ) -> Option<&LogCluster> {
+        let mut cluster = LogCluster::new(insertion.next_id.take(), log_tokens.to_vec())
+            .in_partition(insertion.partition);
+        cluster.add_metadata(insertion.metadata);
+        cluster.touch(insertion.tick);
+        cluster.observe(insertion.timestamp, insertion.rate_window);
+        insertion.created = true;
+        if insertion.track_size {
+            insertion.size_delta = cluster.approximate_size() as isize;
         }
+        self.log_groups.push(cluster);
+        self.log_groups.last()
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Inner {
+    #[serde(with = "token::prefix_keys")]
     children: HashMap<Token, Node>,
     depth: usize,
 }
 
+impl Inner {
+    /// The child key to follow for a prefix token, falling back to the wildcard branch once
+    /// `max_children` is reached
+    fn branch(&self, token: Token, max_children: &u16) -> (Token, Option<BranchReason>) {
+        if !self.children.contains_key(&token) && self.children.len().ge(&(*max_children as usize))
+        {
+            (Token::new_empty_wildcard(), Some(BranchReason::MaxChildren))
+        } else {
+            (token, None)
+        }
+    }
+
+    /// The child key for a prefix key, the tokens containing a digit following the `<*>` branch
+    /// of the trees saved before they had a `<NUM>` branch of their own
+    fn bridge(&self, token: Token) -> Token {
+        let any = Token::new_empty_wildcard();
+        if token == Token::new_typed_wildcard(WildcardKind::Number)
+            && !self.children.contains_key(&token)
+            && self.children.contains_key(&any)
+        {
+            any
+        } else {
+            token
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 enum Node {
     Inner(Inner),
@@ -136,11 +313,7 @@ impl Display for Node {
 impl Node {
     fn log_groups(&self) -> Vec<&LogCluster> {
         match self {
-            Node::Leaf(leaf) => leaf
-                .log_groups
-                .iter()
-                .map(|n| n.borrow())
-                .collect::<Vec<&LogCluster>>(),
+            Node::Leaf(leaf) => leaf.log_groups.iter().collect::<Vec<&LogCluster>>(),
             Node::Inner(inner) => inner
                 .children
                 .values()
@@ -149,6 +322,301 @@ impl Node {
         }
     }
 
+    fn log_groups_mut(&mut self) -> Vec<&mut LogCluster> {
+        match self {
+            Node::Leaf(leaf) => leaf.log_groups.iter_mut().collect(),
+            Node::Inner(inner) => inner
+                .children
+                .values_mut()
+                .flat_map(|n| n.log_groups_mut())
+                .collect(),
+        }
+    }
+
+    /// Whether the node holds no log cluster, a leaf being empty whatever its redirects
+    fn is_empty(&self) -> bool {
+        match self {
+            Node::Leaf(leaf) => leaf.log_groups.is_empty(),
+            Node::Inner(inner) => inner.children.is_empty(),
+        }
+    }
+
+    /// Whether the node holds neither a log cluster nor a redirect
+    fn is_unused(&self) -> bool {
+        match self {
+            Node::Leaf(leaf) => leaf.log_groups.is_empty() && leaf.redirects.is_empty(),
+            Node::Inner(inner) => inner.children.is_empty(),
+        }
+    }
+
+    /// Store an existing cluster in the leaf its template routes to, the keys followed being
+    /// pushed to the path
+    fn place_cluster(
+        &mut self,
+        cluster: LogCluster,
+        max_depth: u16,
+        max_children: u16,
+        numeric: bool,
+        path: &mut Vec<Token>,
+    ) {
+        match self {
+            Node::Inner(inner) => {
+                let tokens = cluster.tokens();
+                let (token, _) = Node::prefix_token(&tokens[inner.depth], numeric);
+                let token = inner.bridge(token);
+                let (key, child) =
+                    if inner.depth == tokens.len() - 1 || inner.depth == max_depth as usize {
+                        (
+                            token.clone(),
+                            inner.children.entry(token).or_insert_with(Node::leaf),
+                        )
+                    } else {
+                        let depth = inner.depth + 1;
+                        let (owned_token, _) = inner.branch(token, &max_children);
+                        (
+                            owned_token.clone(),
+                            inner
+                                .children
+                                .entry(owned_token)
+                                .or_insert_with(|| Node::inner(depth)),
+                        )
+                    };
+                path.push(key);
+                child.place_cluster(cluster, max_depth, max_children, numeric, path)
+            }
+            Node::Leaf(leaf) => leaf.log_groups.push(cluster),
+        }
+    }
+
+    /// Remove the log cluster from the leaf at the end of the path, pruning the nodes left empty
+    fn remove_at(&mut self, path: &[Token], id: ClusterId) -> Option<LogCluster> {
+        match (self, path.split_first()) {
+            (Node::Leaf(leaf), None) => {
+                let index = leaf.log_groups.iter().position(|c| c.id() == id)?;
+                Some(leaf.log_groups.remove(index))
+            }
+            (Node::Inner(inner), Some((key, rest))) => {
+                let child = inner.children.get_mut(key)?;
+                let cluster = child.remove_at(rest, id)?;
+                if child.is_empty() {
+                    inner.children.remove(key);
+                }
+                Some(cluster)
+            }
+            _ => None,
+        }
+    }
+
+    /// Take the log cluster out of the leaf at the end of the path, leaving the nodes in place
+    fn take_at(&mut self, path: &[Token], id: ClusterId) -> Option<LogCluster> {
+        let leaf = self.leaf_at_mut(path)?;
+        let index = leaf.log_groups.iter().position(|c| c.id() == id)?;
+        Some(leaf.log_groups.remove(index))
+    }
+
+    /// Call the visitor with every leaf under this node and the keys followed to it
+    fn visit_leaves(&self, path: &mut Vec<Token>, visitor: &mut dyn FnMut(&[Token], &Leaf)) {
+        match self {
+            Node::Leaf(leaf) => visitor(path, leaf),
+            Node::Inner(inner) => {
+                for (key, child) in inner.children.iter() {
+                    path.push(key.clone());
+                    child.visit_leaves(path, visitor);
+                    path.pop();
+                }
+            }
+        }
+    }
+
+    /// The keys followed to the leaf of the cluster, if it is under this node
+    fn path_to(&self, id: ClusterId) -> Option<Vec<Token>> {
+        match self {
+            Node::Leaf(leaf) => leaf.log_groups.iter().any(|c| c.id() == id).then(Vec::new),
+            Node::Inner(inner) => inner.children.iter().find_map(|(key, child)| {
+                let mut path = child.path_to(id)?;
+                path.insert(0, key.clone());
+                Some(path)
+            }),
+        }
+    }
+
+    fn leaf_at(&self, path: &[Token]) -> Option<&Leaf> {
+        match (self, path.split_first()) {
+            (Node::Leaf(leaf), None) => Some(leaf),
+            (Node::Inner(inner), Some((key, rest))) => inner.children.get(key)?.leaf_at(rest),
+            _ => None,
+        }
+    }
+
+    fn leaf_at_mut(&mut self, path: &[Token]) -> Option<&mut Leaf> {
+        match (self, path.split_first()) {
+            (Node::Leaf(leaf), None) => Some(leaf),
+            (Node::Inner(inner), Some((key, rest))) => {
+                inner.children.get_mut(key)?.leaf_at_mut(rest)
+            }
+            _ => None,
+        }
+    }
+
+    /// The leaf at the end of the path, created along with its parents if needed
+    fn leaf_at_or_insert(&mut self, path: &[Token]) -> Option<&mut Leaf> {
+        match (self, path.split_first()) {
+            (Node::Leaf(leaf), None) => Some(leaf),
+            (Node::Inner(inner), Some((key, rest))) => {
+                let depth = inner.depth + 1;
+                inner
+                    .children
+                    .entry(key.clone())
+                    .or_insert_with(|| {
+                        if rest.is_empty() {
+                            Node::leaf()
+                        } else {
+                            Node::inner(depth)
+                        }
+                    })
+                    .leaf_at_or_insert(rest)
+            }
+            _ => None,
+        }
+    }
+
+    /// The redirect of the leaf to follow for the tokens, when the clusters of the leaf are
+    /// not similar enough and the cluster the redirect leads to is, as the path to the leaf of
+    /// that cluster, the leaf and the index of the cluster
+    fn redirect<'n>(
+        &'n self,
+        leaf: &'n Leaf,
+        log_tokens: &[Token],
+        similarity: &dyn Similarity,
+        min_similarity: f32,
+    ) -> Option<(&'n [Token], &'n Leaf, usize)> {
+        if leaf.redirects.is_empty()
+            || leaf
+                .best_group(log_tokens, similarity)
+                .is_some_and(|gas| gas.similarity.exact_similarity >= min_similarity)
+        {
+            return None;
+        }
+        leaf.redirects.iter().find_map(|redirect| {
+            let target = self.leaf_at(&redirect.path)?;
+            let index = target
+                .log_groups
+                .iter()
+                .position(|c| c.has_id(redirect.id))?;
+            let similar = target.log_groups[index]
+                .similarity(log_tokens, similarity)
+                .exact_similarity
+                >= min_similarity;
+            similar.then_some((redirect.path.as_slice(), target, index))
+        })
+    }
+
+    /// Add the log tokens from this length bucket, like `add_child_recur`, following the
+    /// redirect of the reached leaf if any
+    fn add_log(
+        &mut self,
+        log_tokens: &[Token],
+        insertion: &mut Insertion<'_>,
+    ) -> Option<&LogCluster> {
+        let redirect = self
+            .find_leaf(
+                log_tokens,
+                insertion.max_depth,
+                insertion.max_children,
+                insertion.numeric_prefixes,
+            )
+            .and_then(|leaf| {
+                self.redirect(
+                    leaf,
+                    log_tokens,
+                    insertion.similarity,
+                    insertion.min_similarity,
+                )
+            })
+            .map(|(path, _, index)| (path.to_vec(), index));
+        let (path, group_index) = match redirect {
+            Some(redirect) => redirect,
+            None => return self.add_child_recur(0, log_tokens, insertion),
+        };
+        let leaf = self.leaf_at_mut(&path)?;
+        if let Some(followed) = insertion.path.as_mut() {
+            *followed = path;
+        }
+        let similarity = leaf.log_groups[group_index].similarity(log_tokens, insertion.similarity);
+        let group = GroupAndSimilarity {
+            group_index,
+            similarity,
+        };
+        leaf.add_to_group(Some(group), log_tokens, insertion)
+    }
+
+    /// Point the redirects to the leaf of the cluster now holding their identifier, dropping
+    /// the redirects to a removed cluster or to their own leaf, and the leaves left unused
+    fn retarget_redirects(&mut self) {
+        let mut paths = HashMap::new();
+        self.visit_leaves(&mut vec![], &mut |path, leaf| {
+            for cluster in leaf.log_groups.iter() {
+                for id in std::iter::once(cluster.id()).chain(cluster.aliases().iter().copied()) {
+                    paths.insert(id, path.to_vec());
+                }
+            }
+        });
+        self.retain_redirects(&mut vec![], &paths);
+    }
+
+    fn retain_redirects(&mut self, path: &mut Vec<Token>, paths: &HashMap<ClusterId, Vec<Token>>) {
+        match self {
+            Node::Leaf(leaf) => {
+                leaf.redirects
+                    .retain_mut(|redirect| match paths.get(&redirect.id) {
+                        Some(target) if target != path => {
+                            redirect.path = target.clone();
+                            true
+                        }
+                        _ => false,
+                    })
+            }
+            Node::Inner(inner) => {
+                inner.children.retain(|key, child| {
+                    path.push(key.clone());
+                    child.retain_redirects(path, paths);
+                    path.pop();
+                    !child.is_unused()
+                });
+            }
+        }
+    }
+
+    /// The leaf reached by the tokens from this length bucket, following the same branches
+    /// as an insertion would
+    fn find_leaf(
+        &self,
+        processed_log: &[Token],
+        max_depth: u16,
+        max_children: u16,
+        numeric: bool,
+    ) -> Option<&Leaf> {
+        let mut current = self;
+        for (depth, log_token) in processed_log.iter().enumerate() {
+            let inner = match current {
+                Node::Inner(inner) => inner,
+                Node::Leaf(leaf) => return Some(leaf),
+            };
+            let (token, _) = Node::prefix_token(log_token, numeric);
+            let token = inner.bridge(token);
+            let branch = if depth == processed_log.len() - 1 || depth == max_depth as usize {
+                token
+            } else {
+                inner.branch(token, &max_children).0
+            };
+            current = inner.children.get(&branch)?;
+        }
+        match current {
+            Node::Leaf(leaf) => Some(leaf),
+            Node::Inner(_) => None,
+        }
+    }
+
     fn inner(depth: usize) -> Node {
         Node::Inner(Inner {
             children: HashMap::new(),
@@ -157,67 +625,127 @@ impl Node {
     }
 
     fn leaf() -> Node {
-        Node::Leaf(Leaf { log_groups: vec![] })
+        Node::Leaf(Leaf {
+            log_groups: vec![],
+            redirects: vec![],
+        })
+    }
+
+    /// The key used in the prefix tree for the given log token, tokens containing a digit
+    /// taking the same `<NUM>` wildcard branch when `numeric` is set
+    fn prefix_token(token: &Token, numeric: bool) -> (Token, BranchReason) {
+        match token {
+            Token::Val(s) => {
+                if numeric && s.chars().any(|c| c.is_numeric()) {
+                    (
+                        Token::new_typed_wildcard(WildcardKind::Number),
+                        BranchReason::Numeric,
+                    )
+                } else {
+                    (Token::Val(s.clone()), BranchReason::Exact)
+                }
+            }
+            Token::WildCard(_) => (Token::new_empty_wildcard(), BranchReason::Wildcard),
+        }
     }
 
     fn add_child_recur(
         &mut self,
         depth: usize,
-        max_depth: &u16,
-        max_children: &u16,
-        min_similarity: &f32,
         log_tokens: &[Token],
+        insertion: &mut Insertion<'_>,
     ) -> Option<&LogCluster> {
-        let token = match &log_tokens[depth] {
-            Token::Val(s) => {
-                if s.chars().any(|c| c.is_numeric()) {
-                    Token::new_wildcard("WithNum42".to_string())
-                } else {
-                    Token::Val(s.clone())
-                }
-            }
-            Token::WildCard(_) => Token::new_empty_wildcard(),
-        };
-        if depth == log_tokens.len() - 1 || depth == *max_depth as usize {
+        let (token, _) = Node::prefix_token(&log_tokens[depth], insertion.numeric_prefixes);
+        if depth == log_tokens.len() - 1 || depth == insertion.max_depth as usize {
             if let Node::Inner(node) = self {
+                let token = node.bridge(token);
+                if let Some(path) = insertion.path.as_mut() {
+                    path.push(token.clone());
+                }
                 let child = node.children.entry(token).or_insert_with(Node::leaf);
                 if let Node::Leaf(leaf) = child {
-                    let best_group = leaf.best_group(log_tokens);
-                    return leaf.add_to_group(best_group, min_similarity, log_tokens);
+                    let best_group = leaf.best_group(log_tokens, insertion.similarity);
+                    return leaf.add_to_group(best_group, log_tokens, insertion);
                 }
             }
             return None;
         }
-        return match self {
+        match self {
             Node::Inner(inner) => {
-                let owned_token = if !inner.children.contains_key(&token)
-                    && inner.children.len().ge(&(*max_children as usize))
-                {
-                    Token::new_empty_wildcard()
-                } else {
-                    token
-                };
+                let (owned_token, _) = inner.branch(inner.bridge(token), &insertion.max_children);
+                if let Some(path) = insertion.path.as_mut() {
+                    path.push(owned_token.clone());
+                }
                 let child = inner
                     .children
                     .entry(owned_token)
                     .or_insert_with(|| Node::inner(depth + 1));
-                child.add_child_recur(
-                    depth + 1,
-                    max_depth,
-                    max_children,
-                    min_similarity,
-                    log_tokens,
-                )
+                child.add_child_recur(depth + 1, log_tokens, insertion)
             }
             Node::Leaf(leaf) => {
-                let best_group = leaf.best_group(log_tokens);
-                leaf.add_to_group(best_group, min_similarity, log_tokens)
+                let best_group = leaf.best_group(log_tokens, insertion.similarity);
+                leaf.add_to_group(best_group, log_tokens, insertion)
             }
-        };
+        }
+    }
+}
+
+/// The prefix tree of the log lines sharing the same partition field values
+#[derive(Debug, Serialize, Deserialize)]
+struct Partition {
+    key: Fields,
+    root: HashMap<usize, Node>,
+}
+
+/// The root of the partition, created if needed, the empty partition being the main root
+fn partition_root<'t>(
+    root: &'t mut HashMap<usize, Node>,
+    partitions: &'t mut Vec<Partition>,
+    key: &Fields,
+) -> &'t mut HashMap<usize, Node> {
+    if key.is_empty() {
+        return root;
+    }
+    let index = match partitions.binary_search_by(|p| p.key.cmp(key)) {
+        Ok(index) => index,
+        Err(index) => {
+            partitions.insert(
+                index,
+                Partition {
+                    key: key.clone(),
+                    root: HashMap::new(),
+                },
+            );
+            index
+        }
+    };
+    &mut partitions[index].root
+}
+
+/// The text to cluster and the fields extracted around it
+struct Message {
+    text: String,
+    // every extracted field, as exposed by `ParsedLine::fields`
+    fields: Fields,
+    partition: Fields,
+    metadata: Fields,
+    // milliseconds since the Unix epoch, if the line holds a valid timestamp
+    timestamp: Option<i64>,
+}
+
+impl Message {
+    /// The timestamp of the line, or the current time for lines without a valid timestamp
+    ///
+    /// Taken when the line is inserted, so that the lines preprocessed in parallel by
+    /// `add_log_lines_parallel` get the time of their insertion, in order.
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+            .unwrap_or_else(|| Utc::now().timestamp_millis())
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(remote = "Self")]
 /// Main drain algorithm implementation
 /// Contains the structure of the drain prefix tree along with configuration options
 pub struct DrainTree {
@@ -232,6 +760,63 @@ pub struct DrainTree {
     #[serde(skip)]
     filter_patterns: Vec<grok::Pattern>,
     filter_patterns_str: Vec<String>,
+    #[serde(default = "masking::default_rules")]
+    masking_rules: Vec<MaskingRule>,
+    #[serde(skip)]
+    masks: Vec<Mask>,
+    #[serde(skip, default = "tokenizer::default_tokenizer")]
+    tokenizer: Box<dyn Tokenizer>,
+    #[serde(skip, default = "similarity::default_similarity")]
+    similarity: Box<dyn Similarity>,
+    #[serde(default)]
+    next_cluster_id: ClusterId,
+    // number of log lines ingested, used as the clock of the eviction policies
+    #[serde(default)]
+    tick: u64,
+    #[serde(default)]
+    eviction: Option<Eviction>,
+    #[serde(skip)]
+    on_eviction: Option<EvictionCallback>,
+    #[serde(skip)]
+    observer: Option<Observer>,
+    #[serde(default)]
+    auto_merge: Option<AutoMerge>,
+    // the (partition, template length) buckets touched since the last automatic merge pass
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    merge_pending: BTreeSet<(Fields, usize)>,
+    #[serde(default)]
+    json_message: Option<String>,
+    #[serde(default)]
+    partition_fields: Vec<String>,
+    // the roots of the non-empty partitions, sorted by key, `root` holds the empty partition
+    #[serde(default)]
+    partitions: Vec<Partition>,
+    #[serde(default)]
+    timestamp: Option<TimestampField>,
+    // time constant of the decaying rates, in seconds
+    #[serde(default = "timestamp::default_rate_window")]
+    rate_window: f64,
+    #[serde(default)]
+    sampling: Sampling,
+    // computed on the first insertion once a memory budget is set
+    #[serde(skip)]
+    usage: Option<Usage>,
+    #[serde(skip)]
+    eviction_index: Option<EvictionIndex>,
+}
+
+impl Serialize for DrainTree {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        DrainTree::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DrainTree {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut tree = DrainTree::deserialize(deserializer)?;
+        tree.assign_missing_ids();
+        Ok(tree)
+    }
 }
 
 impl Display for DrainTree {
@@ -240,6 +825,12 @@ impl Display for DrainTree {
         for (k, v) in self.root.iter() {
             str += &format!("Len: {} -> [ {} ]\n", k, v).to_string();
         }
+        for partition in self.partitions.iter() {
+            for (k, v) in partition.root.iter() {
+                str += &format!("Partition: {:?} Len: {} -> [ {} ]\n", partition.key, k, v)
+                    .to_string();
+            }
+        }
         write!(f, "[\n{}\n]", str)
     }
 }
@@ -250,12 +841,31 @@ impl Default for DrainTree {
             root: HashMap::new(),
             filter_patterns_str: vec![],
             filter_patterns: vec![],
+            masking_rules: masking::default_rules(),
+            masks: vec![],
             max_depth: 5,
             max_children: 100,
             min_similarity: 0.5,
             overall_pattern: None,
             overall_pattern_str: None,
             drain_field: None,
+            tokenizer: tokenizer::default_tokenizer(),
+            similarity: similarity::default_similarity(),
+            next_cluster_id: 0,
+            tick: 0,
+            eviction: None,
+            on_eviction: None,
+            observer: None,
+            auto_merge: None,
+            merge_pending: BTreeSet::new(),
+            json_message: None,
+            partition_fields: vec![],
+            partitions: vec![],
+            timestamp: None,
+            rate_window: timestamp::DEFAULT_RATE_WINDOW,
+            sampling: Sampling::default(),
+            usage: None,
+            eviction_index: None,
         }
     }
 }
@@ -309,161 +919,1309 @@ impl DrainTree {
         self
     }
 
-    /// The overall log pattern and which extracted field to cluster
-    /// most logging formats have a well known format mixed with semi-structured text
-    /// This allows you to set the well known format and then only cluster on the semi-structured
-    /// text.
-    /// If you set this, be sure to call `build_patterns` so that they can be compiled before use.
+    /// Add a rule to the masking pipeline, replacing every match of the regex or grok pattern
+    /// by the label between angle brackets, e.g. `<IP>`
     ///
-    /// # Examples:
+    /// The rules run in the order they are added, on the clustered text before it is split
+    /// into tokens: the message extracted by `log_pattern` or `json_message`, not the raw
+    /// line. Unlike the filter patterns, they match inside tokens and across token
+    /// boundaries, and a masked part is a constant of the templates. The variables of the
+    /// parsed lines keep the original text of the masked part, named after the label.
+    /// The grok references of the pattern must name base patterns of the grok crate.
+    /// Be sure to call `build_patterns` so that the rules can be compiled before use.
     /// ```
-    /// let mut g = grok::Grok::with_patterns();
-    /// let filter_patterns = vec![
-    ///         "blk_(|-)[0-9]+",     //blockid
-    ///        "%{IPV4:ip_address}", //IP
-    ///         "%{NUMBER:number}",   //Num
-    ///     ];
     /// let mut drain = drain_rs::DrainTree::new()
-    ///         // HDFS log pattern, variable format printout in the content section
-    ///         .log_pattern("%{NUMBER:date} %{NUMBER:time} %{NUMBER:proc} %{LOGLEVEL:level} %{DATA:component}: %{GREEDYDATA:content}", "content")
-    ///         .build_patterns(&mut g);
-    ///  ```
-    pub fn log_pattern(mut self, overall_pattern: &str, drain_field: &str) -> Self {
-        self.overall_pattern_str = Some(String::from(overall_pattern));
-        self.drain_field = Some(String::from(drain_field));
+    ///     .mask("%{IP}(:%{POSINT})?", "ENDPOINT")
+    ///     .mask("0x[0-9a-fA-F]+", "HEX")
+    ///     .build_patterns(&mut grok::Grok::with_patterns());
+    /// drain.add_log_line("connect to host=10.0.0.1:8080 at 0x7ff3 failed");
+    /// let cluster = drain.add_log_line("connect to host=10.0.0.2 at 0x1a failed").unwrap();
+    /// assert_eq!(cluster.as_string(), "connect to host=<ENDPOINT> at <HEX> failed");
+    /// ```
+    pub fn mask(mut self, pattern: &str, label: &str) -> Self {
+        self.masking_rules
+            .push(MaskingRule::pattern(pattern, label));
         self
     }
 
-    /// Build the patterns that have been supplied in `log_pattern` and `filter_patterns`
-    pub fn build_patterns(mut self, grok: &mut grok::Grok) -> Self {
-        if let Some(pattern_str) = &self.overall_pattern_str {
-            self.overall_pattern = Some(
-                grok.compile(pattern_str.as_str(), true)
-                    .expect("poorly formatted overall_pattern"),
-            );
-        }
-        let mut filter_patterns = Vec::with_capacity(self.filter_patterns_str.len());
-        for pattern in &self.filter_patterns_str {
-            if let Ok(c) = grok.compile(pattern.as_str(), true) {
-                filter_patterns.push(c);
-            }
-        }
-        self.filter_patterns = filter_patterns;
+    /// Replace the whole masking pipeline, which only holds `MaskingRule::NumericPrefixes`
+    /// by default
+    ///
+    /// Leaving out `MaskingRule::NumericPrefixes` switches the digit heuristic off, the
+    /// pattern rules then being the only protection against numbers multiplying the branches.
+    /// ```
+    /// use drain_rs::{DrainTree, MaskingRule};
+    /// let mut drain = DrainTree::new()
+    ///     .masking_rules(vec![MaskingRule::pattern("v%{INT}", "VERSION")])
+    ///     .build_patterns(&mut grok::Grok::with_patterns());
+    /// let get = drain.add_log_line_id("GET HTTP/1.1 v2 ok").unwrap();
+    /// // without the heuristic, `HTTP/2` takes its own branch
+    /// assert_ne!(drain.add_log_line_id("GET HTTP/2 v3 ok"), Some(get));
+    /// assert_eq!(drain.add_log_line_id("GET HTTP/1.1 v3 ok"), Some(get));
+    /// ```
+    pub fn masking_rules(mut self, rules: Vec<MaskingRule>) -> Self {
+        self.masking_rules = rules;
         self
     }
 
-    fn process(filter_patterns: &[grok::Pattern], log_line: String) -> Vec<Token> {
-        // preprocessing to replace user defined regex in the log_line
-        log_line
-            .split(' ')
-            .map(|t| t.trim())
-            .map(|t| {
-                match filter_patterns
-                    .iter()
-                    .map(|p| p.match_against(t))
-                    .find(|o| o.is_some())
-                {
-                    Some(Some(matches)) => match matches.iter().next() {
-                        Some((name, _pattern)) => Token::Val(format!("<{}>", name)),
-                        None => Token::new_empty_wildcard(),
-                    },
-                    _ => Token::Val(String::from(t)),
-                }
-            })
-            .collect()
+    /// Whether the pipeline sends the tokens containing a digit to the `<NUM>` branch
+    fn numeric_prefixes(&self) -> bool {
+        self.masking_rules.contains(&MaskingRule::NumericPrefixes)
     }
 
-    fn dig_inner_prefix_tree<'a>(
-        &self,
-        child: &'a Node,
-        processed_log: &[Token],
-    ) -> Option<&'a LogCluster> {
-        // find the correct node a level 2 using split token strategy
-        let mut current_node = Some(child);
-        for t in processed_log {
-            // It travers all nodes level that match the all starts tokens
-            if let Some(Node::Inner(inner)) = current_node {
-                current_node = inner.children.get(t);
-            }
-        }
-        // if a leaf was found, then fint the best group
-        if let Some(Node::Leaf(leaf)) = current_node {
-            let gas = leaf.best_group(processed_log)?;
-            return Some(&leaf.log_groups[gas.group_index]);
-        }
-        None
+    /// Bound the memory used by the log clusters, evicting clusters according to the policy
+    /// once the budget is exceeded
+    ///
+    /// Nodes of the prefix tree left without clusters are pruned along with evicted clusters.
+    /// The cluster a log line was just added to is never evicted by that same insertion.
+    /// ```
+    /// use drain_rs::{DrainTree, EvictionPolicy, MemoryBudget};
+    /// let mut drain = DrainTree::new()
+    ///     .memory_budget(MemoryBudget::MaxClusters(2), EvictionPolicy::LeastRecentlyUsed);
+    /// let first = drain.add_log_line_id("first kind of line").unwrap();
+    /// drain.add_log_line("second");
+    /// drain.add_log_line("yet another one");
+    /// assert_eq!(drain.cluster_count(), 2);
+    /// assert!(drain.cluster_by_id(first).is_none());
+    /// ```
+    pub fn memory_budget(mut self, budget: MemoryBudget, policy: EvictionPolicy) -> Self {
+        self.eviction = Some(Eviction { budget, policy });
+        self.usage = None;
+        self.eviction_index = None;
+        self
     }
 
-    fn log_group_for_tokens(&self, processed_log: &[Token]) -> Option<&LogCluster> {
-        // retrieve the first node using preprocessed token list len, for level 1
-        let n = self.root.get(&processed_log.len())?;
-        self.dig_inner_prefix_tree(n, processed_log)
+    /// Called with every log cluster evicted to respect the memory budget
+    ///
+    /// The callback is not serialized, set it again after deserializing the tree.
+    pub fn on_eviction<F: FnMut(LogCluster) + Send + 'static>(mut self, callback: F) -> Self {
+        self.on_eviction = Some(EvictionCallback::new(Box::new(callback)));
+        self
     }
 
-    fn apply_overall_pattern(&self, log_line: &str) -> Option<String> {
-        let m = self.overall_pattern.as_ref()?.match_against(log_line)?;
-        let df = self
-            .drain_field
-            .as_ref()
-            .expect("illegal state. [overall_pattern] set without [drain_field] set")
-            .as_str();
-        let s = m.get(df)?;
-        Option::Some(String::from(s))
+    /// Notified whenever a log line creates a cluster or turns constant tokens of a template
+    /// into wildcards, e.g. to alert on never-before-seen log messages
+    ///
+    /// The observer is either a closure or the sender of a channel.
+    /// It is not serialized, set it again after deserializing the tree.
+    /// ```
+    /// use drain_rs::{DrainTree, TemplateEventKind};
+    /// let (sender, receiver) = std::sync::mpsc::channel();
+    /// let mut drain = DrainTree::new().observer(sender);
+    /// drain.add_log_line("user 3 logged in");
+    /// drain.add_log_line("user 4 logged in");
+    /// drain.add_log_line("user 4 logged in");
+    ///
+    /// let events = receiver.try_iter().collect::<Vec<_>>();
+    /// assert_eq!(events.len(), 2);
+    /// assert_eq!(events[0].kind, TemplateEventKind::NewCluster);
+    /// assert_eq!(events[1].kind, TemplateEventKind::TemplateChanged);
+    /// assert_eq!(events[1].old_template.as_deref(), Some("user 3 logged in"));
+    /// assert_eq!(events[1].new_template, "user <*> logged in");
+    /// assert_eq!(events[1].line, "user 4 logged in");
+    /// ```
+    pub fn observer<O: TemplateObserver + 'static>(mut self, observer: O) -> Self {
+        self.observer = Some(Observer::new(Box::new(observer)));
+        self
     }
 
-    #[allow(dead_code)]
-    fn is_compiled(&self) -> bool {
-        self.filter_patterns.len() == self.filter_patterns_str.len()
-            && (self.overall_pattern.is_some() == self.overall_pattern_str.is_some())
+    /// Run `merge_clusters` with the given threshold every `interval` log lines
+    ///
+    /// Each pass only plans the merges within the (partition, template length) buckets the log
+    /// lines went to since the previous pass.
+    /// The cluster returned by `add_log_line` is the surviving cluster when the line's
+    /// cluster gets merged.
+    pub fn auto_merge(mut self, threshold: f32, interval: u64) -> Self {
+        self.auto_merge = if interval > 0 {
+            Some(AutoMerge {
+                threshold,
+                interval,
+            })
+        } else {
+            None
+        };
+        self
     }
 
-    /// Grab the log group for the given log line if it exists.
-    /// This does NOT modify the underlying tree.
+    /// Read the log lines as JSON objects and cluster the field at the given JSON pointer,
+    /// e.g. `/msg` or `/log/message`
+    ///
+    /// The other fields are kept as the metadata of the clusters, see `LogCluster::metadata`,
+    /// except the ones listed in `partition_fields`.
+    /// Lines that are not JSON, or have no message at the pointer, are clustered as a whole.
+    /// If a `log_pattern` is set, it is applied to the message.
     /// ```
-    /// let drain = drain_rs::DrainTree::new();
-    /// assert!(drain.log_group("[Sun Dec 04 04:51:08 2005] [notice] jk2_init() Found child 6725 in scoreboard slot 10").is_none());
-    ///  ```
-    pub fn log_group(&self, log_line: &str) -> Option<&LogCluster> {
-        let processed_line = self
-            .apply_overall_pattern(log_line)
-            .unwrap_or_else(|| log_line.to_string());
-        let tokens = DrainTree::process(&self.filter_patterns, processed_line);
-        self.log_group_for_tokens(tokens.as_slice())
-    }
+    /// let mut drain = drain_rs::DrainTree::new().json_message("/msg");
+    /// let cluster = drain
+    ///     .add_log_line(r#"{"level":"INFO","msg":"user 3 logged in","user":{"name":"bob"}}"#)
+    ///     .unwrap();
+    /// assert_eq!(cluster.as_string(), "user 3 logged in");
+    /// assert!(cluster.metadata()["/user/name"].contains("bob"));
+    /// ```
+    pub fn json_message(mut self, message_pointer: &str) -> Self {
+        self.json_message = Some(String::from(message_pointer));
+        self
+    }
+
+    /// The fields whose values partition the log lines: capture names of the `log_pattern`,
+    /// e.g. `component` and `level`, or JSON pointers with `json_message`, e.g. `/service`
+    ///
+    /// Every distinct combination of values gets its own prefix tree, so that log lines from
+    /// different partitions never share a cluster. A missing field is left out of the
+    /// partition. The values are exposed by `LogCluster::partition`.
+    /// ```
+    /// let mut drain = drain_rs::DrainTree::new()
+    ///     .log_pattern("%{LOGLEVEL:level} %{DATA:component}: %{GREEDYDATA:content}", "content")
+    ///     .partition_fields(vec!["component"])
+    ///     .build_patterns(&mut grok::Grok::with_patterns());
+    /// let server = drain.add_log_line_id("INFO dfs.DataNode: Receiving block").unwrap();
+    /// let client = drain.add_log_line_id("INFO dfs.DFSClient: Receiving block").unwrap();
+    /// assert_ne!(server, client);
+    /// assert_eq!(drain.cluster_by_id(client).unwrap().partition()["component"], "dfs.DFSClient");
+    /// ```
+    ///
+    /// With JSON log lines:
+    /// ```
+    /// let mut drain = drain_rs::DrainTree::new()
+    ///     .json_message("/msg")
+    ///     .partition_fields(vec!["/service"]);
+    /// let api = drain.add_log_line_id(r#"{"service":"api","msg":"request done"}"#).unwrap();
+    /// let db = drain.add_log_line_id(r#"{"service":"db","msg":"request done"}"#).unwrap();
+    /// assert_ne!(api, db);
+    /// assert_eq!(drain.cluster_by_id(db).unwrap().partition()["/service"], "db");
+    /// ```
+    pub fn partition_fields(mut self, partition_fields: Vec<&str>) -> Self {
+        self.partition_fields = partition_fields
+            .iter()
+            .map(|s| String::from(*s))
+            .collect::<Vec<String>>();
+        self
+    }
+
+    /// The extracted field holding the timestamp of the log lines and its `chrono` format,
+    /// e.g. `%Y-%m-%d %H:%M:%S%.3f`, or `rfc3339`
+    ///
+    /// The field is a capture name of the `log_pattern`, or a JSON pointer with `json_message`.
+    /// The timestamps set `LogCluster::first_seen`, `LogCluster::last_seen` and the rates.
+    /// Timestamps without an offset are taken as UTC. Log lines without a valid timestamp,
+    /// and every log line when this is not set, happen when they are added to the tree.
+    /// ```
+    /// let mut drain = drain_rs::DrainTree::new()
+    ///     .log_pattern("%{TIMESTAMP_ISO8601:ts} %{GREEDYDATA:content}", "content")
+    ///     .timestamp_field("ts", "%Y-%m-%dT%H:%M:%S")
+    ///     .build_patterns(&mut grok::Grok::with_patterns());
+    /// drain.add_log_line("2021-04-01T10:00:00 disk full");
+    /// let cluster = drain.add_log_line("2021-04-01T10:00:30 disk full").unwrap();
+    /// assert_eq!(cluster.first_seen().unwrap().to_rfc3339(), "2021-04-01T10:00:00+00:00");
+    /// assert_eq!(cluster.last_seen().unwrap().to_rfc3339(), "2021-04-01T10:00:30+00:00");
+    /// ```
+    pub fn timestamp_field(mut self, field: &str, format: &str) -> Self {
+        self.timestamp = Some(TimestampField {
+            field: String::from(field),
+            format: String::from(format),
+        });
+        self
+    }
+
+    /// Time constant of the decaying rate of the clusters, one minute by default
+    ///
+    /// The longer the window, the smoother the rate, see `LogCluster::events_per_second`.
+    pub fn rate_window(mut self, rate_window: Duration) -> Self {
+        self.rate_window = rate_window.as_secs_f64().max(f64::MIN_POSITIVE);
+        self
+    }
+
+    /// How many values each wildcard samples, and how it chooses them, the first 10 distinct
+    /// values by default
+    ///
+    /// The samples are shown by `LogCluster::as_detailed_string` and used to detect the GROK
+    /// patterns of the wildcards. Whatever the strategy, each wildcard also estimates its number
+    /// of distinct values, see `LogCluster::distinct_counts`.
+    /// ```
+    /// use drain_rs::{DrainTree, SamplingStrategy};
+    /// let mut drain = DrainTree::new()
+    ///     .max_depth(2)
+    ///     .wildcard_sampling(SamplingStrategy::MostFrequent, 1);
+    /// drain.add_log_line("login of user bob");
+    /// for _ in 0..5 {
+    ///     drain.add_log_line("login of user eve");
+    /// }
+    /// let cluster = drain.log_group("login of user joe").unwrap();
+    /// assert_eq!(cluster.as_detailed_string(), r#"login of user <** ["eve"] **>"#);
+    /// ```
+    pub fn wildcard_sampling(mut self, strategy: SamplingStrategy, size: usize) -> Self {
+        self.sampling.strategy = strategy;
+        self.sampling.size = size;
+        self
+    }
+
+    /// Keep running statistics of the values of every wildcard: their type, inferred from the
+    /// GROK base patterns, and the distribution of the numbers, see `LogCluster::variable_stats`
+    ///
+    /// Every value is matched against a few patterns, which slows the ingestion down.
+    /// ```
+    /// use drain_rs::{DrainTree, VariableType};
+    /// let mut drain = DrainTree::new().variable_statistics();
+    /// for size in 1..=100 {
+    ///     drain.add_log_line(&format!("Received block blk_{} of size {}", size % 7, size * 1024));
+    /// }
+    /// let cluster = drain.log_group("Received block blk_0 of size 1024").unwrap();
+    /// let (position, size) = cluster.variable_stats()[1];
+    /// assert_eq!(position, 5);
+    /// assert_eq!(size.variable_type(), Some(VariableType::Int));
+    /// assert_eq!(size.max(), Some(102400.0));
+    /// assert_eq!(size.mean(), Some(51712.0));
+    /// let median = size.percentile(50.0).unwrap();
+    /// assert!((median - 51200.0).abs() < 0.02 * 51200.0);
+    /// ```
+    pub fn variable_statistics(mut self) -> Self {
+        self.sampling.statistics = true;
+        self
+    }
+
+    /// How the clustered text is split into tokens, defaults to `SpaceTokenizer`
+    ///
+    /// The tokenizer is not serialized, set it again after deserializing the tree.
+    /// ```
+    /// let mut drain = drain_rs::DrainTree::new().tokenizer(drain_rs::WhitespaceTokenizer);
+    /// let id = drain.add_log_line_id("connection  closed\tby peer").unwrap();
+    /// assert_eq!(drain.add_log_line_id("connection closed by peer"), Some(id));
+    /// ```
+    pub fn tokenizer<T: Tokenizer + 'static>(mut self, tokenizer: T) -> Self {
+        self.tokenizer = Box::new(tokenizer);
+        self
+    }
+
+    /// How the log lines are compared to the templates of the clusters of their leaf,
+    /// defaults to `SeqDist`, see `Weighted`, `Lcs` and `TypedMatch` for the other built-ins
+    ///
+    /// The similarity is not serialized, set it again after deserializing the tree.
+    /// ```
+    /// let mut drain = drain_rs::DrainTree::new()
+    ///     .max_depth(1)
+    ///     .similarity(drain_rs::Weighted::default());
+    /// let id = drain.add_log_line_id("user login from web as bob").unwrap();
+    /// assert_eq!(drain.add_log_line_id("user login from app as eve"), Some(id));
+    /// ```
+    pub fn similarity<S: Similarity + 'static>(mut self, similarity: S) -> Self {
+        self.similarity = Box::new(similarity);
+        self
+    }
+
+    /// The overall log pattern and which extracted field to cluster
+    /// most logging formats have a well known format mixed with semi-structured text
+    /// This allows you to set the well known format and then only cluster on the semi-structured
+    /// text.
+    /// If you set this, be sure to call `build_patterns` so that they can be compiled before use.
+    ///
+    /// # Examples:
+    /// ```
+    /// let mut g = grok::Grok::with_patterns();
+    /// let filter_patterns = vec![
+    ///         "blk_(|-)[0-9]+",     //blockid
+    ///        "%{IPV4:ip_address}", //IP
+    ///         "%{NUMBER:number}",   //Num
+    ///     ];
+    /// let mut drain = drain_rs::DrainTree::new()
+    ///         // HDFS log pattern, variable format printout in the content section
+    ///         .log_pattern("%{NUMBER:date} %{NUMBER:time} %{NUMBER:proc} %{LOGLEVEL:level} %{DATA:component}: %{GREEDYDATA:content}", "content")
+    ///         .build_patterns(&mut g);
+    ///  ```
+    pub fn log_pattern(mut self, overall_pattern: &str, drain_field: &str) -> Self {
+        self.overall_pattern_str = Some(String::from(overall_pattern));
+        self.drain_field = Some(String::from(drain_field));
+        self
+    }
+
+    /// Build the patterns that have been supplied in `log_pattern` and `filter_patterns`
+    ///
+    /// # Panics
+    /// If any of the patterns fails to compile, see `try_build_patterns` for a fallible version.
+    pub fn build_patterns(mut self, grok: &mut grok::Grok) -> Self {
+        if let Err(e) = self.try_build_patterns(grok) {
+            panic!("{}", e)
+        }
+        self
+    }
+
+    /// Build the patterns that have been supplied in `log_pattern` and `filter_patterns`
+    ///
+    /// Every pattern is compiled, and all the ones that fail are reported in the error, the
+    /// tree being left as it was.
+    /// ```
+    /// let mut g = grok::Grok::with_patterns();
+    /// let err = drain_rs::DrainTree::new()
+    ///     .filter_patterns(vec!["%{IPV4:ip_address}", "%{NOT_A_PATTERN:oops}"])
+    ///     .try_build_patterns(&mut g)
+    ///     .unwrap_err();
+    /// match err {
+    ///     drain_rs::Error::InvalidPatterns(errors) => {
+    ///         assert_eq!(errors.len(), 1);
+    ///         assert_eq!(errors[0].pattern, "%{NOT_A_PATTERN:oops}");
+    ///     }
+    ///     _ => panic!("unexpected error"),
+    /// }
+    /// ```
+    pub fn try_build_patterns(&mut self, grok: &mut grok::Grok) -> Result<(), Error> {
+        let mut errors = vec![];
+        let mut overall_pattern = None;
+        if let Some(pattern_str) = &self.overall_pattern_str {
+            if self.drain_field.is_none() {
+                return Err(Error::MissingDrainField);
+            }
+            match grok.compile(pattern_str.as_str(), true) {
+                Ok(c) => overall_pattern = Some(c),
+                Err(e) => errors.push(PatternError {
+                    kind: PatternKind::LogPattern,
+                    pattern: pattern_str.clone(),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        let mut filter_patterns = Vec::with_capacity(self.filter_patterns_str.len());
+        for pattern in &self.filter_patterns_str {
+            match grok.compile(pattern.as_str(), true) {
+                Ok(c) => filter_patterns.push(c),
+                Err(e) => errors.push(PatternError {
+                    kind: PatternKind::FilterPattern,
+                    pattern: pattern.clone(),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        let mut masks = Vec::with_capacity(self.masking_rules.len());
+        for rule in &self.masking_rules {
+            if let MaskingRule::Pattern { pattern, label } = rule {
+                match masking::compile(pattern, grok) {
+                    Ok(regex) => masks.push(Mask {
+                        regex,
+                        label: label.clone(),
+                    }),
+                    Err(e) => errors.push(PatternError {
+                        kind: PatternKind::MaskingRule,
+                        pattern: pattern.clone(),
+                        reason: e.to_string(),
+                    }),
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(Error::InvalidPatterns(errors));
+        }
+        self.overall_pattern = overall_pattern;
+        self.filter_patterns = filter_patterns;
+        self.masks = masks;
+        Ok(())
+    }
+
+    fn process(
+        masks: &[Mask],
+        filter_patterns: &[grok::Pattern],
+        tokenizer: &dyn Tokenizer,
+        log_line: &str,
+    ) -> ProcessedLine {
+        // preprocessing to replace user defined regex in the log_line
+        let mut processed = ProcessedLine {
+            tokens: vec![],
+            values: vec![],
+            names: vec![],
+        };
+        let masked = MaskedLine::new(masks, log_line);
+        for t in tokenizer.tokenize(&masked.text) {
+            let (value, label) = masked.original(t);
+            let (token, name) = match filter_patterns
+                .iter()
+                .map(|p| p.match_against(t))
+                .find(|o| o.is_some())
+            {
+                Some(Some(matches)) => match matches.iter().next() {
+                    Some((name, _pattern)) => {
+                        (Token::Val(format!("<{}>", name)), Some(name.to_string()))
+                    }
+                    None => (Token::new_empty_wildcard(), None),
+                },
+                _ => (Token::Val(String::from(t)), label.map(String::from)),
+            };
+            processed.tokens.push(token);
+            processed.values.push(value);
+            processed.names.push(name);
+        }
+        processed
+    }
+
+    /// The roots of every partition, starting with the empty one
+    fn roots(&self) -> impl Iterator<Item = &HashMap<usize, Node>> {
+        std::iter::once(&self.root).chain(self.partitions.iter().map(|p| &p.root))
+    }
+
+    /// Give an identifier to the clusters of trees serialized before clusters had one, the
+    /// next identifier following the largest one
+    fn assign_missing_ids(&mut self) {
+        let next_cluster_id = self.next_cluster_id;
+        let mut clusters = std::iter::once(&mut self.root)
+            .chain(self.partitions.iter_mut().map(|p| &mut p.root))
+            .flat_map(|root| root.values_mut())
+            .flat_map(Node::log_groups_mut)
+            .collect::<Vec<&mut LogCluster>>();
+        let unassigned = log_cluster::unassigned_id();
+        let largest = clusters
+            .iter()
+            .flat_map(|c| std::iter::once(c.id()).chain(c.aliases().iter().copied()))
+            .filter(|&id| id != unassigned)
+            .max();
+        let mut next_id = largest.map_or(next_cluster_id, |id| next_cluster_id.max(id + 1));
+        for cluster in clusters.iter_mut().filter(|c| c.id() == unassigned) {
+            cluster.assign_id(next_id);
+            next_id += 1;
+        }
+        self.next_cluster_id = next_id;
+    }
+
+    /// The root of the partition, if it exists
+    fn partition(&self, key: &Fields) -> Option<&HashMap<usize, Node>> {
+        if key.is_empty() {
+            return Some(&self.root);
+        }
+        let index = self.partitions.binary_search_by(|p| p.key.cmp(key)).ok()?;
+        Some(&self.partitions[index].root)
+    }
+
+    /// The leaf reached by the tokens, following the same branches as an insertion would
+    fn leaf_for_tokens(&self, partition: &Fields, processed_log: &[Token]) -> Option<&Leaf> {
+        // retrieve the first node using preprocessed token list len, for level 1
+        self.partition(partition)?
+            .get(&processed_log.len())?
+            .find_leaf(
+                processed_log,
+                self.max_depth,
+                self.max_children,
+                self.numeric_prefixes(),
+            )
+    }
+
+    fn log_group_for_tokens(
+        &self,
+        partition: &Fields,
+        processed_log: &[Token],
+    ) -> Option<&LogCluster> {
+        let bucket = self.partition(partition)?.get(&processed_log.len())?;
+        let leaf = bucket.find_leaf(
+            processed_log,
+            self.max_depth,
+            self.max_children,
+            self.numeric_prefixes(),
+        )?;
+        let similarity = self.similarity.as_ref();
+        if let Some((_, target, index)) =
+            bucket.redirect(leaf, processed_log, similarity, self.min_similarity)
+        {
+            return Some(&target.log_groups[index]);
+        }
+        let gas = leaf.best_group(processed_log, similarity)?;
+        Some(&leaf.log_groups[gas.group_index])
+    }
+
+    /// The drain field and the other fields extracted by the overall log pattern
+    fn apply_overall_pattern(&self, log_line: &str) -> Result<Option<(String, Fields)>, Error> {
+        let m = match &self.overall_pattern {
+            Some(pattern) => pattern.match_against(log_line),
+            None => return Ok(None),
+        };
+        let df = self.drain_field.as_ref().ok_or(Error::MissingDrainField)?;
+        Ok(m.and_then(|m| {
+            let message = String::from(m.get(df.as_str())?);
+            let fields = m
+                .iter()
+                .filter(|(name, _)| *name != df.as_str())
+                .map(|(name, value)| (String::from(name), String::from(value)))
+                .collect::<Fields>();
+            Some((message, fields))
+        }))
+    }
+
+    /// The message of the JSON log line, then the drain field of the overall log pattern
+    fn extract_message(&self, log_line: &str) -> Result<Message, Error> {
+        let (text, json_fields) = self
+            .json_message
+            .as_ref()
+            .and_then(|pointer| json::extract(log_line, pointer))
+            .unwrap_or_else(|| (log_line.to_string(), BTreeMap::new()));
+        let metadata = json_fields
+            .iter()
+            .filter(|(name, _)| !self.partition_fields.contains(name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect::<Fields>();
+        let mut message = Message {
+            text,
+            fields: json_fields,
+            partition: BTreeMap::new(),
+            metadata,
+            timestamp: None,
+        };
+        if let Some((text, fields)) = self.apply_overall_pattern(&message.text)? {
+            message.text = text;
+            message.fields.extend(fields);
+        }
+        message.partition = self
+            .partition_fields
+            .iter()
+            .filter_map(|name| Some((name.clone(), message.fields.get(name)?.clone())))
+            .collect();
+        message.timestamp = self
+            .timestamp
+            .as_ref()
+            .and_then(|t| t.parse(&message.fields))
+            .map(|t| t.timestamp_millis());
+        Ok(message)
+    }
+
+    fn process_line(&self, log_line: &str) -> Result<(ProcessedLine, Message), Error> {
+        let message = self.extract_message(log_line)?;
+        Ok((
+            DrainTree::process(
+                &self.masks,
+                &self.filter_patterns,
+                self.tokenizer.as_ref(),
+                &message.text,
+            ),
+            message,
+        ))
+    }
+
+    /// The (pattern, label) of the pattern rules of the masking pipeline
+    fn pattern_rules(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.masking_rules.iter().filter_map(|rule| match rule {
+            MaskingRule::Pattern { pattern, label } => Some((pattern.as_str(), label.as_str())),
+            MaskingRule::NumericPrefixes => None,
+        })
+    }
+
+    fn is_compiled(&self) -> bool {
+        self.filter_patterns.len() == self.filter_patterns_str.len()
+            && self.masks.len() == self.pattern_rules().count()
+            && (self.overall_pattern.is_some() == self.overall_pattern_str.is_some())
+    }
+
+    /// Grab the log group for the given log line if it exists.
+    /// This does NOT modify the underlying tree.
+    /// ```
+    /// let drain = drain_rs::DrainTree::new();
+    /// assert!(drain.log_group("[Sun Dec 04 04:51:08 2005] [notice] jk2_init() Found child 6725 in scoreboard slot 10").is_none());
+    ///  ```
+    pub fn log_group(&self, log_line: &str) -> Option<&LogCluster> {
+        let (processed, message) = self.process_line(log_line).ok()?;
+        self.log_group_for_tokens(&message.partition, processed.tokens.as_slice())
+    }
+
+    /// Match the log line against its log cluster and extract the variable parts.
+    /// This does NOT modify the underlying tree.
+    ///
+    /// Variables are the tokens facing a wildcard of the template along with the tokens
+    /// replaced by a filter pattern, which are named after the filter pattern capture, and
+    /// the tokens holding a mask, named after its label.
+    /// ```
+    /// let mut g = grok::Grok::with_patterns();
+    /// let mut drain = drain_rs::DrainTree::new()
+    ///     .max_depth(2)
+    ///     .filter_patterns(vec!["%{IPV4:ip_address}"])
+    ///     .log_pattern("%{NUMBER:id} \\[%{LOGLEVEL:level}\\] %{GREEDYDATA:content}", "content")
+    ///     .build_patterns(&mut g);
+    /// drain.add_log_line("1 [INFO] request from user bob at 10.0.0.1");
+    /// drain.add_log_line("2 [INFO] request from user alice at 10.0.0.2");
+    ///
+    /// let parsed = drain.parse("3 [WARN] request from user eve at 10.0.0.3").unwrap();
+    /// assert_eq!(parsed.template, "request from user <*> at <ip_address>");
+    /// assert_eq!(parsed.variables[0].value, "eve");
+    /// assert_eq!(parsed.variables[1].name.as_deref(), Some("ip_address"));
+    /// assert_eq!(parsed.variables[1].value, "10.0.0.3");
+    /// assert_eq!(parsed.fields["level"], "WARN");
+    /// ```
+    pub fn parse(&self, log_line: &str) -> Option<ParsedLine<'_>> {
+        let (processed, message) = self.process_line(log_line).ok()?;
+        let cluster = self.log_group_for_tokens(&message.partition, processed.tokens.as_slice())?;
+        Some(ParsedLine {
+            cluster,
+            template: self.template(cluster),
+            variables: cluster.extract_variables(&processed),
+            fields: message.fields,
+        })
+    }
 
     /// Add a new log line to the overall tree and return the current
     /// reference to the created/modified log cluster
     ///
     /// Over time, the log clusters could change as new log lines are added.
+    /// Lines that cannot be processed are skipped, use `try_add_log_line` to know why.
     /// ```
     /// let mut drain = drain_rs::DrainTree::new();
     /// assert!(drain.add_log_line("[Sun Dec 04 04:51:08 2005] [notice] jk2_init() Found child 6725 in scoreboard slot 10").is_some());
     ///  ```
     pub fn add_log_line(&mut self, log_line: &str) -> Option<&LogCluster> {
-        let processed_line = self.apply_overall_pattern(log_line);
-        let tokens = DrainTree::process(
-            &self.filter_patterns,
-            processed_line.unwrap_or_else(|| log_line.to_string()),
-        );
-        let len = tokens.len();
-        let log_tokens = tokens.as_slice();
-        let log_cluster_option = self
-            .root
-            .entry(len)
+        let (processed, message) = self.process_line(log_line).ok()?;
+        self.add_tokens(processed.tokens, &message, log_line)
+    }
+
+    /// Add the log lines to the tree, in order, and return the identifier of the cluster of
+    /// every line, see `add_log_line_id`
+    ///
+    /// The lines are tokenized and matched against the patterns on every available core,
+    /// by batches, then inserted one after the other, so the clusters are the same as when
+    /// adding the lines sequentially.
+    /// ```
+    /// let mut drain = drain_rs::DrainTree::new()
+    ///     .filter_patterns(vec!["%{IPV4:ip_address}"])
+    ///     .build_patterns(&mut grok::Grok::with_patterns());
+    /// let lines = (0..1000)
+    ///     .map(|i| format!("user {} called from 10.0.0.{}", i, i % 256))
+    ///     .collect::<Vec<String>>();
+    /// let ids = drain.add_log_lines_parallel(&lines);
+    /// assert_eq!(ids.len(), 1000);
+    /// assert!(ids.iter().all(|id| *id == Some(0)));
+    /// ```
+    pub fn add_log_lines_parallel<S: AsRef<str> + Sync>(
+        &mut self,
+        log_lines: &[S],
+    ) -> Vec<Option<ClusterId>> {
+        let threads = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        let mut ids = Vec::with_capacity(log_lines.len());
+        // batches bound the memory held by the processed lines
+        for batch in log_lines.chunks(PARALLEL_BATCH_LINES * threads) {
+            let chunk_size = batch.len().div_ceil(threads).max(1);
+            let tree: &DrainTree = self;
+            let processed = std::thread::scope(|scope| {
+                let handles = batch
+                    .chunks(chunk_size)
+                    .map(|chunk| {
+                        scope.spawn(move || {
+                            chunk
+                                .iter()
+                                .map(|line| tree.process_line(line.as_ref()).ok())
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| {
+                        handle
+                            .join()
+                            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+                    })
+                    .collect::<Vec<_>>()
+            });
+            for (line, processed) in batch.iter().zip(processed) {
+                ids.push(processed.and_then(|(processed, message)| {
+                    self.add_tokens(processed.tokens, &message, line.as_ref())
+                        .map(LogCluster::id)
+                }));
+            }
+        }
+        ids
+    }
+
+    /// Add a multi-line event to the tree, clustering its first line along with its signature
+    ///
+    /// See `MultilineAggregator` to assemble the events.
+    pub fn add_event(&mut self, event: &Event) -> Option<&LogCluster> {
+        self.add_log_line(event.message().as_str())
+    }
+
+    /// Add a new log line to the overall tree and return the current
+    /// reference to the created/modified log cluster
+    ///
+    /// Unlike `add_log_line`, this refuses to ingest lines while the tree is in an invalid
+    /// state, for example when it was deserialized and `build_patterns` was not called yet.
+    /// ```
+    /// let mut drain = drain_rs::DrainTree::new().filter_patterns(vec!["%{IPV4:ip_address}"]);
+    /// assert!(matches!(
+    ///     drain.try_add_log_line("connected to 10.0.0.1").unwrap_err(),
+    ///     drain_rs::Error::PatternsNotBuilt
+    /// ));
+    /// let mut drain = drain.build_patterns(&mut grok::Grok::with_patterns());
+    /// assert!(drain.try_add_log_line("connected to 10.0.0.1").unwrap().is_some());
+    ///  ```
+    pub fn try_add_log_line(&mut self, log_line: &str) -> Result<Option<&LogCluster>, Error> {
+        if !self.is_compiled() {
+            return Err(Error::PatternsNotBuilt);
+        }
+        let (processed, message) = self.process_line(log_line)?;
+        Ok(self.add_tokens(processed.tokens, &message, log_line))
+    }
+
+    fn add_tokens(
+        &mut self,
+        tokens: Vec<Token>,
+        message: &Message,
+        log_line: &str,
+    ) -> Option<&LogCluster> {
+        if tokens.is_empty() {
+            return None;
+        }
+        self.tick += 1;
+        let mut insertion = Insertion {
+            max_depth: self.max_depth,
+            max_children: self.max_children,
+            min_similarity: self.min_similarity,
+            numeric_prefixes: self.numeric_prefixes(),
+            similarity: self.similarity.as_ref(),
+            next_id: NextId::Counter(&mut self.next_cluster_id),
+            partition: &message.partition,
+            metadata: &message.metadata,
+            timestamp: message.timestamp(),
+            rate_window: self.rate_window,
+            sampling: self.sampling,
+            tick: self.tick,
+            track_size: matches!(
+                self.eviction,
+                Some(Eviction {
+                    budget: MemoryBudget::MaxBytes(_),
+                    ..
+                })
+            ),
+            observe: self.observer.is_some(),
+            path: self.eviction.map(|_| vec![]),
+            created: false,
+            changed_from: None,
+            size_delta: 0,
+        };
+        let policy = self.eviction.map(|eviction| eviction.policy);
+        let (id, key) = partition_root(&mut self.root, &mut self.partitions, &message.partition)
+            .entry(tokens.len())
             .or_insert_with(|| Node::inner(0))
-            .add_child_recur(
-                0,
-                &self.max_depth,
-                &self.max_children,
-                &self.min_similarity,
-                log_tokens,
-            );
-        if let Some(log_cluster) = log_cluster_option {
-            log_cluster.extract_variables(log_tokens);
-            // println!("log_cluster => {}", log_cluster);
-            // println!("log_cluster => {}", log_cluster.as_string());
+            .add_log(tokens.as_slice(), &mut insertion)
+            .map(|cluster| (cluster.id(), policy.map(|policy| policy.key(cluster))))?;
+        let (created, size_delta) = (insertion.created, insertion.size_delta);
+        if let (Some(key), Some(index)) = (key, self.eviction_index.as_mut()) {
+            if created {
+                let location = Location {
+                    partition: message.partition.clone(),
+                    len: tokens.len(),
+                    path: insertion.path.take().unwrap_or_default(),
+                };
+                index.insert(id, key, location);
+            } else {
+                index.update(id, key);
+            }
+        }
+        if created || insertion.changed_from.is_some() {
+            let changed_from = insertion.changed_from.take();
+            self.notify(id, &message.partition, &tokens, changed_from, log_line);
+        }
+        let mut id = id;
+        if let Some(auto_merge) = self.auto_merge {
+            self.merge_pending
+                .insert((message.partition.clone(), tokens.len()));
+            // a deserialized interval can be 0, which the builder rejects
+            if self.tick.checked_rem(auto_merge.interval) == Some(0) {
+                let buckets = std::mem::take(&mut self.merge_pending);
+                let merges = self.merge_buckets(auto_merge.threshold, buckets);
+                if let Some((_, into)) = merges.iter().find(|(merged, _)| *merged == id) {
+                    id = *into;
+                }
+            }
+        }
+        if self.eviction.is_some() {
+            self.enforce_memory_budget(id, created, size_delta);
+        }
+        let tree: &DrainTree = self;
+        tree.leaf_for_tokens(&message.partition, tokens.as_slice())
+            .and_then(|leaf| leaf.log_groups.iter().find(|c| c.id() == id))
+            .or_else(|| tree.cluster_by_id(id))
+    }
+
+    /// Send the template event of the cluster a log line was just added to
+    fn notify(
+        &mut self,
+        id: ClusterId,
+        partition: &Fields,
+        tokens: &[Token],
+        changed_from: Option<Vec<String>>,
+        log_line: &str,
+    ) {
+        if self.observer.is_none() {
+            return;
+        }
+        let cluster = match self
+            .leaf_for_tokens(partition, tokens)
+            .and_then(|leaf| leaf.log_groups.iter().find(|c| c.id() == id))
+            .or_else(|| self.cluster_by_id(id))
+        {
+            Some(cluster) => cluster,
+            None => return,
+        };
+        let event = self.template_event(cluster, changed_from, log_line);
+        if let Some(observer) = self.observer.as_mut() {
+            observer.on_event(&event);
+        }
+    }
+
+    fn template_event(
+        &self,
+        cluster: &LogCluster,
+        changed_from: Option<Vec<String>>,
+        log_line: &str,
+    ) -> TemplateEvent {
+        TemplateEvent {
+            kind: if changed_from.is_some() {
+                TemplateEventKind::TemplateChanged
+            } else {
+                TemplateEventKind::NewCluster
+            },
+            cluster_id: cluster.id(),
+            old_template: changed_from.map(|template| self.tokenizer.join(&template)),
+            new_template: self.template(cluster),
+            line: log_line.to_string(),
+            partition: cluster.partition().clone(),
+        }
+    }
+
+    /// Merge the clusters whose templates have the same length and are similar enough,
+    /// within each partition
+    ///
+    /// Drain can split a single template into several clusters, when a variable token is part
+    /// of the prefix or when `max_children` sends some lines to the `<*>` branch.
+    /// Two templates are as similar as the fraction of positions where they hold the same
+    /// constant or both hold a wildcard.
+    /// Merged clusters are removed from the tree, the surviving cluster sums their matches,
+    /// keeps their wildcard values and lists their identifiers in `LogCluster::aliases`.
+    /// The leaves they leave redirect their log lines to the surviving cluster, which can move
+    /// to another leaf as its template is generalized.
+    ///
+    /// Returns the (merged, surviving) identifier pairs.
+    /// ```
+    /// let mut drain = drain_rs::DrainTree::new().max_depth(3).max_children(1);
+    /// let alpha = drain.add_log_line_id("alpha task done now").unwrap();
+    /// let beta = drain.add_log_line_id("beta task done now").unwrap();
+    /// assert_ne!(alpha, beta);
+    ///
+    /// assert_eq!(drain.merge_clusters(0.7), vec![(beta, alpha)]);
+    /// let merged = drain.cluster_by_id(beta).unwrap();
+    /// assert_eq!(merged.id(), alpha);
+    /// assert_eq!(merged.as_string(), "<*> task done now");
+    /// assert_eq!(merged.num_matched(), 2);
+    /// assert_eq!(drain.add_log_line_id("beta task done now"), Some(alpha));
+    /// ```
+    pub fn merge_clusters(&mut self, threshold: f32) -> Vec<(ClusterId, ClusterId)> {
+        let main = Fields::new();
+        let buckets = std::iter::once((&main, &self.root))
+            .chain(self.partitions.iter().map(|p| (&p.key, &p.root)))
+            .flat_map(|(partition, root)| root.keys().map(move |len| (partition.clone(), *len)))
+            .collect();
+        self.merge_buckets(threshold, buckets)
+    }
+
+    /// Merge the clusters of the given (partition, template length) buckets, see
+    /// `merge_clusters`
+    fn merge_buckets(
+        &mut self,
+        threshold: f32,
+        buckets: BTreeSet<(Fields, usize)>,
+    ) -> Vec<(ClusterId, ClusterId)> {
+        let mut plan = vec![];
+        for (partition, len) in buckets.iter() {
+            plan.extend(self.merge_bucket(threshold, partition, *len));
+        }
+        if !plan.is_empty() {
+            self.usage = None;
+            self.eviction_index = None;
+        }
+        plan
+    }
+
+    fn merge_bucket(
+        &mut self,
+        threshold: f32,
+        partition: &Fields,
+        len: usize,
+    ) -> Vec<(ClusterId, ClusterId)> {
+        let (max_depth, max_children, numeric) =
+            (self.max_depth, self.max_children, self.numeric_prefixes());
+        let root = if partition.is_empty() {
+            &mut self.root
+        } else {
+            match self.partitions.binary_search_by(|p| p.key.cmp(partition)) {
+                Ok(index) => &mut self.partitions[index].root,
+                Err(_) => return vec![],
+            }
+        };
+        let bucket = match root.get_mut(&len) {
+            Some(bucket) => bucket,
+            None => return vec![],
+        };
+        let plan = merge::plan_merges(&bucket.log_groups(), threshold);
+        let mut merges: BTreeMap<ClusterId, Vec<ClusterId>> = BTreeMap::new();
+        for (merged, into) in plan.iter() {
+            merges.entry(*into).or_default().push(*merged);
+        }
+        for (into, merged) in merges {
+            // the leaves left by the clusters keep routing their log lines to the survivor
+            let left = std::iter::once(into)
+                .chain(merged.iter().copied())
+                .filter_map(|id| bucket.path_to(id).map(|path| (id, path)))
+                .collect::<Vec<(ClusterId, Vec<Token>)>>();
+            let survivor = left
+                .first()
+                .filter(|(id, _)| *id == into)
+                .and_then(|(id, path)| bucket.take_at(path, *id));
+            let mut survivor = match survivor {
+                Some(survivor) => survivor,
+                None => continue,
+            };
+            for (id, path) in left.iter().skip(1) {
+                if let Some(cluster) = bucket.take_at(path, *id) {
+                    survivor.merge(cluster, self.rate_window, &self.sampling);
+                }
+            }
+            // the generalized template may route to another leaf, e.g. the `<*>` branch
+            let mut path = vec![];
+            bucket.place_cluster(survivor, max_depth, max_children, numeric, &mut path);
+            for (id, left) in left {
+                if let Some(leaf) = bucket.leaf_at_or_insert(&left) {
+                    leaf.redirects.push(Redirect {
+                        id,
+                        path: path.clone(),
+                    });
+                }
+            }
+        }
+        if !plan.is_empty() {
+            bucket.retarget_redirects();
+        }
+        plan
+    }
+
+    fn enforce_memory_budget(&mut self, keep: ClusterId, created: bool, size_delta: isize) {
+        let eviction = match self.eviction {
+            Some(eviction) => eviction,
+            None => return,
+        };
+        let mut usage = match self.usage {
+            Some(mut usage) => {
+                usage.clusters += created as usize;
+                usage.bytes = (usage.bytes as isize + size_delta).max(0) as usize;
+                usage
+            }
+            None => Usage {
+                clusters: self.cluster_count(),
+                bytes: self.memory_usage(),
+            },
+        };
+        let mut index = match self.eviction_index.take() {
+            Some(index) => index,
+            None => self.eviction_index(eviction.policy),
+        };
+        while usage.exceeds(&eviction.budget) {
+            let (id, location) = match index.pop(keep) {
+                Some(victim) => victim,
+                None => break,
+            };
+            let cluster = match self.remove_at(id, &location) {
+                Some(cluster) => cluster,
+                None => continue,
+            };
+            usage.clusters -= 1;
+            usage.bytes = usage.bytes.saturating_sub(cluster.approximate_size());
+            if let Some(callback) = self.on_eviction.as_mut() {
+                callback.call(cluster);
+            }
+        }
+        self.usage = Some(usage);
+        self.eviction_index = Some(index);
+    }
+
+    /// Index every log cluster by the key of the policy, along with its location
+    fn eviction_index(&self, policy: EvictionPolicy) -> EvictionIndex {
+        let mut index = EvictionIndex::default();
+        let main = Fields::new();
+        let partitions = std::iter::once((&main, &self.root))
+            .chain(self.partitions.iter().map(|p| (&p.key, &p.root)));
+        for (partition, root) in partitions {
+            for (len, node) in root.iter() {
+                node.visit_leaves(&mut vec![], &mut |path, leaf| {
+                    for cluster in leaf.log_groups.iter() {
+                        let location = Location {
+                            partition: partition.clone(),
+                            len: *len,
+                            path: path.to_vec(),
+                        };
+                        index.insert(cluster.id(), policy.key(cluster), location);
+                    }
+                });
+            }
+        }
+        index
+    }
+
+    /// Remove the log cluster from its location, pruning the nodes left empty
+    fn remove_at(&mut self, id: ClusterId, location: &Location) -> Option<LogCluster> {
+        let partition = if location.partition.is_empty() {
+            None
+        } else {
+            let key = &location.partition;
+            Some(self.partitions.binary_search_by(|p| p.key.cmp(key)).ok()?)
+        };
+        let root = match partition {
+            Some(index) => &mut self.partitions[index].root,
+            None => &mut self.root,
+        };
+        let bucket = root.get_mut(&location.len)?;
+        let cluster = bucket.remove_at(&location.path, id)?;
+        // only the survivors of a merge are the target of redirects
+        if !cluster.aliases().is_empty() {
+            bucket.retarget_redirects();
+        }
+        if bucket.is_empty() {
+            root.remove(&location.len);
+        }
+        if let Some(index) = partition.filter(|_| root.is_empty()) {
+            self.partitions.remove(index);
+        }
+        Some(cluster)
+    }
+
+    /// Number of log clusters in the tree
+    pub fn cluster_count(&self) -> usize {
+        self.roots()
+            .flat_map(|root| root.values())
+            .map(|n| n.log_groups().len())
+            .sum()
+    }
+
+    /// Approximate number of bytes used by the log clusters of the tree
+    pub fn memory_usage(&self) -> usize {
+        self.log_groups().iter().map(|c| c.approximate_size()).sum()
+    }
+
+    /// Add a new log line to the overall tree and return the identifier of the
+    /// created/modified log cluster
+    ///
+    /// The identifier stays the same for as long as the cluster exists, even as its template
+    /// gains wildcards, and is kept when the tree is serialized.
+    /// ```
+    /// let mut drain = drain_rs::DrainTree::new();
+    /// let id = drain.add_log_line_id("user 3 logged in").unwrap();
+    /// assert_eq!(drain.add_log_line_id("user 4 logged in"), Some(id));
+    /// assert_eq!(drain.cluster_by_id(id).unwrap().as_string(), "user <*> logged in");
+    ///  ```
+    pub fn add_log_line_id(&mut self, log_line: &str) -> Option<ClusterId> {
+        self.add_log_line(log_line).map(LogCluster::id)
+    }
+
+    /// The decaying rate of the log cluster at the given time, in logs per second
+    ///
+    /// The rate decays from `LogCluster::events_per_second` as time passes without logs,
+    /// so comparing it with `LogCluster::num_matched` over the lifetime of the cluster
+    /// tells whether the logs are accelerating.
+    /// ```
+    /// use chrono::{TimeZone, Utc};
+    /// let mut drain = drain_rs::DrainTree::new()
+    ///     .json_message("/msg")
+    ///     .timestamp_field("/time", "%s");
+    /// for second in 0..60 {
+    ///     let line = format!(r#"{{"time":"{}","msg":"disk full"}}"#, 1600000000 + second);
+    ///     drain.add_log_line(&line);
+    /// }
+    /// let cluster = drain.log_groups()[0];
+    /// let later = Utc.timestamp_opt(1600000600, 0).unwrap();
+    /// assert!(drain.events_per_second(cluster, later) < cluster.events_per_second());
+    /// ```
+    pub fn events_per_second(&self, log_cluster: &LogCluster, at: DateTime<Utc>) -> f64 {
+        log_cluster.rate_at(at.timestamp_millis(), self.rate_window)
+    }
+
+    /// Render the template of the log cluster with the configured tokenizer
+    pub fn template(&self, log_cluster: &LogCluster) -> String {
+        self.tokenizer.join(&log_cluster.template_tokens())
+    }
+
+    /// Generate the GROK pattern of the log cluster, matching the raw log lines of the cluster
+    ///
+    /// Unlike `GrokGenerator::generate_grok_with_tokenizer`, the tokens replaced by the filter
+    /// patterns and the masking rules are matched by their patterns, captured in the field
+    /// they are named after, so the pattern can be given back to `log_pattern`.
+    /// ```
+    /// let mut g = grok::Grok::with_patterns();
+    /// let mut drain = drain_rs::DrainTree::new()
+    ///     .filter_patterns(vec!["%{IPV4:ip_address}"])
+    ///     .mask("0x[0-9a-f]+", "HEX")
+    ///     .build_patterns(&mut g);
+    /// drain.add_log_line("user bob called 10.0.0.1 with (key=0x1f)");
+    /// drain.add_log_line("user bob called 10.0.0.2 with (key=0x2a)");
+    /// let generator = drain_rs::grok_generator::GrokGenerator::new_with_base_patterns();
+    /// let pattern = drain.grok_pattern(drain.log_groups()[0], &generator).unwrap();
+    /// let pattern = g.compile(&pattern, true).unwrap();
+    /// let matches = pattern.match_against("user bob called 10.1.2.3 with (key=0xff)").unwrap();
+    /// assert_eq!(matches.get("ip_address"), Some("10.1.2.3"));
+    /// assert_eq!(matches.get("hex"), Some("0xff"));
+    /// ```
+    pub fn grok_pattern(
+        &self,
+        log_cluster: &LogCluster,
+        generator: &grok_generator::GrokGenerator,
+    ) -> Option<String> {
+        let placeholders = self
+            .filter_patterns_str
+            .iter()
+            .flat_map(|pattern| grok_generator::Placeholder::for_filter_pattern(pattern))
+            .chain(
+                self.pattern_rules()
+                    .map(|(pattern, label)| grok_generator::Placeholder::for_mask(pattern, label)),
+            )
+            .collect::<Vec<_>>();
+        generator.generate_grok_with_placeholders(
+            log_cluster,
+            self.tokenizer.as_ref(),
+            &placeholders,
+        )
+    }
+
+    /// Grab the log cluster with the given identifier if it exists.
+    ///
+    /// The identifiers of merged clusters resolve to the cluster they were merged into.
+    pub fn cluster_by_id(&self, id: ClusterId) -> Option<&LogCluster> {
+        self.roots()
+            .flat_map(|root| root.values())
+            .flat_map(|n| n.log_groups())
+            .find(|c| c.has_id(id))
+    }
+
+    /// Describe how the log line would be clustered, without modifying the tree
+    ///
+    /// This details the partition, the length bucket, every prefix node followed (and why), the similarity
+    /// of every cluster in the reached leaf and the final decision.
+    /// ```
+    /// let mut drain = drain_rs::DrainTree::new().max_depth(2);
+    /// drain.add_log_line("user 3 logged in");
+    /// let explanation = drain.explain("user 4 logged out").unwrap();
+    /// assert_eq!(explanation.length, 4);
+    /// assert_eq!(explanation.steps[1].reason, drain_rs::BranchReason::Numeric);
+    /// assert_eq!(explanation.candidates.len(), 1);
+    /// assert_eq!(explanation.decision, drain_rs::Decision::Matched(0));
+    /// println!("{}", explanation);
+    /// ```
+    pub fn explain(&self, log_line: &str) -> Result<Explanation, Error> {
+        let (processed, message) = self.process_line(log_line)?;
+        let tokens = processed.tokens;
+        let length = tokens.len();
+        let bucket = self
+            .partition(&message.partition)
+            .and_then(|root| root.get(&length));
+        let mut current = bucket;
+        let length_exists = current.is_some();
+        let mut steps = vec![];
+        for (depth, log_token) in tokens.iter().enumerate() {
+            let inner = match current {
+                Some(Node::Inner(inner)) => Some(inner),
+                Some(Node::Leaf(_)) => break,
+                None => None,
+            };
+            let (token, mut reason) = Node::prefix_token(log_token, self.numeric_prefixes());
+            let token = match inner {
+                Some(inner) => inner.bridge(token),
+                None => token,
+            };
+            let is_last = depth == length - 1 || depth == self.max_depth as usize;
+            let branch = match inner {
+                Some(inner) if !is_last => {
+                    let (branch, forced) = inner.branch(token, &self.max_children);
+                    reason = forced.unwrap_or(reason);
+                    branch
+                }
+                _ => token,
+            };
+            current = inner.and_then(|inner| inner.children.get(&branch));
+            steps.push(PrefixStep {
+                depth,
+                token: log_token.to_string(),
+                branch: branch.to_string(),
+                reason,
+                exists: current.is_some(),
+            });
+            if is_last {
+                break;
+            }
         }
-        log_cluster_option
+        let candidates = match current {
+            Some(Node::Leaf(leaf)) => leaf
+                .log_groups
+                .iter()
+                .map(|group| Candidate {
+                    cluster_id: group.id(),
+                    template: self.template(group),
+                    similarity: group.similarity(tokens.as_slice(), self.similarity.as_ref()),
+                })
+                .collect::<Vec<Candidate>>(),
+            _ => vec![],
+        };
+        // the clusters merged away from the leaf send the line to the cluster they merged into
+        let redirected = match (bucket, current) {
+            (Some(bucket), Some(Node::Leaf(leaf))) => bucket
+                .redirect(
+                    leaf,
+                    tokens.as_slice(),
+                    self.similarity.as_ref(),
+                    self.min_similarity,
+                )
+                .map(|(_, target, index)| target.log_groups[index].id()),
+            _ => None,
+        };
+        let decision = match (redirected, current) {
+            (Some(id), _) => Decision::Matched(id),
+            (None, Some(Node::Leaf(leaf))) => {
+                match leaf.best_group(tokens.as_slice(), self.similarity.as_ref()) {
+                    Some(gas) if gas.similarity.exact_similarity < self.min_similarity => {
+                        Decision::BelowMinSimilarity {
+                            best: gas.similarity.exact_similarity,
+                        }
+                    }
+                    Some(gas) => Decision::Matched(leaf.log_groups[gas.group_index].id()),
+                    None => Decision::NewCluster,
+                }
+            }
+            _ => Decision::NewCluster,
+        };
+        Ok(Explanation {
+            message: message.text,
+            partition: message.partition,
+            tokens: tokens.iter().map(|t| t.to_string()).collect(),
+            length,
+            length_exists,
+            steps,
+            candidates,
+            min_similarity: self.min_similarity,
+            decision,
+        })
+    }
+
+    /// Write a compact binary snapshot of the tree, to be read back with `load_from`
+    ///
+    /// Snapshots carry a format version and a checksum. Like the serde serialization,
+    /// they hold the settings, the pattern strings and the clusters, but not the compiled
+    /// patterns, the tokenizer, the similarity or the callbacks.
+    /// ```
+    /// let mut drain = drain_rs::DrainTree::new();
+    /// let id = drain.add_log_line_id("user bob logged in").unwrap();
+    /// let mut snapshot = vec![];
+    /// drain.save_to(&mut snapshot).unwrap();
+    ///
+    /// let restored = drain_rs::DrainTree::load_from(snapshot.as_slice())
+    ///     .unwrap()
+    ///     .build_patterns(&mut grok::Grok::with_patterns());
+    /// assert_eq!(restored.log_group("user bob logged in").unwrap().id(), id);
+    /// ```
+    pub fn save_to<W: std::io::Write>(&self, writer: W) -> Result<(), Error> {
+        snapshot::save(self, writer)
+    }
+
+    /// Read a snapshot written by `save_to`, or by any earlier version of this library,
+    /// including the JSON serialization of the tree
+    ///
+    /// As with deserialization, `build_patterns` must be called before adding log lines,
+    /// and the tokenizer, the similarity and the callbacks must be set again.
+    pub fn load_from<R: std::io::Read>(reader: R) -> Result<DrainTree, Error> {
+        snapshot::load(reader)
     }
 
     /// Grab all the current log clusters
@@ -478,8 +2236,8 @@ impl DrainTree {
     /// assert_eq!(drain.log_groups().len(), 2);
     /// ```
     pub fn log_groups(&self) -> Vec<&LogCluster> {
-        self.root
-            .values()
+        self.roots()
+            .flat_map(|root| root.values())
             .flat_map(|n| n.log_groups())
             .collect::<Vec<&LogCluster>>()
     }
@@ -495,7 +2253,7 @@ mod tests {
         let mut v = Vec::with_capacity(strs.len());
         for s in strs.iter() {
             if *s == WILDCARD {
-                v.push(Token::WildCard)
+                v.push(Token::new_empty_wildcard())
             } else {
                 v.push(Token::Val(String::from(*s)))
             }
@@ -503,6 +2261,31 @@ mod tests {
         v
     }
 
+    static NO_FIELDS: Fields = BTreeMap::new();
+
+    fn insertion(min_similarity: f32, next_id: &mut ClusterId) -> Insertion<'_> {
+        Insertion {
+            max_depth: 5,
+            max_children: 100,
+            min_similarity,
+            numeric_prefixes: true,
+            similarity: &SeqDist,
+            next_id: NextId::Counter(next_id),
+            partition: &NO_FIELDS,
+            metadata: &NO_FIELDS,
+            timestamp: 0,
+            rate_window: timestamp::DEFAULT_RATE_WINDOW,
+            sampling: Sampling::default(),
+            tick: 1,
+            track_size: false,
+            observe: false,
+            path: None,
+            created: false,
+            changed_from: None,
+            size_delta: 0,
+        }
+    }
+
     #[test]
     fn patterns_built() {
         let drain = DrainTree::new();
@@ -536,31 +2319,125 @@ mod tests {
         assert!(drain.is_compiled());
     }
 
+    #[test]
+    fn invalid_patterns_reported() {
+        let mut g = grok::Grok::with_patterns();
+        let result = DrainTree::new()
+            .filter_patterns(vec!["%{IPV4:ip_address}", "%{MISSING:a}", "blk_([0-9]+"])
+            .log_pattern("%{ALSO_MISSING:id} %{GREEDYDATA:content}", "content")
+            .try_build_patterns(&mut g);
+        let errors = match result {
+            Err(Error::InvalidPatterns(errors)) => errors,
+            other => panic!("unexpected result {:?}", other),
+        };
+        assert_eq!(
+            errors
+                .iter()
+                .map(|e| (e.kind, e.pattern.as_str()))
+                .collect::<Vec<(PatternKind, &str)>>(),
+            vec![
+                (
+                    PatternKind::LogPattern,
+                    "%{ALSO_MISSING:id} %{GREEDYDATA:content}"
+                ),
+                (PatternKind::FilterPattern, "%{MISSING:a}"),
+                (PatternKind::FilterPattern, "blk_([0-9]+"),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_builds_keep_the_tree() {
+        let mut drain = DrainTree::new()
+            .filter_patterns(vec!["%{IPV4:ip_address}"])
+            .build_patterns(&mut grok::Grok::with_patterns());
+        let id = drain.add_log_line_id("connected to 10.0.0.1").unwrap();
+
+        assert!(drain.try_build_patterns(&mut grok::Grok::empty()).is_err());
+        let cluster = drain.try_add_log_line("connected to 10.0.0.2").unwrap();
+        assert_eq!(cluster.unwrap().id(), id);
+    }
+
+    #[test]
+    fn parallel_ingestion() {
+        let lines = (0..20000)
+            .map(|i| match i % 5 {
+                0 => format!("{} [INFO] user {} called 10.0.{}.{}", i, i, i % 7, i % 200),
+                1 => format!("{} [WARN] disk sda{} is {}% full", i, i % 3, i % 100),
+                2 => String::new(),
+                3 => format!("{} [INFO] job {} finished in {} ms", i, i % 17, i),
+                _ => format!("{} [DEBUG] step {} of job {} done", i, i % 4, i % 9),
+            })
+            .collect::<Vec<String>>();
+        let base = || {
+            DrainTree::new()
+                .filter_patterns(vec!["%{IPV4:ip_address}", "%{NUMBER:number}"])
+                .log_pattern(
+                    "%{NUMBER:id} \\[%{LOGLEVEL:level}\\] %{GREEDYDATA:content}",
+                    "content",
+                )
+                .build_patterns(&mut grok::Grok::with_patterns())
+        };
+        let mut sequential = base();
+        let expected = lines
+            .iter()
+            .map(|line| sequential.add_log_line_id(line))
+            .collect::<Vec<_>>();
+        let mut parallel = base();
+        assert_eq!(parallel.add_log_lines_parallel(&lines), expected);
+        let templates = |drain: &DrainTree| {
+            let mut templates = drain
+                .log_groups()
+                .iter()
+                .map(|c| (c.id(), c.as_detailed_string(), c.num_matched()))
+                .collect::<Vec<_>>();
+            templates.sort();
+            templates
+        };
+        assert_eq!(templates(&parallel), templates(&sequential));
+    }
+
+    #[test]
+    fn parallel_ingestion_of_no_lines() {
+        let mut drain = DrainTree::new();
+        assert_eq!(drain.add_log_lines_parallel::<&str>(&[]), vec![]);
+        assert_eq!(drain.cluster_count(), 0);
+    }
+
     #[test]
     fn similarity_check() {
         let tokens = tokens_from(&["foo", WILDCARD, "foo", "bar", "baz"]);
         let template = tokens_from(&["foo", "bar", WILDCARD, "bar", "baz"]);
-        let group = LogCluster::new(template);
-        let similarity = group.similarity(tokens.as_slice());
+        let group = LogCluster::new(0, template);
+        let similarity = group.similarity(tokens.as_slice(), &SeqDist);
 
         assert!(approx_eq!(f32, similarity.exact_similarity, 0.6));
         assert_eq!(similarity.approximate_similarity, 1);
     }
 
+    #[test]
+    fn wildcards_match_the_values_they_saw() {
+        let mut group = LogCluster::new(0, tokens_from(&["foo", "bar"]));
+        group.add_log(&tokens_from(&["foo", "baz"]), &Sampling::default());
+        let similarity = group.similarity(&tokens_from(&["foo", WILDCARD]), &SeqDist);
+        assert!(approx_eq!(f32, similarity.exact_similarity, 1.0));
+    }
+
     #[test]
     fn best_group() {
         let tokens = tokens_from(&["foo", WILDCARD, "foo", "bar", "baz"]);
 
         let leaf = Leaf {
             log_groups: vec![
-                LogCluster::new(tokens_from(&["foo", "bar", WILDCARD, "bar", "baz"])),
-                LogCluster::new(tokens_from(&["foo", "bar", "other", "bar", "baz"])),
-                LogCluster::new(tokens_from(&["a", "b", WILDCARD, "c", "baz"])),
+                LogCluster::new(0, tokens_from(&["foo", "bar", WILDCARD, "bar", "baz"])),
+                LogCluster::new(1, tokens_from(&["foo", "bar", "other", "bar", "baz"])),
+                LogCluster::new(2, tokens_from(&["a", "b", WILDCARD, "c", "baz"])),
             ],
+            redirects: vec![],
         };
 
         let best_group = leaf
-            .best_group(tokens.as_slice())
+            .best_group(tokens.as_slice(), &SeqDist)
             .expect("missing best group");
 
         assert_eq!(best_group.group_index, 0);
@@ -569,12 +2446,13 @@ mod tests {
 
         let leaf = Leaf {
             log_groups: vec![
-                LogCluster::new(tokens_from(&["a", "b", WILDCARD, "c", "baz"])),
-                LogCluster::new(tokens_from(&["foo", "bar", "other", "bar", "baz"])),
+                LogCluster::new(0, tokens_from(&["a", "b", WILDCARD, "c", "baz"])),
+                LogCluster::new(1, tokens_from(&["foo", "bar", "other", "bar", "baz"])),
             ],
+            redirects: vec![],
         };
         let best_group = leaf
-            .best_group(tokens.as_slice())
+            .best_group(tokens.as_slice(), &SeqDist)
             .expect("missing best group");
 
         assert_eq!(best_group.group_index, 1);
@@ -588,17 +2466,23 @@ mod tests {
         let min_sim = 0.5;
         let leaf_ctor = || Leaf {
             log_groups: vec![
-                LogCluster::new(tokens_from(&["foo", "bar", WILDCARD, "bar", "baz"])),
-                LogCluster::new(tokens_from(&["foo", "bar", "other", "bar", "baz"])),
-                LogCluster::new(tokens_from(&["a", "b", WILDCARD, "c", "baz"])),
+                LogCluster::new(0, tokens_from(&["foo", "bar", WILDCARD, "bar", "baz"])),
+                LogCluster::new(1, tokens_from(&["foo", "bar", "other", "bar", "baz"])),
+                LogCluster::new(2, tokens_from(&["a", "b", WILDCARD, "c", "baz"])),
             ],
+            redirects: vec![],
         };
 
         // Add new group as no similarity was provided
         {
             let mut leaf = leaf_ctor();
-            leaf.add_to_group(Option::None, &min_sim, tokens.as_slice());
+            leaf.add_to_group(
+                Option::None,
+                tokens.as_slice(),
+                &mut insertion(min_sim, &mut 3),
+            );
             assert_eq!(leaf.log_groups.len(), 4);
+            assert_eq!(leaf.log_groups[3].id(), 3);
         }
         // lower than minimum similarity, new group is added
         {
@@ -611,16 +2495,21 @@ mod tests {
                         approximate_similarity: 4,
                     },
                 }),
-                &min_sim,
                 tokens.as_slice(),
+                &mut insertion(min_sim, &mut 3),
             );
             assert_eq!(leaf.log_groups.len(), 4);
         }
 
         {
             let mut leaf = leaf_ctor();
-            leaf.add_to_group(Option::None, &min_sim, tokens.as_slice());
+            leaf.add_to_group(
+                Option::None,
+                tokens.as_slice(),
+                &mut insertion(min_sim, &mut 3),
+            );
             assert_eq!(leaf.log_groups.len(), 4);
+            assert_eq!(leaf.log_groups[3].id(), 3);
         }
         // adds new group and adjusts stored tokens
         {
@@ -633,14 +2522,11 @@ mod tests {
                         approximate_similarity: 3,
                     },
                 }),
-                &min_sim,
                 tokens.as_slice(),
+                &mut insertion(min_sim, &mut 3),
             );
             assert_eq!(leaf.log_groups.len(), 3);
-            assert_eq!(
-                leaf.log_groups[0].log_tokens,
-                tokens_from(&["foo", "bar", WILDCARD, "bar", "baz"])
-            );
+            assert_eq!(leaf.log_groups[0].as_string(), "foo bar <*> bar baz");
         }
     }
 }
@@ -1,17 +1,58 @@
 use crate::grok_generator::GrokGenerator;
+use crate::parse::{ProcessedLine, Variable};
+use crate::sampling::Sampling;
+use crate::similarity::{Similarity, TokenRef};
+use crate::statistics::VariableStats;
+use crate::timestamp::decay;
 use crate::token::Token;
+use chrono::{DateTime, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::fmt::Formatter;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Identifier of a log cluster, unique and stable within the tree that created it
+pub type ClusterId = u64;
+
+// the identifier of the clusters serialized before they had one, until the tree assigns them one
+pub(crate) fn unassigned_id() -> ClusterId {
+    ClusterId::MAX
+}
+
+// maximum number of distinct values kept for every metadata field
+const MAX_METADATA_VALUES: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 /// Represents a cluster of logs
 pub struct LogCluster {
+    // Assigned when the cluster is created and never changed afterwards
+    #[serde(default = "unassigned_id")]
+    id: ClusterId,
     // The tokens representing this unique cluster
     log_tokens: Vec<Token>,
     // The number logs matched
     num_matched: u64,
+    // The number of lines ingested by the tree when this cluster last matched
+    #[serde(default)]
+    last_matched: u64,
+    // The identifiers of the clusters merged into this one
+    #[serde(default)]
+    aliases: Vec<ClusterId>,
+    // The values of the partition fields shared by the logs of this cluster
+    #[serde(default)]
+    partition: BTreeMap<String, String>,
+    // The distinct values seen for the other fields of the logs
+    #[serde(default)]
+    metadata: BTreeMap<String, BTreeSet<String>>,
+    // milliseconds since the Unix epoch of the earliest and latest matched logs
+    #[serde(default)]
+    first_seen: Option<i64>,
+    #[serde(default)]
+    last_seen: Option<i64>,
+    // decaying number of logs per second, as of last_seen
+    #[serde(default)]
+    rate: f64,
 }
 
 impl fmt::Display for LogCluster {
@@ -30,10 +71,100 @@ impl fmt::Display for LogCluster {
 }
 
 impl LogCluster {
-    pub fn new(log_tokens: Vec<Token>) -> LogCluster {
+    pub(crate) fn new(id: ClusterId, log_tokens: Vec<Token>) -> LogCluster {
         LogCluster {
+            id,
             log_tokens,
             num_matched: 1,
+            last_matched: 0,
+            aliases: vec![],
+            partition: BTreeMap::new(),
+            metadata: BTreeMap::new(),
+            first_seen: None,
+            last_seen: None,
+            rate: 0.0,
+        }
+    }
+
+    pub(crate) fn in_partition(mut self, partition: &BTreeMap<String, String>) -> LogCluster {
+        self.partition = partition.clone();
+        self
+    }
+
+    /// The values of the partition fields of this cluster, see `DrainTree::partition_fields`
+    pub fn partition(&self) -> &BTreeMap<String, String> {
+        &self.partition
+    }
+
+    /// The distinct values seen for the fields of the logs that are neither the message nor
+    /// a partition field, up to 10 values per field
+    pub fn metadata(&self) -> &BTreeMap<String, BTreeSet<String>> {
+        &self.metadata
+    }
+
+    pub(crate) fn add_metadata<'a>(
+        &mut self,
+        fields: impl IntoIterator<Item = (&'a String, &'a String)>,
+    ) {
+        for (field, value) in fields {
+            let values = self.metadata.entry(field.clone()).or_default();
+            if values.len() < MAX_METADATA_VALUES {
+                values.insert(value.clone());
+            }
+        }
+    }
+
+    /// The stable identifier of this cluster
+    pub fn id(&self) -> ClusterId {
+        self.id
+    }
+
+    pub(crate) fn assign_id(&mut self, id: ClusterId) {
+        self.id = id;
+    }
+
+    /// The identifiers of the clusters that were merged into this one
+    pub fn aliases(&self) -> &[ClusterId] {
+        &self.aliases
+    }
+
+    /// Whether this cluster is known by the given identifier, either its own or an alias
+    pub fn has_id(&self, id: ClusterId) -> bool {
+        self.id == id || self.aliases.contains(&id)
+    }
+
+    pub(crate) fn tokens(&self) -> &[Token] {
+        &self.log_tokens
+    }
+
+    /// Absorb the other cluster, generalizing the template to cover both of them
+    pub(crate) fn merge(&mut self, other: LogCluster, rate_window: f64, sampling: &Sampling) {
+        for (token, other_token) in self.log_tokens.iter_mut().zip(other.log_tokens.iter()) {
+            token.merge(self.num_matched, other_token, other.num_matched, sampling);
+        }
+        self.num_matched += other.num_matched;
+        self.last_matched = self.last_matched.max(other.last_matched);
+        if let Some(last_seen) = other.last_seen {
+            self.rate = match self.last_seen {
+                Some(own) if last_seen > own => {
+                    self.last_seen = Some(last_seen);
+                    decay(self.rate, last_seen - own, rate_window) + other.rate
+                }
+                Some(own) => self.rate + decay(other.rate, own - last_seen, rate_window),
+                None => {
+                    self.last_seen = Some(last_seen);
+                    other.rate
+                }
+            };
+        }
+        self.first_seen = match (self.first_seen, other.first_seen) {
+            (Some(own), Some(first_seen)) => Some(own.min(first_seen)),
+            (own, first_seen) => own.or(first_seen),
+        };
+        self.aliases.push(other.id);
+        self.aliases.extend(other.aliases);
+        for (field, values) in other.metadata.iter() {
+            self.add_metadata(values.iter().map(|value| (field, value)));
         }
     }
 
@@ -42,13 +173,95 @@ impl LogCluster {
         self.num_matched
     }
 
+    /// The number of lines ingested by the tree when this cluster last matched a line
+    pub fn last_matched(&self) -> u64 {
+        self.last_matched
+    }
+
+    /// When the earliest log of this cluster happened, as extracted from the log lines when
+    /// `DrainTree::timestamp_field` is set, or when it was added to the tree otherwise
+    pub fn first_seen(&self) -> Option<DateTime<Utc>> {
+        Utc.timestamp_millis_opt(self.first_seen?).single()
+    }
+
+    /// When the latest log of this cluster happened, see `first_seen`
+    pub fn last_seen(&self) -> Option<DateTime<Utc>> {
+        Utc.timestamp_millis_opt(self.last_seen?).single()
+    }
+
+    /// Decaying estimate of the number of logs per second, as of `last_seen`
+    ///
+    /// Every log counts for `1 / rate_window` and decays exponentially with the time constant
+    /// `DrainTree::rate_window`, so a steady stream of logs converges to its actual rate.
+    /// See `DrainTree::events_per_second` for the estimate at a later time.
+    pub fn events_per_second(&self) -> f64 {
+        self.rate
+    }
+
+    pub(crate) fn rate_at(&self, timestamp: i64, rate_window: f64) -> f64 {
+        match self.last_seen {
+            Some(last_seen) if timestamp > last_seen => {
+                decay(self.rate, timestamp - last_seen, rate_window)
+            }
+            Some(_) => self.rate,
+            None => 0.0,
+        }
+    }
+
+    /// Account for a log happening at the timestamp, in milliseconds since the Unix epoch
+    pub(crate) fn observe(&mut self, timestamp: i64, rate_window: f64) {
+        let weight = 1.0 / rate_window;
+        match self.last_seen {
+            Some(last_seen) if timestamp >= last_seen => {
+                self.rate = decay(self.rate, timestamp - last_seen, rate_window) + weight;
+                self.last_seen = Some(timestamp);
+            }
+            // logs arriving out of order count as already decayed
+            Some(last_seen) => self.rate += decay(weight, last_seen - timestamp, rate_window),
+            None => {
+                self.rate = weight;
+                self.last_seen = Some(timestamp);
+            }
+        }
+        self.first_seen = Some(
+            self.first_seen
+                .map_or(timestamp, |first| first.min(timestamp)),
+        );
+    }
+
+    pub(crate) fn touch(&mut self, tick: u64) {
+        self.last_matched = tick;
+    }
+
+    /// Approximate number of bytes used by this cluster, including the sampled wildcard values
+    /// and the metadata values
+    pub fn approximate_size(&self) -> usize {
+        let string_size = |s: &String| std::mem::size_of::<String>() + s.len();
+        std::mem::size_of::<LogCluster>()
+            + self
+                .log_tokens
+                .iter()
+                .map(|t| t.approximate_size())
+                .sum::<usize>()
+            + self
+                .partition
+                .iter()
+                .map(|(k, v)| string_size(k) + string_size(v))
+                .sum::<usize>()
+            + self
+                .metadata
+                .iter()
+                .map(|(k, values)| string_size(k) + values.iter().map(string_size).sum::<usize>())
+                .sum::<usize>()
+    }
+
     /// Grab the current token strings
     pub fn as_string(&self) -> String {
-        self.log_tokens
-            .iter()
-            .map(|t| t.to_string())
-            .collect::<Vec<String>>()
-            .join(" ")
+        self.template_tokens().join(" ")
+    }
+
+    pub(crate) fn template_tokens(&self) -> Vec<String> {
+        self.log_tokens.iter().map(|t| t.to_string()).collect()
     }
 
     /// Grab the current detailed token strings
@@ -60,62 +273,105 @@ impl LogCluster {
             .join(" ")
     }
 
-    pub fn similarity(&self, log: &[Token]) -> GroupSimilarity {
-        let len = self.log_tokens.len() as f32;
-        let mut approximate_similarity: u32 = 0;
-        let mut exact_similarity: f32 = 0.0;
+    /// The estimated number of distinct values replaced by each wildcard of the template,
+    /// with the position of the wildcard
+    ///
+    /// The estimates come from a HyperLogLog sketch, within a few percent of the actual counts.
+    /// ```
+    /// let mut drain = drain_rs::DrainTree::new();
+    /// for i in 0..100 {
+    ///     drain.add_log_line(&format!("worker started on host-{}", i % 20));
+    /// }
+    /// let cluster = drain.log_group("worker started on host-0").unwrap();
+    /// let (position, distinct) = cluster.distinct_counts()[0];
+    /// assert_eq!(position, 3);
+    /// assert!((18..=22).contains(&distinct));
+    /// ```
+    pub fn distinct_counts(&self) -> Vec<(usize, u64)> {
+        self.log_tokens
+            .iter()
+            .enumerate()
+            .filter_map(|(position, token)| Some((position, token.distinct_count()?)))
+            .collect()
+    }
 
-        for (pattern, token) in self.log_tokens.iter().zip(log.iter()) {
-            if token == pattern {
-                exact_similarity += 1.0;
-            } else if (*pattern).is_wildcard() {
-                approximate_similarity += 1;
-            }
-        }
-        GroupSimilarity {
-            approximate_similarity,
-            exact_similarity: exact_similarity / len,
-        }
+    /// The statistics of the values replaced by each wildcard of the template, with the
+    /// position of the wildcard, once enabled by `DrainTree::variable_statistics`
+    ///
+    /// Wildcards that never replaced a value since then are left out.
+    pub fn variable_stats(&self) -> Vec<(usize, &VariableStats)> {
+        self.log_tokens
+            .iter()
+            .enumerate()
+            .filter_map(|(position, token)| Some((position, token.variable_stats()?)))
+            .filter(|(_, stats)| !stats.is_empty())
+            .collect()
     }
 
-    pub fn add_log(&mut self, new_log: &[Token]) -> &LogCluster {
+    pub(crate) fn similarity(&self, log: &[Token], similarity: &dyn Similarity) -> GroupSimilarity {
+        let template = TokenRef::all(&self.log_tokens).collect::<Vec<TokenRef<'_>>>();
+        let log = TokenRef::all(log).collect::<Vec<TokenRef<'_>>>();
+        similarity.similarity(&template, &log)
+    }
+
+    pub(crate) fn add_log(&mut self, new_log: &[Token], sampling: &Sampling) -> &LogCluster {
         // update log cluster if we detect variable parts
         for (new_token, stored_token) in new_log.iter().zip(self.log_tokens.iter_mut()) {
-            if !new_token.is_wildcard() {
+            if let Token::Val(value) = new_token {
                 // check if the current log_line token is the different from the logcluster tokens
+                // the constant it replaces was seen by every log matched so far
                 if new_token != stored_token && !stored_token.is_wildcard() {
-                    *stored_token = Token::new_empty_wildcard();
+                    stored_token.merge(self.num_matched, new_token, 1, sampling);
+                } else {
+                    stored_token.sample(value, 1, sampling);
                 }
-                stored_token.add_token_if_wildcard(&new_token);
             }
         }
         self.num_matched += 1;
         self
     }
-    pub fn extract_variables(&self, log: &[Token]) -> Vec<String> {
-        // Extract values of the variable parts into a hashmap
+    pub(crate) fn extract_variables(&self, log: &ProcessedLine) -> Vec<Variable> {
+        // Extract values of the variable parts, either facing a wildcard or filtered
         let mut variables = vec![];
-        for (i, token) in log.iter().enumerate() {
-            if self.log_tokens[i].is_wildcard() {
-                // println!("{} -> {}", token, self.log_tokens[i].as_string_detailed());
-                variables.push(token.to_string());
+        for (i, template_token) in self.log_tokens.iter().enumerate().take(log.len()) {
+            if template_token.is_wildcard() || log.names[i].is_some() {
+                variables.push(Variable {
+                    position: i,
+                    name: log.names[i].clone(),
+                    value: log.values[i].clone(),
+                });
             }
         }
         variables
     }
 
+    /// The anchored GROK pattern matching the log lines of this cluster,
+    /// see `GrokGenerator::generate_grok_from_logcluster`
     pub fn detect_best_grok(&self, grok_generator: &GrokGenerator) -> Option<String> {
-        self.log_tokens
-            .iter()
-            .for_each(|token| print!("{} ", token.detect_best_grok(&grok_generator)));
-        println!("#");
-        Some("test".to_string())
+        grok_generator.generate_grok_from_logcluster(self)
+    }
+}
+
+/// Fraction of positions where both templates hold the same constant or wildcards of the
+/// same kind
+pub(crate) fn template_similarity(template: &[Token], other: &[Token]) -> f32 {
+    if template.is_empty() || template.len() != other.len() {
+        return 0.0;
     }
+    let same = template
+        .iter()
+        .zip(other.iter())
+        .filter(|(a, b)| a.matches(b))
+        .count();
+    same as f32 / template.len() as f32
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
+/// How similar a log line is to the template of a cluster
 pub struct GroupSimilarity {
-    approximate_similarity: u32,
+    /// Number of template wildcards facing a differing token, used to break ties
+    pub approximate_similarity: u32,
+    /// Fraction of tokens exactly equal to the template
     pub exact_similarity: f32,
 }
 
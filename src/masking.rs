@@ -0,0 +1,429 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A rule of the masking pipeline, see `DrainTree::mask` and `DrainTree::masking_rules`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MaskingRule {
+    /// Replace every match of the regex or grok pattern by the label, e.g. `<HEX>`
+    Pattern {
+        /// The regex or grok pattern to mask
+        pattern: String,
+        /// The label replacing the matches, without the angle brackets
+        label: String,
+    },
+    /// Send the tokens containing a digit down the same `<NUM>` branch of the prefix tree,
+    /// the heuristic of the original drain, the tokens staying constants of the templates
+    ///
+    /// It keeps numbers from multiplying the branches of the tree, at the cost of sending
+    /// different constants containing digits, such as `HTTP/1.1` and `v2`, to the same leaf.
+    NumericPrefixes,
+}
+
+impl MaskingRule {
+    /// Replace every match of the regex or grok pattern by the label
+    pub fn pattern(pattern: &str, label: &str) -> Self {
+        MaskingRule::Pattern {
+            pattern: String::from(pattern),
+            label: String::from(label),
+        }
+    }
+}
+
+/// The pipeline of a new tree, only holding the digit heuristic
+pub(crate) fn default_rules() -> Vec<MaskingRule> {
+    vec![MaskingRule::NumericPrefixes]
+}
+
+/// Compile a pattern rule into a regex that can find all of its matches in a single pass
+///
+/// The grok crate neither gives the position of a match nor iterates over the matches, so the
+/// pattern is checked by grok, then its references are expanded into the regex compiled here.
+pub(crate) fn compile(pattern: &str, grok: &mut grok::Grok) -> Result<onig::Regex, grok::Error> {
+    grok.compile(pattern, true)?;
+    let regex = expand(pattern)?;
+    onig::Regex::new(&regex).map_err(|_| grok::Error::RegexCompilationFailed(regex))
+}
+
+// the most references expanded in a pattern, the limit of the grok crate
+const MAX_EXPANSIONS: usize = 1024;
+
+/// Expand the `%{NAME}` and `%{NAME:field}` references of a grok pattern, recursively, into
+/// the definitions of the base patterns of the grok crate
+fn expand(pattern: &str) -> Result<String, grok::Error> {
+    let reference = onig::Regex::new(r"%\{(\w+)(?::[^}]*)?\}")
+        .map_err(|_| grok::Error::RegexCompilationFailed(pattern.to_string()))?;
+    let definitions = grok::patterns()
+        .iter()
+        .copied()
+        .collect::<HashMap<&str, &str>>();
+    let mut regex = pattern.to_string();
+    for _ in 0..MAX_EXPANSIONS {
+        let (start, end, name) = match reference.captures(&regex) {
+            Some(captures) => match (captures.pos(0), captures.at(1)) {
+                (Some((start, end)), Some(name)) => (start, end, name.to_string()),
+                _ => return Err(grok::Error::RegexCompilationFailed(regex)),
+            },
+            None => return Ok(regex),
+        };
+        let definition = definitions
+            .get(name.as_str())
+            .ok_or(grok::Error::DefinitionNotFound(name))?;
+        regex.replace_range(start..end, &format!("(?:{})", definition));
+    }
+    Err(grok::Error::RecursionTooDeep)
+}
+
+/// A compiled pattern rule
+#[derive(Debug)]
+pub(crate) struct Mask {
+    pub regex: onig::Regex,
+    pub label: String,
+}
+
+// a part of the log line, by byte offsets in the line
+#[derive(Debug, Clone, Copy)]
+enum Piece<'a> {
+    Text(usize, usize),
+    Mask(usize, usize, &'a str),
+}
+
+impl Piece<'_> {
+    // length of the piece in the masked text
+    fn masked_len(&self) -> usize {
+        match self {
+            Piece::Text(start, end) => end - start,
+            Piece::Mask(_, _, label) => label.len() + 2,
+        }
+    }
+}
+
+/// A log line whose masked parts are replaced by the labels of the masks, e.g. `<IP>`
+#[derive(Debug)]
+pub(crate) struct MaskedLine<'a> {
+    pub text: String,
+    line: &'a str,
+    // the pieces of the line with their offset in the masked text
+    pieces: Vec<(usize, Piece<'a>)>,
+}
+
+impl<'a> MaskedLine<'a> {
+    /// Apply the rules in order, each rule searching the text masked by the previous ones
+    /// and only masking the matches that do not overlap a label
+    pub fn new(masks: &'a [Mask], line: &'a str) -> Self {
+        let mut masked = MaskedLine {
+            text: line.to_string(),
+            line,
+            pieces: vec![(0, Piece::Text(0, line.len()))],
+        };
+        for mask in masks {
+            masked.apply(mask);
+        }
+        masked
+    }
+
+    fn apply(&mut self, mask: &'a Mask) {
+        let mut matches = mask
+            .regex
+            .find_iter(&self.text)
+            .filter(|(start, end)| start < end)
+            .peekable();
+        if matches.peek().is_none() {
+            return;
+        }
+        let mut pieces = Vec::with_capacity(self.pieces.len() + 2);
+        for &(offset, piece) in self.pieces.iter() {
+            let (start, end) = match piece {
+                Piece::Text(start, end) => (start, end),
+                piece => {
+                    pieces.push(piece);
+                    continue;
+                }
+            };
+            let (text_start, text_end) = (offset, offset + end - start);
+            let mut cursor = start;
+            while let Some(&(match_start, match_end)) = matches.peek() {
+                if match_start >= text_end {
+                    break;
+                }
+                matches.next();
+                if match_start < text_start || match_end > text_end {
+                    continue;
+                }
+                let (match_start, match_end) =
+                    (start + match_start - offset, start + match_end - offset);
+                if match_start > cursor {
+                    pieces.push(Piece::Text(cursor, match_start));
+                }
+                pieces.push(Piece::Mask(match_start, match_end, &mask.label));
+                cursor = match_end;
+            }
+            if cursor < end {
+                pieces.push(Piece::Text(cursor, end));
+            }
+        }
+        let mut text = String::with_capacity(self.text.len());
+        self.pieces = pieces
+            .into_iter()
+            .map(|piece| {
+                let offset = text.len();
+                match piece {
+                    Piece::Text(start, end) => text.push_str(&self.line[start..end]),
+                    Piece::Mask(_, _, label) => {
+                        text.push('<');
+                        text.push_str(label);
+                        text.push('>');
+                    }
+                }
+                (offset, piece)
+            })
+            .collect();
+        self.text = text;
+    }
+
+    /// The original text of a token of the masked text, and the label of its first mask
+    ///
+    /// The original text of a token holding masks spans from the start of its first mask to
+    /// the end of its last one, e.g. `abcd` for the token `(key=<HEX>)`.
+    /// Tokens that are not slices of the masked text are returned as is.
+    pub fn original(&self, token: &str) -> (String, Option<&'a str>) {
+        let start = (token.as_ptr() as usize).wrapping_sub(self.text.as_ptr() as usize);
+        let end = start.wrapping_add(token.len());
+        if self.pieces.len() == 1 || start > self.text.len() || end > self.text.len() {
+            return (token.to_string(), None);
+        }
+        let mut masks = self
+            .pieces
+            .iter()
+            .filter_map(|(offset, piece)| match *piece {
+                Piece::Mask(mask_start, mask_end, label)
+                    if *offset < end && offset + piece.masked_len() > start =>
+                {
+                    Some((mask_start, mask_end, label))
+                }
+                _ => None,
+            });
+        match masks.next() {
+            Some((first_start, first_end, label)) => {
+                let last_end = masks.next_back().map_or(first_end, |(_, end, _)| end);
+                (self.line[first_start..last_end].to_string(), Some(label))
+            }
+            // the text around the masks is the original one
+            None => (token.to_string(), None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BranchReason, DrainTree, Error, PatternKind};
+
+    const LINE: &str =
+        "ssh session c0ffee00-1234-5678-9abc-def012345678 from 10.0.0.1 port 22 (key=beef42)";
+
+    fn masks(rules: &[(&str, &str)]) -> Vec<Mask> {
+        let mut grok = grok::Grok::with_patterns();
+        rules
+            .iter()
+            .map(|(pattern, label)| Mask {
+                regex: compile(pattern, &mut grok).unwrap(),
+                label: label.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_keep_the_context_of_the_line() {
+        let masks = masks(&[(r"\bx", "X"), (r"(?<=id=)\d+", "ID")]);
+        let masked = MaskedLine::new(&masks, "xx id=12 id=34");
+        assert_eq!(masked.text, "<X>x id=<ID> id=<ID>");
+    }
+
+    #[test]
+    fn later_rules_do_not_match_labels() {
+        let masks = masks(&[("%{IP}", "IP"), ("[A-Z]+", "WORD")]);
+        let masked = MaskedLine::new(&masks, "from 10.0.0.1 to HOST");
+        assert_eq!(masked.text, "from <IP> to <WORD>");
+    }
+
+    #[test]
+    fn original_is_the_masked_span() {
+        let masks = masks(&[("0x[0-9a-f]+", "HEX")]);
+        let masked = MaskedLine::new(&masks, "read (addr=0x1f..0x2a) ok");
+        let token = masked.text.split(' ').nth(1).unwrap();
+        assert_eq!(token, "(addr=<HEX>..<HEX>)");
+        assert_eq!(
+            masked.original(token),
+            ("0x1f..0x2a".to_string(), Some("HEX"))
+        );
+        let token = masked.text.split(' ').nth(2).unwrap();
+        assert_eq!(masked.original(token), ("ok".to_string(), None));
+    }
+
+    #[test]
+    fn grok_patterns_are_expanded() {
+        let mut grok = grok::Grok::with_patterns();
+        let regex = compile("%{IPV4}:%{POSINT}", &mut grok).unwrap();
+        assert_eq!(regex.find("to 10.0.0.1:80 now"), Some((3, 14)));
+        assert!(compile("%{NOT_A_PATTERN}", &mut grok).is_err());
+        assert!(compile("(", &mut grok).is_err());
+    }
+
+    #[test]
+    fn nested_references_are_expanded() {
+        let mut grok = grok::Grok::with_patterns();
+        let regex = compile("%{IP:client} port %{INT}", &mut grok).unwrap();
+        assert_eq!(regex.find("from 10.0.0.1 port 22"), Some((5, 21)));
+
+        // only the base patterns are expanded
+        grok.insert_definition("SESSION", "s[0-9]+");
+        assert!(matches!(
+            compile("%{SESSION}", &mut grok),
+            Err(grok::Error::DefinitionNotFound(name)) if name == "SESSION"
+        ));
+    }
+
+    fn drain() -> DrainTree {
+        DrainTree::new()
+            .max_depth(2)
+            .mask("%{IP} port %{INT}", "ADDR")
+            .mask("%{UUID}", "UUID")
+            .mask("[0-9a-f]{4,}", "HEX")
+            .mask("x*", "EMPTY")
+            .build_patterns(&mut grok::Grok::with_patterns())
+    }
+
+    #[test]
+    fn templates_hold_the_labels() {
+        let mut drain = drain();
+        let cluster = drain.add_log_line(LINE).unwrap();
+        assert_eq!(
+            cluster.as_string(),
+            "ssh session <UUID> from <ADDR> (key=<HEX>)"
+        );
+    }
+
+    #[test]
+    fn variables_hold_the_masked_text() {
+        let mut drain = drain();
+        let id = drain.add_log_line_id(LINE).unwrap();
+        let parsed = drain
+            .parse("ssh session 00000000-0000-0000-0000-000000000000 from 10.1.1.1 port 2222 (key=abcd)")
+            .unwrap();
+        assert_eq!(parsed.cluster.id(), id);
+        let variables = parsed
+            .variables
+            .iter()
+            .map(|v| (v.position, v.name.as_deref(), v.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            variables,
+            vec![
+                (2, Some("UUID"), "00000000-0000-0000-0000-000000000000"),
+                (4, Some("ADDR"), "10.1.1.1 port 2222"),
+                (5, Some("HEX"), "abcd"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rules_are_built_again_after_deserialization() {
+        let mut drain = drain();
+        let id = drain.add_log_line_id(LINE).unwrap();
+        let json = serde_json::to_string(&drain).unwrap();
+
+        let mut restored: DrainTree = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            restored.try_add_log_line(LINE).unwrap_err(),
+            Error::PatternsNotBuilt
+        ));
+        let restored = restored.build_patterns(&mut grok::Grok::with_patterns());
+        assert_eq!(restored.log_group(LINE).unwrap().id(), id);
+    }
+
+    #[test]
+    fn rules_run_on_the_extracted_message() {
+        let mut drain = DrainTree::new()
+            .log_pattern("%{INT:id} %{GREEDYDATA:content}", "content")
+            .mask(r"^\w+", "FIRST")
+            .build_patterns(&mut grok::Grok::with_patterns());
+        let cluster = drain.add_log_line("42 retry 3 times").unwrap();
+        assert_eq!(cluster.as_string(), "<FIRST> 3 times");
+    }
+
+    #[test]
+    fn invalid_rules_are_reported() {
+        let errors = match DrainTree::new()
+            .mask("(", "BROKEN")
+            .try_build_patterns(&mut grok::Grok::with_patterns())
+        {
+            Err(Error::InvalidPatterns(errors)) => errors,
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        };
+        assert_eq!(errors[0].kind, PatternKind::MaskingRule);
+    }
+
+    #[test]
+    fn numeric_prefixes_share_a_branch() {
+        let mut drain = DrainTree::new();
+        let id = drain.add_log_line_id("worker 12 started").unwrap();
+        assert_eq!(drain.log_group("worker 13 started").unwrap().id(), id);
+
+        let explanation = drain.explain("worker 14 started").unwrap();
+        assert_eq!(explanation.steps[1].branch, "<NUM>");
+        assert_eq!(explanation.steps[1].reason, BranchReason::Numeric);
+    }
+
+    #[test]
+    fn numeric_prefixes_stay_out_of_the_samples() {
+        let mut drain = DrainTree::new();
+        drain.add_log_line("worker 12 started");
+        let cluster = drain.add_log_line("worker 15 started").unwrap();
+        assert_eq!(
+            cluster.as_detailed_string(),
+            r#"worker <** ["12", "15"] **> started"#
+        );
+    }
+
+    #[test]
+    fn numeric_prefixes_survive_serialization() {
+        let mut drain = DrainTree::new();
+        let id = drain.add_log_line_id("worker 12 started").unwrap();
+        let json = serde_json::to_string(&drain).unwrap();
+        assert!(json.contains(r#""<NUM>":"#));
+
+        let mut restored: DrainTree = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.log_group("worker 16 started").unwrap().id(), id);
+        restored.add_log_line("worker 17 started");
+        assert_eq!(restored.cluster_count(), 1);
+    }
+
+    #[test]
+    fn numeric_prefixes_follow_older_wildcard_branches() {
+        let mut drain = DrainTree::new();
+        let id = drain.add_log_line_id("worker 12 started").unwrap();
+        // the trees saved before the `<NUM>` branches sent the numbers to the `<*>` branch
+        let json = serde_json::to_string(&drain)
+            .unwrap()
+            .replace(r#""<NUM>":"#, r#""<*>":"#);
+
+        let mut restored: DrainTree = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.log_group("worker 16 started").unwrap().id(), id);
+        let explanation = restored.explain("worker 16 started").unwrap();
+        assert_eq!(explanation.steps[1].branch, "<*>");
+        assert_eq!(explanation.steps[1].reason, BranchReason::Numeric);
+        restored.add_log_line("worker 17 started");
+        assert_eq!(restored.cluster_count(), 1);
+    }
+
+    #[test]
+    fn numeric_prefixes_can_be_switched_off() {
+        let clusters = |mut drain: DrainTree| {
+            drain.add_log_line("v1 started ok");
+            drain.add_log_line("v2 started ok");
+            drain.cluster_count()
+        };
+        assert_eq!(clusters(DrainTree::new()), 1);
+        assert_eq!(clusters(DrainTree::new().masking_rules(vec![])), 2);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::log_cluster::{template_similarity, ClusterId, LogCluster};
+use crate::sampling::Sampling;
+use crate::token::Token;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Periodic merge pass run while ingesting log lines
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct AutoMerge {
+    pub threshold: f32,
+    pub interval: u64,
+}
+
+/// Decide which clusters to merge, as pairs of (merged cluster, surviving cluster)
+///
+/// Clusters are visited by increasing identifier within each partition and template length,
+/// each one is merged into the first surviving cluster it is similar enough to, otherwise
+/// it survives.
+/// The template of a survivor is generalized as clusters are merged into it.
+pub(crate) fn plan_merges(clusters: &[&LogCluster], threshold: f32) -> Vec<(ClusterId, ClusterId)> {
+    let mut groups: BTreeMap<(&BTreeMap<String, String>, usize), Vec<&LogCluster>> =
+        BTreeMap::new();
+    for cluster in clusters {
+        groups
+            .entry((cluster.partition(), cluster.tokens().len()))
+            .or_default()
+            .push(cluster);
+    }
+    let mut plan = vec![];
+    for group in groups.values_mut() {
+        group.sort_by_key(|c| c.id());
+        let mut survivors: Vec<(ClusterId, Vec<Token>)> = vec![];
+        for cluster in group.iter() {
+            match survivors
+                .iter_mut()
+                .find(|(_, template)| template_similarity(template, cluster.tokens()) >= threshold)
+            {
+                Some((id, template)) => {
+                    for (token, other) in template.iter_mut().zip(cluster.tokens()) {
+                        token.merge(1, other, 1, &Sampling::default());
+                    }
+                    plan.push((cluster.id(), *id));
+                }
+                None => survivors.push((cluster.id(), cluster.tokens().to_vec())),
+            }
+        }
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Decision, DrainTree};
+
+    // two clusters with the same template, split by the value of their first token
+    fn split_tree() -> DrainTree {
+        let mut drain = DrainTree::new().max_depth(3);
+        drain.add_log_line("alpha task done now");
+        drain.add_log_line("beta task done now");
+        assert_eq!(drain.cluster_count(), 2);
+        drain
+    }
+
+    #[test]
+    fn lines_of_merged_clusters_join_the_survivor() {
+        let mut drain = split_tree();
+        assert_eq!(drain.merge_clusters(0.7), vec![(1, 0)]);
+
+        assert_eq!(drain.add_log_line_id("alpha task done now"), Some(0));
+        assert_eq!(drain.add_log_line_id("beta task done now"), Some(0));
+        assert_eq!(drain.cluster_count(), 1);
+        assert_eq!(drain.cluster_by_id(0).unwrap().num_matched(), 4);
+    }
+
+    #[test]
+    fn lookups_follow_merged_clusters() {
+        let mut drain = split_tree();
+        drain.merge_clusters(0.7);
+
+        assert_eq!(drain.log_group("beta task done now").unwrap().id(), 0);
+        let explanation = drain.explain("alpha task done now").unwrap();
+        assert_eq!(explanation.decision, Decision::Matched(0));
+    }
+
+    #[test]
+    fn dissimilar_lines_still_create_clusters() {
+        let mut drain = split_tree();
+        drain.merge_clusters(0.7);
+
+        assert_eq!(drain.add_log_line_id("alpha job failed badly"), Some(2));
+        assert_eq!(drain.add_log_line_id("alpha task done now"), Some(0));
+    }
+
+    #[test]
+    fn survivors_merged_again_keep_the_redirects() {
+        let mut drain = split_tree();
+        drain.merge_clusters(0.7);
+        drain.add_log_line("gamma task done later");
+        assert_eq!(drain.merge_clusters(0.5), vec![(2, 0)]);
+
+        assert_eq!(drain.add_log_line_id("beta task done now"), Some(0));
+        assert_eq!(drain.add_log_line_id("gamma task done later"), Some(0));
+        assert_eq!(drain.cluster_count(), 1);
+    }
+
+    #[test]
+    fn auto_merge_runs_every_interval() {
+        let mut drain = DrainTree::new()
+            .max_depth(3)
+            .max_children(1)
+            .auto_merge(0.7, 2);
+        let alpha = drain.add_log_line_id("alpha task done now").unwrap();
+        let merged = drain.add_log_line("beta task done now").unwrap();
+        assert_eq!(merged.id(), alpha);
+        assert_eq!(merged.aliases().len(), 1);
+        assert_eq!(drain.cluster_count(), 1);
+
+        let cluster = drain.log_group("gamma task done now").unwrap();
+        assert_eq!(cluster.id(), alpha);
+        assert_eq!(cluster.num_matched(), 2);
+    }
+
+    #[test]
+    fn auto_merge_plans_the_touched_buckets() {
+        let mut drain = split_tree().auto_merge(0.7, 1);
+        drain.add_log_line("disk full");
+        assert_eq!(drain.cluster_count(), 3);
+
+        let merged = drain.add_log_line("gamma task done now").unwrap();
+        assert_eq!(merged.id(), 0);
+        assert_eq!(drain.cluster_count(), 2);
+    }
+
+    #[test]
+    fn dissimilar_clusters_are_not_merged() {
+        let mut drain = DrainTree::new().max_depth(3).max_children(1);
+        drain.add_log_line("alpha task done now");
+        drain.add_log_line("delta task failed later");
+        assert_eq!(drain.merge_clusters(0.7), vec![]);
+        assert_eq!(drain.cluster_count(), 2);
+    }
+
+    #[test]
+    fn deserialized_zero_intervals_do_not_merge() {
+        let drain = DrainTree::new().max_depth(3).auto_merge(0.7, 1);
+        let json = serde_json::to_string(&drain)
+            .unwrap()
+            .replace(r#""interval":1"#, r#""interval":0"#);
+        let mut drain: DrainTree = serde_json::from_str(&json).unwrap();
+        drain.add_log_line("alpha task done now");
+        drain.add_log_line("beta task done now");
+        assert_eq!(drain.cluster_count(), 2);
+    }
+
+    #[test]
+    fn redirects_are_serialized() {
+        let mut drain = split_tree();
+        drain.merge_clusters(0.7);
+        let json = serde_json::to_string(&drain).unwrap();
+
+        let mut restored: DrainTree = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.add_log_line_id("beta task done now"), Some(0));
+        assert_eq!(restored.cluster_count(), 1);
+    }
+}
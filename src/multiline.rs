@@ -0,0 +1,419 @@
+use crate::error::{Error, PatternError, PatternKind};
+use std::time::{Duration, Instant};
+
+/// A log event made of one or more log lines, see `MultilineAggregator`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    lines: Vec<String>,
+    signature: Option<String>,
+}
+
+// lines of Python tracebacks and Java stack traces that are not indented
+const CONTINUATION_PREFIXES: &[&str] = &[
+    "Caused by:",
+    "Traceback (most recent call last)",
+    "During handling of the above exception",
+    "The above exception was the direct cause",
+];
+
+/// The exception type a line names, as in `java.io.IOException: closed`,
+/// `Caused by: java.io.IOException` or `KeyError: 'id'`
+fn exception_type(line: &str) -> Option<&str> {
+    let line = line.trim();
+    let line = line.strip_prefix("Caused by:").unwrap_or(line).trim_start();
+    let name = match line.find(':') {
+        Some(i) => &line[..i],
+        None => line,
+    };
+    let is_type = !name.is_empty()
+        && !name.contains(char::is_whitespace)
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || "._$".contains(c))
+        && (name.ends_with("Exception") || name.ends_with("Error") || name.ends_with("Throwable"));
+    if is_type {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+impl Event {
+    /// Create an event from its lines, the exception chain is not extracted
+    pub fn new(lines: Vec<String>) -> Self {
+        Event {
+            lines,
+            signature: None,
+        }
+    }
+
+    /// Every line of the event, in order
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// The first line of the event, which starts it
+    pub fn first_line(&self) -> &str {
+        self.lines.first().map(|l| l.as_str()).unwrap_or("")
+    }
+
+    /// The lines of the event joined by line breaks
+    pub fn text(&self) -> String {
+        self.lines.join("\n")
+    }
+
+    /// The exception types named by the event, in order and separated by spaces,
+    /// when the aggregator is configured to extract them, see `MultilineAggregator::signature`
+    /// ```
+    /// use drain_rs::MultilineAggregator;
+    /// let mut aggregator = MultilineAggregator::new().indentation(true).signature(true);
+    /// aggregator.push("request failed");
+    /// aggregator.push("java.lang.IllegalStateException: no session");
+    /// aggregator.push("    at com.foo.Session.get(Session.java:42)");
+    /// aggregator.push("Caused by: java.io.IOException: closed");
+    /// aggregator.push("    at com.foo.Socket.read(Socket.java:7)");
+    /// let event = aggregator.flush().unwrap();
+    /// assert_eq!(event.lines().len(), 5);
+    /// assert_eq!(
+    ///     event.signature(),
+    ///     Some("java.lang.IllegalStateException java.io.IOException")
+    /// );
+    /// ```
+    pub fn signature(&self) -> Option<&str> {
+        self.signature.as_deref()
+    }
+
+    /// The text that is clustered: the first line, followed by the signature if any
+    pub fn message(&self) -> String {
+        match &self.signature {
+            Some(signature) => format!("{} {}", self.first_line(), signature),
+            None => self.first_line().to_string(),
+        }
+    }
+}
+
+/// Assembles consecutive log lines into events, before they are clustered
+///
+/// A line continues the current event when it is indented or is part of an exception chain,
+/// if `indentation` is enabled, or when it does not match the `start_pattern`, if one is set.
+/// Otherwise it starts a new event.
+/// An event is also closed once it holds `max_lines` lines, or when a line arrives after
+/// `timeout` elapsed since the event started.
+/// ```
+/// use drain_rs::{DrainTree, MultilineAggregator};
+/// let mut aggregator = MultilineAggregator::new()
+///     .start_pattern("^%{TIMESTAMP_ISO8601} ")
+///     .build_patterns(&mut grok::Grok::with_patterns());
+/// let mut drain = DrainTree::new();
+/// let lines = [
+///     "2021-04-01T10:00:00 ERROR request failed",
+///     "java.lang.IllegalStateException: no session",
+///     "\tat com.foo.Session.get(Session.java:42)",
+///     "2021-04-01T10:00:01 INFO request served",
+/// ];
+/// for line in lines.iter() {
+///     if let Some(event) = aggregator.push(line) {
+///         assert_eq!(event.lines().len(), 3);
+///         drain.add_event(&event);
+///     }
+/// }
+/// let event = aggregator.flush().unwrap();
+/// assert_eq!(event.first_line(), "2021-04-01T10:00:01 INFO request served");
+/// drain.add_event(&event);
+/// assert_eq!(drain.cluster_count(), 2);
+/// ```
+#[derive(Debug)]
+pub struct MultilineAggregator {
+    start_pattern_str: Option<String>,
+    start_pattern: Option<grok::Pattern>,
+    indentation: bool,
+    max_lines: usize,
+    timeout: Option<Duration>,
+    signature: bool,
+    pending: Vec<String>,
+    started: Option<Instant>,
+}
+
+impl Default for MultilineAggregator {
+    fn default() -> Self {
+        MultilineAggregator {
+            start_pattern_str: None,
+            start_pattern: None,
+            indentation: false,
+            max_lines: 500,
+            timeout: None,
+            signature: false,
+            pending: vec![],
+            started: None,
+        }
+    }
+}
+
+impl MultilineAggregator {
+    /// Create an aggregator where every line is an event until rules are configured
+    pub fn new() -> Self {
+        MultilineAggregator::default()
+    }
+
+    /// Grok pattern matching the lines that start an event, e.g. `^%{TIMESTAMP_ISO8601} `
+    ///
+    /// The pattern is matched anywhere in the line unless it is anchored.
+    /// `build_patterns` must be called to compile it.
+    pub fn start_pattern(mut self, start_pattern: &str) -> Self {
+        self.start_pattern_str = Some(String::from(start_pattern));
+        self.start_pattern = None;
+        self
+    }
+
+    /// Whether lines starting with whitespace continue the current event, along with the
+    /// unindented lines of stack traces such as `Caused by: ...` or `ValueError: ...`
+    pub fn indentation(mut self, indentation: bool) -> Self {
+        self.indentation = indentation;
+        self
+    }
+
+    /// Maximum number of lines of an event, 500 by default
+    pub fn max_lines(mut self, max_lines: usize) -> Self {
+        self.max_lines = max_lines.max(1);
+        self
+    }
+
+    /// How long an event waits for continuation lines, unlimited by default
+    ///
+    /// The timeout is checked as lines are pushed and by `poll`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Whether to extract the exception chain of the events, so that `DrainTree::add_event`
+    /// clusters it along with the first line
+    pub fn signature(mut self, signature: bool) -> Self {
+        self.signature = signature;
+        self
+    }
+
+    /// Build the start pattern supplied in `start_pattern`
+    ///
+    /// # Panics
+    /// If the pattern fails to compile, see `try_build_patterns` for a fallible version.
+    pub fn build_patterns(mut self, grok: &mut grok::Grok) -> Self {
+        if let Err(e) = self.try_build_patterns(grok) {
+            panic!("{}", e)
+        }
+        self
+    }
+
+    /// Build the start pattern supplied in `start_pattern`, the aggregator being left as it
+    /// was if it fails to compile
+    pub fn try_build_patterns(&mut self, grok: &mut grok::Grok) -> Result<(), Error> {
+        if let Some(pattern_str) = &self.start_pattern_str {
+            match grok.compile(pattern_str.as_str(), true) {
+                Ok(c) => self.start_pattern = Some(c),
+                Err(e) => {
+                    return Err(Error::InvalidPatterns(vec![PatternError {
+                        kind: PatternKind::StartPattern,
+                        pattern: pattern_str.clone(),
+                        reason: e.to_string(),
+                    }]))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_continuation(&self, line: &str) -> bool {
+        if self.indentation
+            && (line.starts_with(char::is_whitespace)
+                || exception_type(line).is_some()
+                || CONTINUATION_PREFIXES.iter().any(|p| line.starts_with(p)))
+        {
+            return true;
+        }
+        match &self.start_pattern {
+            Some(pattern) => pattern.match_against(line).is_none(),
+            None => false,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        match (self.timeout, self.started) {
+            (Some(timeout), Some(started)) => now.duration_since(started) >= timeout,
+            _ => false,
+        }
+    }
+
+    /// Add the next log line, returns the event it completes, if any
+    ///
+    /// An event is only known to be complete once the line starting the next one arrives,
+    /// so the last event is returned by `flush`.
+    ///
+    /// # Panics
+    /// If a start pattern is set and `build_patterns` was not called.
+    pub fn push(&mut self, line: &str) -> Option<Event> {
+        self.push_at(line, Instant::now())
+    }
+
+    /// Add the next log line, arrived at the given instant, see `push`
+    ///
+    /// The instants are those of the caller's clock, e.g. the time the lines were read at
+    /// when they are replayed.
+    /// ```
+    /// use drain_rs::MultilineAggregator;
+    /// use std::time::{Duration, Instant};
+    /// let mut aggregator = MultilineAggregator::new()
+    ///     .indentation(true)
+    ///     .timeout(Duration::from_secs(1));
+    /// let start = Instant::now();
+    /// aggregator.push_at("request failed", start);
+    /// let late = start + Duration::from_secs(2);
+    /// let event = aggregator.push_at("  at handler", late).unwrap();
+    /// assert_eq!(event.lines(), ["request failed"]);
+    /// ```
+    ///
+    /// # Panics
+    /// If a start pattern is set and `build_patterns` was not called.
+    pub fn push_at(&mut self, line: &str, now: Instant) -> Option<Event> {
+        assert!(
+            self.start_pattern_str.is_none() || self.start_pattern.is_some(),
+            "start pattern is not compiled, call [build_patterns] first"
+        );
+        let completed = if !self.pending.is_empty()
+            && (self.pending.len() >= self.max_lines
+                || self.is_expired(now)
+                || !self.is_continuation(line))
+        {
+            self.flush()
+        } else {
+            None
+        };
+        if self.pending.is_empty() {
+            self.started = Some(now);
+        }
+        self.pending.push(line.to_string());
+        if completed.is_none() && self.pending.len() >= self.max_lines {
+            return self.flush();
+        }
+        completed
+    }
+
+    /// Close the current event if its timeout elapsed, to be called while no line arrives
+    pub fn poll(&mut self) -> Option<Event> {
+        self.poll_at(Instant::now())
+    }
+
+    /// Close the current event if its timeout elapsed at the given instant, see `push_at`
+    pub fn poll_at(&mut self, now: Instant) -> Option<Event> {
+        if self.is_expired(now) {
+            self.flush()
+        } else {
+            None
+        }
+    }
+
+    /// Close the current event, if any, e.g. once the input ends
+    pub fn flush(&mut self) -> Option<Event> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        self.started = None;
+        let lines = std::mem::take(&mut self.pending);
+        let signature = if self.signature {
+            let types = lines
+                .iter()
+                .filter_map(|l| exception_type(l))
+                .collect::<Vec<&str>>();
+            if types.is_empty() {
+                None
+            } else {
+                Some(types.join(" "))
+            }
+        } else {
+            None
+        };
+        Some(Event { lines, signature })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DrainTree;
+
+    #[test]
+    fn indented_lines_continue_the_event() {
+        let mut aggregator = MultilineAggregator::new().indentation(true).max_lines(3);
+        assert_eq!(aggregator.push("first"), None);
+        assert_eq!(aggregator.push("  one"), None);
+        let event = aggregator.push("  two").unwrap();
+        assert_eq!(event.lines(), ["first", "  one", "  two"]);
+        assert_eq!(event.signature(), None);
+    }
+
+    #[test]
+    fn events_over_max_lines_are_split() {
+        let mut aggregator = MultilineAggregator::new().indentation(true).max_lines(3);
+        for line in ["first", "  one", "  two"] {
+            aggregator.push(line);
+        }
+        assert_eq!(aggregator.push("  three"), None);
+        assert_eq!(aggregator.flush().unwrap().lines(), ["  three"]);
+        assert_eq!(aggregator.flush(), None);
+    }
+
+    #[test]
+    fn idle_events_time_out() {
+        let mut aggregator = MultilineAggregator::new()
+            .indentation(true)
+            .timeout(Duration::from_millis(20));
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        aggregator.push_at("first", at(0));
+        assert_eq!(aggregator.poll_at(at(19)), None);
+        assert_eq!(
+            aggregator.push_at("  late", at(30)).unwrap().lines(),
+            ["first"]
+        );
+        assert_eq!(aggregator.poll_at(at(49)), None);
+        assert_eq!(aggregator.poll_at(at(50)).unwrap().lines(), ["  late"]);
+    }
+
+    #[test]
+    fn signatures_separate_the_exceptions() {
+        let mut aggregator = MultilineAggregator::new().indentation(true).signature(true);
+        let mut drain = DrainTree::new();
+        let traces = [
+            ("request 1 failed", "java.io.IOException: closed"),
+            ("request 2 failed", "java.io.IOException: reset"),
+            (
+                "request 3 failed",
+                "java.util.ConcurrentModificationException",
+            ),
+            ("request 4 failed", "KeyError: 'id'"),
+        ];
+        let mut ids = vec![];
+        for (first, exception) in traces.iter() {
+            aggregator.push(first);
+            aggregator.push(exception);
+            aggregator.push("\tat com.foo.Handler.run(Handler.java:12)");
+            let event = aggregator.flush().unwrap();
+            ids.push(drain.add_event(&event).unwrap().id());
+        }
+        assert_eq!(ids[0], ids[1]);
+        assert_ne!(ids[1], ids[2]);
+        assert_ne!(ids[2], ids[3]);
+        assert_eq!(
+            drain.template(drain.cluster_by_id(ids[0]).unwrap()),
+            "request <*> failed java.io.IOException"
+        );
+        assert_eq!(drain.cluster_count(), 3);
+    }
+
+    #[test]
+    fn invalid_start_patterns_are_reported() {
+        let invalid = MultilineAggregator::new()
+            .start_pattern("^(%{TIMESTAMP_ISO8601} ")
+            .try_build_patterns(&mut grok::Grok::with_patterns());
+        assert!(matches!(invalid, Err(Error::InvalidPatterns(_))));
+    }
+}
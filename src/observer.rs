@@ -0,0 +1,147 @@
+use crate::log_cluster::ClusterId;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Formatter;
+use std::sync::mpsc::{Sender, SyncSender};
+use std::sync::{Mutex, PoisonError};
+
+/// What happened to the template of a log cluster
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateEventKind {
+    /// A log line did not match any cluster, a new cluster is created from it
+    NewCluster,
+    /// A log line turned constant tokens of the template into wildcards
+    TemplateChanged,
+}
+
+/// A log cluster created or generalized by a log line, see `DrainTree::observer`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateEvent {
+    /// What happened
+    pub kind: TemplateEventKind,
+    /// The identifier of the cluster
+    pub cluster_id: ClusterId,
+    /// The template before the log line, `None` for a new cluster
+    pub old_template: Option<String>,
+    /// The template after the log line
+    pub new_template: String,
+    /// The log line, as given to the tree
+    pub line: String,
+    /// The values of the partition fields of the cluster
+    pub partition: BTreeMap<String, String>,
+}
+
+/// Receives the template events of a tree, as log lines are added
+///
+/// Closures taking a `&TemplateEvent` are observers, and so are the senders of a channel,
+/// which ignore the events once the receiver is dropped.
+pub trait TemplateObserver: Send {
+    /// Called with every event, before the log line insertion returns
+    fn on_event(&mut self, event: &TemplateEvent);
+}
+
+impl<F: FnMut(&TemplateEvent) + Send> TemplateObserver for F {
+    fn on_event(&mut self, event: &TemplateEvent) {
+        self(event)
+    }
+}
+
+impl TemplateObserver for Sender<TemplateEvent> {
+    fn on_event(&mut self, event: &TemplateEvent) {
+        let _ = self.send(event.clone());
+    }
+}
+
+impl TemplateObserver for SyncSender<TemplateEvent> {
+    fn on_event(&mut self, event: &TemplateEvent) {
+        let _ = self.send(event.clone());
+    }
+}
+
+/// The observer of a tree
+///
+/// The mutex keeps the tree `Sync` without requiring a `Sync` observer, it is never locked
+/// as the observer is only called through `&mut`.
+pub(crate) struct Observer(Mutex<Box<dyn TemplateObserver>>);
+
+impl Observer {
+    pub fn new(observer: Box<dyn TemplateObserver>) -> Self {
+        Observer(Mutex::new(observer))
+    }
+
+    pub fn on_event(&mut self, event: &TemplateEvent) {
+        self.0
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .on_event(event)
+    }
+}
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Observer")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DrainTree;
+    use std::sync::Arc;
+
+    // the events of the lines, partitioned by their level
+    fn events(lines: &[&str]) -> Vec<TemplateEvent> {
+        let events = Arc::new(Mutex::new(vec![]));
+        let sink = events.clone();
+        let mut drain = DrainTree::new()
+            .max_depth(2)
+            .log_pattern("%{LOGLEVEL:level} %{GREEDYDATA:content}", "content")
+            .partition_fields(vec!["level"])
+            .observer(move |e: &TemplateEvent| sink.lock().unwrap().push(e.clone()))
+            .build_patterns(&mut grok::Grok::with_patterns());
+        for line in lines {
+            drain.add_log_line(line);
+        }
+        let events = events.lock().unwrap().clone();
+        events
+    }
+
+    #[test]
+    fn new_clusters_are_reported() {
+        let events = events(&["INFO disk sda is full", "INFO disk sda went away"]);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, TemplateEventKind::NewCluster);
+        assert_eq!(events[0].old_template, None);
+        assert_eq!(events[0].new_template, "disk sda is full");
+        assert_eq!(events[0].line, "INFO disk sda is full");
+        assert_eq!(events[1].new_template, "disk sda went away");
+    }
+
+    #[test]
+    fn changed_templates_are_reported_once() {
+        let events = events(&[
+            "INFO disk sda is full",
+            "INFO disk sda is empty",
+            "INFO disk sda is busy",
+            "INFO disk sda is full",
+        ]);
+        let kinds = events.iter().map(|e| e.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                TemplateEventKind::NewCluster,
+                TemplateEventKind::TemplateChanged
+            ]
+        );
+        assert_eq!(events[1].cluster_id, events[0].cluster_id);
+        assert_eq!(events[1].old_template.as_deref(), Some("disk sda is full"));
+        assert_eq!(events[1].new_template, "disk sda is <*>");
+    }
+
+    #[test]
+    fn events_hold_the_partition() {
+        let events = events(&["INFO disk sda is full", "WARN disk sda is full"]);
+        assert_eq!(events[0].partition["level"], "INFO");
+        assert_eq!(events[1].partition["level"], "WARN");
+    }
+}
@@ -0,0 +1,42 @@
+use crate::log_cluster::LogCluster;
+use crate::token::Token;
+use std::collections::BTreeMap;
+
+/// The tokens of a log line, along with the text and filter pattern name each was built from
+#[derive(Debug)]
+pub(crate) struct ProcessedLine {
+    pub tokens: Vec<Token>,
+    pub values: Vec<String>,
+    pub names: Vec<Option<String>>,
+}
+
+impl ProcessedLine {
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+}
+
+/// A variable part of a log line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    /// Position of the token in the clustered message
+    pub position: usize,
+    /// The name of the filter pattern capture that matched this token, if any
+    pub name: Option<String>,
+    /// The original text of the token
+    pub value: String,
+}
+
+/// A log line matched against its cluster, see `DrainTree::parse`
+#[derive(Debug)]
+pub struct ParsedLine<'a> {
+    /// The matching cluster
+    pub cluster: &'a LogCluster,
+    /// The template of the cluster
+    pub template: String,
+    /// The values of the variable parts of the line, in order
+    pub variables: Vec<Variable>,
+    /// The other fields extracted by the overall log pattern, and the fields of a JSON log
+    /// line keyed by their JSON pointer
+    pub fields: BTreeMap<String, String>,
+}
@@ -0,0 +1,529 @@
+use crate::grok_generator;
+use crate::statistics::{VariableStats, VariableType};
+use crate::token::WildcardKind;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+
+/// Number of values sampled by every wildcard, by default
+pub(crate) const DEFAULT_SAMPLE_SIZE: usize = 10;
+
+// 2^8 registers, a standard error of about 6.5% on the distinct counts
+const HLL_PRECISION: u32 = 8;
+const HLL_REGISTERS: usize = 1 << HLL_PRECISION;
+
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_WIDTH: usize = 128;
+
+/// How the wildcards of the clusters choose the values they keep, see
+/// `DrainTree::wildcard_sampling`
+///
+/// The sampled values are what `GrokGenerator` detects the patterns of the wildcards from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SamplingStrategy {
+    /// Keep the first distinct values seen, biased towards the early logs of the cluster
+    FirstN,
+    /// Keep a uniform sample of the values of all the matched logs, with reservoir sampling
+    ///
+    /// A value fills as many slots of the reservoir as it is drawn, the samples being the
+    /// distinct values of the reservoir, so there can be fewer of them than the size.
+    Reservoir,
+    /// Keep the values seen the most often, as counted by a count-min sketch
+    MostFrequent,
+}
+
+/// What the wildcards of a tree keep about their values
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Sampling {
+    pub strategy: SamplingStrategy,
+    pub size: usize,
+    // whether the wildcards also keep typed statistics of their values
+    #[serde(default)]
+    pub statistics: bool,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Sampling {
+            strategy: SamplingStrategy::FirstN,
+            size: DEFAULT_SAMPLE_SIZE,
+            statistics: false,
+        }
+    }
+}
+
+/// A 64 bits hash of the value, stable across platforms and releases as it is serialized
+pub(crate) fn hash(value: &str, seed: u64) -> u64 {
+    // FNV-1a followed by the splitmix64 finalizer, to spread the bits of short strings
+    let mut hash = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for byte in value.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Estimates the number of distinct values added, in constant space
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct HyperLogLog {
+    // empty until the first value is added
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty()
+    }
+
+    pub fn add(&mut self, value: &str) {
+        if self.registers.is_empty() {
+            self.registers = vec![0; HLL_REGISTERS];
+        }
+        let hash = hash(value, 0);
+        let index = (hash >> (64 - HLL_PRECISION)) as usize;
+        let rank = ((hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1))).leading_zeros() + 1;
+        self.registers[index] = self.registers[index].max(rank as u8);
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        if self.registers.is_empty() {
+            self.registers = other.registers.clone();
+        } else if !other.registers.is_empty() {
+            for (register, other) in self.registers.iter_mut().zip(other.registers.iter()) {
+                *register = (*register).max(*other);
+            }
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        if self.registers.is_empty() {
+            return 0;
+        }
+        let m = self.registers.len() as f64;
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|&r| 2f64.powi(-i32::from(r)))
+            .sum();
+        let estimate = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            // linear counting is more accurate for small cardinalities
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    pub fn approximate_size(&self) -> usize {
+        self.registers.len()
+    }
+}
+
+/// Estimates how many times each value was added, never under the actual count
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct CountMinSketch {
+    counters: Vec<u32>,
+}
+
+impl Default for CountMinSketch {
+    fn default() -> Self {
+        CountMinSketch {
+            counters: vec![0; SKETCH_DEPTH * SKETCH_WIDTH],
+        }
+    }
+}
+
+impl CountMinSketch {
+    fn cells(value: &str) -> impl Iterator<Item = usize> + '_ {
+        (0..SKETCH_DEPTH).map(move |row| {
+            row * SKETCH_WIDTH + (hash(value, row as u64 + 1) % SKETCH_WIDTH as u64) as usize
+        })
+    }
+
+    pub fn add(&mut self, value: &str, count: u64) {
+        let count = count.min(u64::from(u32::MAX)) as u32;
+        for cell in CountMinSketch::cells(value) {
+            self.counters[cell] = self.counters[cell].saturating_add(count);
+        }
+    }
+
+    pub fn estimate(&self, value: &str) -> u64 {
+        CountMinSketch::cells(value)
+            .map(|cell| u64::from(self.counters[cell]))
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub fn merge(&mut self, other: &CountMinSketch) {
+        for (counter, other) in self.counters.iter_mut().zip(other.counters.iter()) {
+            *counter = counter.saturating_add(*other);
+        }
+    }
+
+    pub fn approximate_size(&self) -> usize {
+        self.counters.len() * std::mem::size_of::<u32>()
+    }
+}
+
+/// What a wildcard of a template knows about the values it replaced
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Wildcard {
+    #[serde(default, skip_serializing_if = "WildcardKind::is_any")]
+    pub kind: WildcardKind,
+    #[serde(default)]
+    pub samples: BTreeSet<String>,
+    // number of values seen, counting repeated values
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub seen: u64,
+    #[serde(default, skip_serializing_if = "HyperLogLog::is_empty")]
+    pub distinct: HyperLogLog,
+    // only kept by the reservoir sampling, the samples being its distinct values
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reservoir: Vec<String>,
+    // only kept by the most frequent sampling
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sketch: Option<CountMinSketch>,
+    // a lower bound of the estimated counts of the samples, for the most frequent sampling
+    #[serde(skip)]
+    pub floor: Option<u64>,
+    // only kept once variable statistics are enabled
+    #[serde(default, skip_serializing_if = "VariableStats::is_empty")]
+    pub stats: VariableStats,
+    // the type of the samples, inferred as they change, see `Wildcard::infer_type`
+    #[serde(skip)]
+    pub inferred: Option<VariableType>,
+}
+
+// the floor and the inferred type are caches, left out of the equality
+impl PartialEq for Wildcard {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && self.samples == other.samples
+            && self.seen == other.seen
+            && self.distinct == other.distinct
+            && self.reservoir == other.reservoir
+            && self.sketch == other.sketch
+            && self.stats == other.stats
+    }
+}
+
+// the statistics never hold NaN, which makes the equality total
+impl Eq for Wildcard {}
+
+impl Hash for Wildcard {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.kind.hash(state);
+        self.samples.hash(state);
+    }
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+impl Wildcard {
+    /// Whether nothing was ever seen by this wildcard
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+            && self.seen == 0
+            && self.distinct.is_empty()
+            && self.stats.is_empty()
+    }
+
+    /// Record `count` occurrences of the value
+    pub fn sample(&mut self, value: &str, count: u64, sampling: &Sampling) {
+        self.seen += count;
+        self.distinct.add(value);
+        if sampling.statistics {
+            self.stats.add(value, count);
+        }
+        match sampling.strategy {
+            SamplingStrategy::FirstN => {
+                if self.samples.len() < sampling.size {
+                    self.insert_sample(value);
+                }
+            }
+            SamplingStrategy::Reservoir => {
+                if self.reservoir.is_empty() {
+                    // the samples of the trees saved before the reservoir was kept
+                    self.reservoir.extend(self.samples.iter().cloned());
+                }
+                let mut count = count;
+                while count > 0 && self.reservoir.len() < sampling.size {
+                    self.reservoir.push(value.to_string());
+                    self.insert_sample(value);
+                    count -= 1;
+                }
+                if count == 0 {
+                    return;
+                }
+                // the value replaces the sample at a random index below seen, when there is one,
+                // the pseudo random number being derived from the value and the count
+                let draw = hash(value, self.seen) % self.seen;
+                if draw < sampling.size as u64 * count {
+                    let index = (draw % self.reservoir.len() as u64) as usize;
+                    let evicted = std::mem::replace(&mut self.reservoir[index], value.to_string());
+                    if !self.reservoir.contains(&evicted) {
+                        self.remove_sample(&evicted);
+                    }
+                    self.insert_sample(value);
+                }
+            }
+            SamplingStrategy::MostFrequent => {
+                let sketch = self.sketch.get_or_insert_with(CountMinSketch::default);
+                sketch.add(value, count);
+                if self.samples.contains(value) {
+                    return;
+                }
+                let estimate = sketch.estimate(value);
+                if self.samples.len() < sampling.size {
+                    self.insert_sample(value);
+                    self.floor = self.floor.map(|floor| floor.min(estimate));
+                    return;
+                }
+                // the estimates only grow, so the samples are only scanned once the value
+                // passes the floor
+                if self.floor.is_some_and(|floor| estimate <= floor) {
+                    return;
+                }
+                let least_frequent = self
+                    .samples
+                    .iter()
+                    .map(|s| (sketch.estimate(s), s))
+                    .min()
+                    .map(|(estimate, s)| (estimate, s.clone()));
+                if let Some((least, least_frequent)) = least_frequent {
+                    self.floor = Some(least);
+                    if estimate > least {
+                        self.remove_sample(&least_frequent);
+                        self.insert_sample(value);
+                        self.floor = None;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Combine the values seen by the other wildcard into this one
+    pub fn merge(&mut self, other: &Wildcard, sampling: &Sampling) {
+        match sampling.strategy {
+            SamplingStrategy::FirstN => {
+                for value in other.samples.iter() {
+                    if self.samples.len() >= sampling.size {
+                        break;
+                    }
+                    self.insert_sample(value);
+                }
+            }
+            SamplingStrategy::Reservoir => {
+                let own = if self.reservoir.is_empty() {
+                    self.samples.iter().cloned().collect()
+                } else {
+                    std::mem::take(&mut self.reservoir)
+                };
+                let other_reservoir = if other.reservoir.is_empty() {
+                    other.samples.iter().cloned().collect()
+                } else {
+                    other.reservoir.clone()
+                };
+                // each wildcard contributes in proportion to the number of values it saw
+                let total = (self.seen + other.seen).max(1);
+                let kept = (sampling.size as u64 * self.seen.max(1) / total) as usize;
+                let kept = kept.max(1).min(own.len());
+                let mut reservoir = own[..kept].to_vec();
+                for value in other_reservoir.iter().chain(own[kept..].iter()) {
+                    if reservoir.len() >= sampling.size {
+                        break;
+                    }
+                    reservoir.push(value.clone());
+                }
+                self.samples = reservoir.iter().cloned().collect();
+                self.reservoir = reservoir;
+            }
+            SamplingStrategy::MostFrequent => {
+                let sketch = self.sketch.get_or_insert_with(CountMinSketch::default);
+                if let Some(other) = other.sketch.as_ref() {
+                    sketch.merge(other);
+                }
+                let mut candidates = self
+                    .samples
+                    .iter()
+                    .chain(other.samples.iter())
+                    .map(|s| (sketch.estimate(s), s.clone()))
+                    .collect::<Vec<_>>();
+                candidates.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+                candidates.dedup_by(|a, b| a.1 == b.1);
+                self.samples = candidates
+                    .into_iter()
+                    .map(|(_, s)| s)
+                    .take(sampling.size)
+                    .collect();
+                self.floor = None;
+            }
+        }
+        self.seen += other.seen;
+        self.distinct.merge(&other.distinct);
+        self.stats.merge(&other.stats);
+        self.infer_type();
+    }
+
+    fn insert_sample(&mut self, value: &str) {
+        if self.samples.insert(value.to_string()) {
+            let inferred = grok_generator::variable_type(value);
+            self.inferred = Some(match self.inferred {
+                Some(current) => current.join(inferred),
+                None => inferred,
+            });
+        }
+    }
+
+    fn remove_sample(&mut self, value: &str) {
+        if self.samples.remove(value) {
+            self.infer_type();
+        }
+    }
+
+    /// Infer the type of the sampled values again, `None` without samples
+    ///
+    /// The type is kept up to date as the samples change, so that `TypedMatch` does not
+    /// match the samples of the templates against the grok patterns on every comparison.
+    pub fn infer_type(&mut self) {
+        self.inferred = self
+            .samples
+            .iter()
+            .map(|value| grok_generator::variable_type(value))
+            .reduce(VariableType::join);
+    }
+
+    /// The estimated number of distinct values, at least the number of sampled values
+    pub fn distinct_count(&self) -> u64 {
+        self.distinct.estimate().max(self.samples.len() as u64)
+    }
+
+    pub fn approximate_size(&self) -> usize {
+        self.samples
+            .iter()
+            .chain(self.reservoir.iter())
+            .map(|v| std::mem::size_of::<String>() + v.len())
+            .sum::<usize>()
+            + self.distinct.approximate_size()
+            + self
+                .sketch
+                .as_ref()
+                .map_or(0, CountMinSketch::approximate_size)
+            + self.stats.approximate_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DrainTree;
+
+    const SAMPLES: &str = r#"login of user <** ["bob", "eve"] **>"#;
+
+    fn drain() -> DrainTree {
+        let mut drain = DrainTree::new().max_depth(2);
+        drain.add_log_line("login of user bob");
+        drain.add_log_line("login of user eve");
+        drain
+    }
+
+    // the samples of 1000 users logging in once, then of 3 users logging in most of the time
+    fn samples(strategy: SamplingStrategy, size: usize) -> Vec<String> {
+        let mut drain = DrainTree::new()
+            .max_depth(2)
+            .wildcard_sampling(strategy, size);
+        for i in 0..1000 {
+            drain.add_log_line(&format!("login of user u{}", i));
+        }
+        for i in 0..2000 {
+            drain.add_log_line(&format!("login of user admin{}", i % 3));
+        }
+        let cluster = drain.log_group("login of user u0").unwrap();
+        cluster.tokens()[3].as_string_vector().unwrap()
+    }
+
+    #[test]
+    fn samples_survive_serialization() {
+        let drain = drain();
+        assert_eq!(drain.log_groups()[0].as_detailed_string(), SAMPLES);
+
+        let json = serde_json::to_string(&drain).unwrap();
+        let restored: DrainTree = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.log_groups()[0].as_detailed_string(), SAMPLES);
+    }
+
+    #[test]
+    fn samples_survive_snapshots() {
+        let drain = drain();
+        let mut snapshot = vec![];
+        drain.save_to(&mut snapshot).unwrap();
+
+        let restored = DrainTree::load_from(snapshot.as_slice()).unwrap();
+        let cluster = restored.log_group("login of user joe").unwrap();
+        assert_eq!(cluster.as_detailed_string(), SAMPLES);
+    }
+
+    #[test]
+    fn sampling_state_survives_serialization() {
+        let mut drain = DrainTree::new()
+            .max_depth(2)
+            .wildcard_sampling(SamplingStrategy::MostFrequent, 3);
+        for i in 0..100 {
+            drain.add_log_line(&format!("login of user u{}", i % 7));
+        }
+        let cluster = drain.log_groups()[0];
+
+        let json = serde_json::to_string(&drain).unwrap();
+        let restored: DrainTree = serde_json::from_str(&json).unwrap();
+        let restored = restored.cluster_by_id(cluster.id()).unwrap();
+        assert_eq!(restored.as_detailed_string(), cluster.as_detailed_string());
+        assert_eq!(restored.distinct_counts(), cluster.distinct_counts());
+    }
+
+    #[test]
+    fn distinct_values_are_estimated() {
+        let mut drain = DrainTree::new().max_depth(2);
+        for i in 0..1000 {
+            drain.add_log_line(&format!("login of user u{}", i));
+        }
+        let distinct = drain.log_groups()[0].distinct_counts()[0].1;
+        assert!((900..=1100).contains(&distinct), "{}", distinct);
+    }
+
+    #[test]
+    fn first_n_keeps_the_first_values() {
+        assert_eq!(
+            samples(SamplingStrategy::FirstN, 3),
+            vec!["u0".to_string(), "u1".to_string(), "u2".to_string()]
+        );
+        assert_eq!(samples(SamplingStrategy::FirstN, 10).len(), 10);
+    }
+
+    #[test]
+    fn reservoirs_keep_values_of_the_whole_stream() {
+        // the heavy hitters fill several slots of the reservoir, and are sampled once
+        let reservoir = samples(SamplingStrategy::Reservoir, 20);
+        assert!(reservoir.len() < 20);
+        assert!(reservoir.iter().any(|s| s.starts_with("admin")));
+        assert!(reservoir.iter().any(|s| s.starts_with('u')));
+    }
+
+    #[test]
+    fn most_frequent_keeps_the_heavy_hitters() {
+        assert_eq!(
+            samples(SamplingStrategy::MostFrequent, 3),
+            vec![
+                "admin0".to_string(),
+                "admin1".to_string(),
+                "admin2".to_string()
+            ]
+        );
+    }
+}
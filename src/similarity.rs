@@ -0,0 +1,349 @@
+use crate::grok_generator;
+use crate::log_cluster::GroupSimilarity;
+use crate::statistics::VariableType;
+use crate::token::Token;
+use std::fmt;
+
+/// Scores how similar a log line is to the template of a cluster, the log line joining the
+/// most similar cluster of its leaf when the exact similarity reaches `min_similarity`
+///
+/// Both slices have the same length, as the clusters of a leaf all have the length of the
+/// log line.
+pub trait Similarity: fmt::Debug + Send + Sync {
+    /// Compare the tokens of the log line to the tokens of the template
+    fn similarity(&self, template: &[TokenRef<'_>], log: &[TokenRef<'_>]) -> GroupSimilarity;
+}
+
+pub(crate) fn default_similarity() -> Box<dyn Similarity> {
+    Box::new(SeqDist)
+}
+
+/// A token of a template or of a log line, as seen by a `Similarity`
+///
+/// The tokens of a log line are constants, unless a filter pattern without a named capture
+/// turned them into wildcards.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenRef<'a>(&'a Token);
+
+impl<'a> TokenRef<'a> {
+    pub(crate) fn all(tokens: &'a [Token]) -> impl Iterator<Item = TokenRef<'a>> {
+        tokens.iter().map(TokenRef)
+    }
+
+    /// The value of a constant token, `None` for a wildcard
+    pub fn constant(&self) -> Option<&'a str> {
+        match self.0 {
+            Token::Val(value) => Some(value),
+            Token::WildCard(_) => None,
+        }
+    }
+
+    /// Whether the token is a wildcard
+    pub fn is_wildcard(&self) -> bool {
+        self.0.is_wildcard()
+    }
+
+    /// Whether both tokens are the same constant, or both are wildcards of the same kind,
+    /// whatever values they sampled
+    pub fn matches(&self, other: &TokenRef<'_>) -> bool {
+        self.0.matches(other.0)
+    }
+
+    /// The values sampled by a wildcard, nothing for a constant
+    pub fn samples(&self) -> impl Iterator<Item = &'a str> {
+        let samples = match self.0 {
+            Token::WildCard(wildcard) => Some(wildcard.samples.iter().map(String::as_str)),
+            Token::Val(_) => None,
+        };
+        samples.into_iter().flatten()
+    }
+
+    /// The type of a constant, or of the values of a wildcard, `None` for a wildcard that
+    /// never saw a value
+    ///
+    /// The type of a wildcard comes from its statistics when `DrainTree::variable_statistics`
+    /// is enabled, it is inferred from its samples otherwise.
+    pub fn variable_type(&self) -> Option<VariableType> {
+        match self.0 {
+            Token::Val(value) => Some(grok_generator::variable_type(value)),
+            Token::WildCard(wildcard) => wildcard.stats.variable_type().or(wildcard.inferred),
+        }
+    }
+}
+
+// number of template wildcards facing a differing token, the tie breaker of every built-in
+fn approximate_similarity(template: &[TokenRef<'_>], log: &[TokenRef<'_>]) -> u32 {
+    template
+        .iter()
+        .zip(log.iter())
+        .filter(|(pattern, token)| pattern.is_wildcard() && !pattern.matches(token))
+        .count() as u32
+}
+
+/// The similarity of the original drain algorithm, the fraction of tokens equal to the
+/// template, this is the default similarity
+///
+/// Template wildcards only match wildcards, ties are broken by the number of template
+/// wildcards facing the other tokens.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SeqDist;
+
+impl Similarity for SeqDist {
+    fn similarity(&self, template: &[TokenRef<'_>], log: &[TokenRef<'_>]) -> GroupSimilarity {
+        let exact = template
+            .iter()
+            .zip(log.iter())
+            .filter(|(pattern, token)| pattern.matches(token))
+            .count();
+        GroupSimilarity {
+            approximate_similarity: approximate_similarity(template, log),
+            exact_similarity: exact as f32 / template.len() as f32,
+        }
+    }
+}
+
+/// Weighs the constants of the template more than its wildcards, which match any token
+///
+/// Every wildcard counts for `wildcard_weight` of a constant, both in the matched weight and
+/// in the total weight, so a weight of 0 only compares the constants of the template.
+/// ```
+/// use drain_rs::{DrainTree, Weighted};
+/// let mut drain = DrainTree::new()
+///     .max_depth(1)
+///     .min_similarity(0.7)
+///     .similarity(Weighted::new(0.0));
+/// drain.add_log_line("user login from web as bob ok");
+/// let id = drain.add_log_line_id("user login from app as eve ok").unwrap();
+/// let cluster = drain.cluster_by_id(id).unwrap();
+/// assert_eq!(cluster.as_string(), "user login from <*> as <*> ok");
+/// // 4 of the 5 constants match, where `SeqDist` only sees 4 of 7 tokens
+/// assert_eq!(drain.add_log_line_id("user login to cli as joe ok"), Some(id));
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Weighted {
+    wildcard_weight: f32,
+}
+
+impl Weighted {
+    /// Count every wildcard of the template for `wildcard_weight` of a constant, from 0 to 1
+    pub fn new(wildcard_weight: f32) -> Self {
+        Weighted {
+            wildcard_weight: wildcard_weight.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl Default for Weighted {
+    /// Wildcards count for half a constant
+    fn default() -> Self {
+        Weighted::new(0.5)
+    }
+}
+
+impl Similarity for Weighted {
+    fn similarity(&self, template: &[TokenRef<'_>], log: &[TokenRef<'_>]) -> GroupSimilarity {
+        let (mut matched, mut total) = (0.0, 0.0);
+        for (pattern, token) in template.iter().zip(log.iter()) {
+            if pattern.is_wildcard() {
+                matched += self.wildcard_weight;
+                total += self.wildcard_weight;
+            } else {
+                matched += if pattern.matches(token) { 1.0 } else { 0.0 };
+                total += 1.0;
+            }
+        }
+        GroupSimilarity {
+            approximate_similarity: approximate_similarity(template, log),
+            exact_similarity: if total > 0.0 { matched / total } else { 1.0 },
+        }
+    }
+}
+
+/// The length of the longest common subsequence of the tokens over the length of the
+/// template, which tolerates tokens shifted by a token inserted or removed earlier in the line
+/// ```
+/// use drain_rs::{DrainTree, Lcs};
+/// let mut drain = DrainTree::new()
+///     .max_depth(0)
+///     .min_similarity(0.7)
+///     .similarity(Lcs);
+/// let id = drain.add_log_line_id("task done in 12 ms").unwrap();
+/// // 4 of the 5 tokens are in the same order, shifted by one
+/// assert_eq!(drain.add_log_line_id("task #3 done in 12"), Some(id));
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lcs;
+
+impl Similarity for Lcs {
+    fn similarity(&self, template: &[TokenRef<'_>], log: &[TokenRef<'_>]) -> GroupSimilarity {
+        // lengths of the common subsequences of the template and of the log prefixes
+        let mut previous = vec![0usize; log.len() + 1];
+        let mut current = vec![0usize; log.len() + 1];
+        for pattern in template.iter() {
+            for (j, token) in log.iter().enumerate() {
+                current[j + 1] = if pattern.matches(token) {
+                    previous[j] + 1
+                } else {
+                    current[j].max(previous[j + 1])
+                };
+            }
+            std::mem::swap(&mut previous, &mut current);
+        }
+        GroupSimilarity {
+            approximate_similarity: approximate_similarity(template, log),
+            exact_similarity: previous[log.len()] as f32 / template.len() as f32,
+        }
+    }
+}
+
+/// Like `SeqDist`, a template wildcard also matching the constants of its type, e.g. a number
+/// matching a wildcard of numbers
+///
+/// Wildcards whose values have several types, or no known type, only match wildcards.
+/// The types of the wildcards are inferred from their samples unless
+/// `DrainTree::variable_statistics` is enabled, which is much faster.
+/// ```
+/// use drain_rs::{DrainTree, TypedMatch};
+/// let mut drain = DrainTree::new()
+///     .max_depth(2)
+///     .min_similarity(0.8)
+///     .similarity(TypedMatch)
+///     .variable_statistics();
+/// drain.add_log_line("request of user bob took 12 ms");
+/// let id = drain.add_log_line_id("request of user bob took 7 ms").unwrap();
+/// assert_eq!(drain.add_log_line_id("request of user eve took 30 ms"), Some(id));
+/// // a word cannot replace the numeric wildcard
+/// assert_ne!(drain.add_log_line_id("request of user eve took long ms"), Some(id));
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TypedMatch;
+
+impl Similarity for TypedMatch {
+    fn similarity(&self, template: &[TokenRef<'_>], log: &[TokenRef<'_>]) -> GroupSimilarity {
+        let exact = template
+            .iter()
+            .zip(log.iter())
+            .filter(|(pattern, token)| {
+                pattern.matches(token)
+                    || (pattern.is_wildcard()
+                        && !token.is_wildcard()
+                        && match (pattern.variable_type(), token.variable_type()) {
+                            (Some(VariableType::Text), _) | (None, _) => false,
+                            (Some(expected), Some(actual)) => expected.join(actual) == expected,
+                            (Some(_), None) => false,
+                        })
+            })
+            .count();
+        GroupSimilarity {
+            approximate_similarity: approximate_similarity(template, log),
+            exact_similarity: exact as f32 / template.len() as f32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log_cluster::LogCluster;
+    use crate::sampling::Sampling;
+    use crate::DrainTree;
+    use float_cmp::approx_eq;
+
+    fn tokens(strs: &[&str]) -> Vec<Token> {
+        strs.iter().map(|s| Token::Val(s.to_string())).collect()
+    }
+
+    // `took <*> ms on <*>`, the first wildcard holding numbers
+    fn cluster() -> LogCluster {
+        let mut cluster = LogCluster::new(0, tokens(&["took", "12", "ms", "on", "a"]));
+        cluster.add_log(
+            &tokens(&["took", "7.5", "ms", "on", "b"]),
+            &Sampling::default(),
+        );
+        cluster
+    }
+
+    fn similarity(log: &[&str], similarity: &dyn Similarity) -> f32 {
+        cluster()
+            .similarity(&tokens(log), similarity)
+            .exact_similarity
+    }
+
+    const LOG: [&str; 5] = ["took", "3", "ms", "on", "c"];
+    const SECONDS: [&str; 5] = ["took", "3", "s", "on", "c"];
+    const SHIFTED: [&str; 5] = ["took", "ms", "on", "a", "b"];
+
+    #[test]
+    fn seq_dist_only_counts_the_constants() {
+        assert!(approx_eq!(f32, similarity(&LOG, &SeqDist), 0.6));
+        assert!(approx_eq!(f32, similarity(&SHIFTED, &SeqDist), 0.2));
+    }
+
+    #[test]
+    fn seq_dist_ignores_the_samples_of_the_wildcards() {
+        let mut log = tokens(&LOG);
+        for position in [1, 4] {
+            let mut wildcard = Token::new_empty_wildcard();
+            wildcard.sample("other", 1, &Sampling::default());
+            log[position] = wildcard;
+        }
+        let similarity = cluster().similarity(&log, &SeqDist);
+        assert!(approx_eq!(f32, similarity.exact_similarity, 1.0));
+        assert_eq!(similarity.approximate_similarity, 0);
+    }
+
+    #[test]
+    fn weighted_counts_the_wildcards() {
+        assert!(approx_eq!(f32, similarity(&LOG, &Weighted::new(0.0)), 1.0));
+        assert!(approx_eq!(
+            f32,
+            similarity(&SECONDS, &Weighted::new(0.0)),
+            2.0 / 3.0
+        ));
+        assert!(approx_eq!(
+            f32,
+            similarity(&SECONDS, &Weighted::default()),
+            0.75
+        ));
+    }
+
+    #[test]
+    fn typed_match_checks_the_types_of_the_wildcards() {
+        // the integer fits the decimal wildcard, not the other one
+        assert!(approx_eq!(f32, similarity(&LOG, &TypedMatch), 0.8));
+        assert!(approx_eq!(
+            f32,
+            similarity(&["took", "slow", "ms", "on", "c"], &TypedMatch),
+            0.6
+        ));
+    }
+
+    #[test]
+    fn typed_match_infers_the_types_of_deserialized_wildcards() {
+        let json = serde_json::to_string(&cluster()).unwrap();
+        let restored: LogCluster = serde_json::from_str(&json).unwrap();
+        let similarity = restored.similarity(&tokens(&LOG), &TypedMatch);
+        assert!(approx_eq!(f32, similarity.exact_similarity, 0.8));
+    }
+
+    #[test]
+    fn lcs_allows_shifted_tokens() {
+        assert!(approx_eq!(f32, similarity(&SHIFTED, &Lcs), 0.6));
+    }
+
+    #[test]
+    fn the_configured_similarity_is_explained() {
+        let mut drain = DrainTree::new()
+            .max_depth(0)
+            .min_similarity(0.7)
+            .similarity(Lcs);
+        let id = drain.add_log_line_id("task done in 12 ms").unwrap();
+        let explanation = drain.explain("task #3 done in 12").unwrap();
+        assert!(approx_eq!(
+            f32,
+            explanation.candidates[0].similarity.exact_similarity,
+            0.8
+        ));
+        assert_eq!(explanation.decision, crate::Decision::Matched(id));
+    }
+}
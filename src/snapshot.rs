@@ -0,0 +1,228 @@
+use crate::error::Error;
+use crate::DrainTree;
+use std::io::{Read, Write};
+
+// "DRAINRS" followed by a format byte, reserved to tell binary snapshots from other files
+const MAGIC: &[u8; 8] = b"DRAINRS\x01";
+
+/// Version of the snapshots written by `save`
+///
+/// Version 0 is the JSON serialization of the tree, which has no header.
+/// Version 1 is a MessagePack serialization, with named fields so that fields added with a
+/// default value do not need a new version.
+/// Version 2 keeps the values sampled by the wildcards, which version 1 stored as bare strings.
+pub(crate) const VERSION: u16 = 2;
+
+// magic, version, payload length and payload checksum
+const HEADER_LEN: usize = 8 + 2 + 8 + 4;
+
+/// Write the tree as a versioned binary snapshot
+///
+/// The header holds the magic bytes, the version and the length and CRC-32 of the payload,
+/// all integers in little endian.
+pub(crate) fn save<W: Write>(tree: &DrainTree, mut writer: W) -> Result<(), Error> {
+    let payload = rmp_serde::to_vec_named(tree)
+        .map_err(|e| Error::InvalidSnapshot(format!("cannot encode the tree: {}", e)))?;
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    header.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    writer.write_all(&header).map_err(Error::Io)?;
+    writer.write_all(&payload).map_err(Error::Io)?;
+    writer.flush().map_err(Error::Io)
+}
+
+/// Read a snapshot of any known version, older versions being migrated to the current tree
+pub(crate) fn load<R: Read>(mut reader: R) -> Result<DrainTree, Error> {
+    let mut data = vec![];
+    reader.read_to_end(&mut data).map_err(Error::Io)?;
+    if !data.starts_with(MAGIC) {
+        return if data.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{') {
+            migrate_v0(&data)
+        } else {
+            Err(Error::InvalidSnapshot("unknown format".to_string()))
+        };
+    }
+    if data.len() < HEADER_LEN {
+        return Err(Error::InvalidSnapshot("truncated header".to_string()));
+    }
+    let version = u16::from_le_bytes([data[8], data[9]]);
+    let mut length = [0; 8];
+    length.copy_from_slice(&data[10..18]);
+    let mut checksum = [0; 4];
+    checksum.copy_from_slice(&data[18..22]);
+    let payload = &data[HEADER_LEN..];
+    if payload.len() as u64 != u64::from_le_bytes(length) {
+        return Err(Error::InvalidSnapshot(format!(
+            "expected {} bytes of payload, found {}",
+            u64::from_le_bytes(length),
+            payload.len()
+        )));
+    }
+    if crc32fast::hash(payload) != u32::from_le_bytes(checksum) {
+        return Err(Error::InvalidSnapshot("checksum mismatch".to_string()));
+    }
+    match version {
+        // tokens read both the bare strings of version 1 and the wildcards of version 2
+        1 | 2 => rmp_serde::from_slice(payload)
+            .map_err(|e| Error::InvalidSnapshot(format!("cannot decode the tree: {}", e))),
+        version => Err(Error::UnsupportedSnapshotVersion(version)),
+    }
+}
+
+/// The JSON serialization of the tree, as written before versioned snapshots existed
+///
+/// The oldest trees have no cluster identifiers, deserializing them gives every cluster an
+/// identifier and sets the next one past the largest.
+fn migrate_v0(data: &[u8]) -> Result<DrainTree, Error> {
+    serde_json::from_slice(data)
+        .map_err(|e| Error::InvalidSnapshot(format!("cannot decode the JSON tree: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // saved by the first release, before clusters had identifiers
+    const BASELINE_TREE: &[u8] = include_bytes!("../tests/fixtures/baseline_tree.json");
+
+    const API_LINE: &str = r#"{"service":"api","msg":"login of user joe"}"#;
+
+    fn drain() -> DrainTree {
+        let mut drain = DrainTree::new()
+            .max_depth(2)
+            .json_message("/msg")
+            .partition_fields(vec!["/service"])
+            .timestamp_field("/time", "rfc3339");
+        drain.add_log_line(
+            r#"{"service":"api","time":"2021-04-01T10:00:00Z","msg":"login of user bob"}"#,
+        );
+        drain.add_log_line(r#"{"service":"api","msg":"login of user eve","host":"a"}"#);
+        drain.add_log_line(r#"{"service":"db","msg":"login of user bob"}"#);
+        drain.add_log_line("disk sda is full");
+        drain
+    }
+
+    fn snapshot(drain: &DrainTree) -> Vec<u8> {
+        let mut snapshot = vec![];
+        save(drain, &mut snapshot).unwrap();
+        snapshot
+    }
+
+    #[test]
+    fn snapshots_restore_the_tree() {
+        let drain = drain();
+        let snapshot = snapshot(&drain);
+        assert!(snapshot.starts_with(b"DRAINRS"));
+
+        let restored = load(snapshot.as_slice()).unwrap();
+        let cluster = restored.log_group(API_LINE).unwrap();
+        let original = drain.log_group(API_LINE).unwrap();
+        assert_eq!(cluster.id(), original.id());
+        assert_eq!(cluster.as_string(), "login of user <*>");
+        assert_eq!(cluster.first_seen(), original.first_seen());
+        assert!(cluster.metadata()["/host"].contains("a"));
+        assert_eq!(restored.cluster_count(), 3);
+        assert_eq!(restored.next_cluster_id, drain.next_cluster_id);
+    }
+
+    #[test]
+    fn snapshots_are_smaller_than_json() {
+        let drain = drain();
+        let json = serde_json::to_string(&drain).unwrap();
+        assert!(snapshot(&drain).len() < json.len());
+    }
+
+    #[test]
+    fn json_trees_are_migrated() {
+        let drain = drain();
+        let json = serde_json::to_string(&drain).unwrap();
+        let restored = load(json.as_bytes()).unwrap();
+        assert_eq!(
+            restored.log_group(API_LINE).unwrap().id(),
+            drain.log_group(API_LINE).unwrap().id()
+        );
+    }
+
+    #[test]
+    fn corrupted_snapshots_are_rejected() {
+        let mut corrupted = snapshot(&drain());
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            load(corrupted.as_slice()).unwrap_err(),
+            Error::InvalidSnapshot(reason) if reason == "checksum mismatch"
+        ));
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let mut newer = snapshot(&drain());
+        newer[8] = 9;
+        assert!(matches!(
+            load(newer.as_slice()).unwrap_err(),
+            Error::UnsupportedSnapshotVersion(9)
+        ));
+    }
+
+    #[test]
+    fn truncated_snapshots_are_rejected() {
+        let snapshot = snapshot(&drain());
+        assert!(matches!(
+            load(&snapshot[..snapshot.len() - 1]),
+            Err(Error::InvalidSnapshot(_))
+        ));
+        assert!(matches!(
+            load(&b"not a snapshot"[..]),
+            Err(Error::InvalidSnapshot(_))
+        ));
+    }
+
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn io_errors_are_the_source() {
+        let error = save(&drain(), FullDisk).unwrap_err();
+        assert!(matches!(&error, Error::Io(e) if e.to_string() == "disk full"));
+        let source = std::error::Error::source(&error).unwrap();
+        assert_eq!(source.to_string(), "disk full");
+    }
+
+    #[test]
+    fn baseline_json_is_migrated() {
+        let mut tree = load(BASELINE_TREE)
+            .unwrap()
+            .build_patterns(&mut grok::Grok::with_patterns());
+        let mut ids = tree.log_groups().iter().map(|c| c.id()).collect::<Vec<_>>();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![0, 1, 2, 3]);
+        assert_eq!(tree.next_cluster_id, 4);
+
+        let disk = tree.log_group("disk full").unwrap().id();
+        assert_eq!(tree.add_log_line_id("disk full"), Some(disk));
+        assert_eq!(tree.add_log_line_id("cpu is hot"), Some(4));
+        let user = tree
+            .log_groups()
+            .into_iter()
+            .find(|c| c.as_string() == "user <*> called <ip_address>")
+            .unwrap();
+        assert_eq!(user.num_matched(), 2);
+
+        // the numbers followed the `<*>` branch before they had a branch of their own
+        let job = tree.log_group("job 7 done in 30 ms").unwrap();
+        assert_eq!(job.as_string(), "job <*> done in <*> ms");
+        let job = job.id();
+        assert_eq!(tree.add_log_line_id("job 7 done in 30 ms"), Some(job));
+        assert_eq!(tree.cluster_count(), 5);
+    }
+}
@@ -0,0 +1,404 @@
+use crate::grok_generator;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// relative accuracy of the percentiles
+const RELATIVE_ACCURACY: f64 = 0.02;
+// buckets kept by each side of the quantile sketch, the closest to zero are collapsed
+const MAX_BUCKETS: usize = 512;
+// values closer to zero are counted as zero
+const MIN_MAGNITUDE: f64 = 1e-9;
+
+/// The type inferred for the values of a wildcard, from the GROK base patterns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum VariableType {
+    /// Integers, `INT`
+    Int,
+    /// Decimal numbers, `NUMBER`, including integers
+    Float,
+    /// Hexadecimal numbers with at least one digit, `BASE16NUM`, including integers
+    Hex,
+    /// IP addresses, `IP`
+    Ip,
+    /// UUIDs, `UUID`
+    Uuid,
+    /// Unix or Windows paths, `PATH`
+    Path,
+    /// Dates and times, e.g. `TIMESTAMP_ISO8601`, `SYSLOGTIMESTAMP`, `DATE`
+    Timestamp,
+    /// Anything else, or values of several types
+    Text,
+}
+
+impl VariableType {
+    /// The narrowest type covering the values of both types
+    pub(crate) fn join(self, other: VariableType) -> VariableType {
+        use VariableType::*;
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Int, Float) | (Float, Int) => Float,
+            (Int, Hex) | (Hex, Int) => Hex,
+            _ => Text,
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(self, VariableType::Int | VariableType::Float)
+    }
+}
+
+/// Estimates the percentiles of numbers within a relative error, with logarithmic buckets
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct QuantileSketch {
+    #[serde(default)]
+    positive: BTreeMap<i32, u64>,
+    #[serde(default)]
+    negative: BTreeMap<i32, u64>,
+    #[serde(default)]
+    zero: u64,
+}
+
+fn gamma() -> f64 {
+    (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY)
+}
+
+impl QuantileSketch {
+    fn index(magnitude: f64) -> i32 {
+        (magnitude.ln() / gamma().ln()).ceil() as i32
+    }
+
+    fn value(index: i32) -> f64 {
+        2.0 * gamma().powi(index) / (gamma() + 1.0)
+    }
+
+    fn add(&mut self, value: f64, count: u64) {
+        let buckets = if value > MIN_MAGNITUDE {
+            &mut self.positive
+        } else if value < -MIN_MAGNITUDE {
+            &mut self.negative
+        } else {
+            self.zero += count;
+            return;
+        };
+        *buckets
+            .entry(QuantileSketch::index(value.abs()))
+            .or_insert(0) += count;
+        QuantileSketch::collapse(buckets);
+    }
+
+    fn collapse(buckets: &mut BTreeMap<i32, u64>) {
+        while buckets.len() > MAX_BUCKETS {
+            if let Some((_, count)) = buckets.pop_first() {
+                if let Some(mut lowest) = buckets.first_entry() {
+                    *lowest.get_mut() += count;
+                }
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &QuantileSketch) {
+        for (index, count) in other.positive.iter() {
+            *self.positive.entry(*index).or_insert(0) += count;
+        }
+        for (index, count) in other.negative.iter() {
+            *self.negative.entry(*index).or_insert(0) += count;
+        }
+        self.zero += other.zero;
+        QuantileSketch::collapse(&mut self.positive);
+        QuantileSketch::collapse(&mut self.negative);
+    }
+
+    /// The value of the given rank, from 0 to the number of values minus one
+    fn value_at(&self, rank: u64) -> Option<f64> {
+        let mut seen = 0;
+        for (index, count) in self.negative.iter().rev() {
+            seen += count;
+            if rank < seen {
+                return Some(-QuantileSketch::value(*index));
+            }
+        }
+        seen += self.zero;
+        if rank < seen {
+            return Some(0.0);
+        }
+        for (index, count) in self.positive.iter() {
+            seen += count;
+            if rank < seen {
+                return Some(QuantileSketch::value(*index));
+            }
+        }
+        None
+    }
+}
+
+/// Running statistics of the values of a numeric wildcard
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct NumericStats {
+    count: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    #[serde(default)]
+    sketch: QuantileSketch,
+}
+
+/// Running statistics of the values replaced by a wildcard of a template,
+/// see `LogCluster::variable_stats`
+///
+/// The numeric statistics only cover the values that are numbers, whatever the inferred type.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VariableStats {
+    #[serde(default)]
+    variable_type: Option<VariableType>,
+    #[serde(default)]
+    count: u64,
+    // boxed to keep small the wildcards that never see a number
+    #[serde(default)]
+    numeric: Option<Box<NumericStats>>,
+}
+
+impl VariableStats {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Record `count` occurrences of the value
+    pub(crate) fn add(&mut self, value: &str, count: u64) {
+        let variable_type = grok_generator::variable_type(value);
+        self.variable_type = Some(match self.variable_type {
+            Some(current) => current.join(variable_type),
+            None => variable_type,
+        });
+        self.count += count;
+        if !variable_type.is_numeric() {
+            return;
+        }
+        let number = match value.parse::<f64>() {
+            Ok(number) if number.is_finite() => number,
+            _ => return,
+        };
+        let numeric = self.numeric.get_or_insert_with(|| {
+            Box::new(NumericStats {
+                count: 0,
+                min: number,
+                max: number,
+                sum: 0.0,
+                sketch: QuantileSketch::default(),
+            })
+        });
+        numeric.count += count;
+        numeric.min = numeric.min.min(number);
+        numeric.max = numeric.max.max(number);
+        numeric.sum += number * count as f64;
+        numeric.sketch.add(number, count);
+    }
+
+    pub(crate) fn merge(&mut self, other: &VariableStats) {
+        self.variable_type = match (self.variable_type, other.variable_type) {
+            (Some(own), Some(other)) => Some(own.join(other)),
+            (own, other) => own.or(other),
+        };
+        self.count += other.count;
+        match (self.numeric.as_mut(), other.numeric.as_ref()) {
+            (Some(own), Some(other)) => {
+                own.count += other.count;
+                own.min = own.min.min(other.min);
+                own.max = own.max.max(other.max);
+                own.sum += other.sum;
+                own.sketch.merge(&other.sketch);
+            }
+            (None, Some(other)) => self.numeric = Some(other.clone()),
+            _ => {}
+        }
+    }
+
+    pub(crate) fn approximate_size(&self) -> usize {
+        self.numeric.as_ref().map_or(0, |numeric| {
+            std::mem::size_of::<NumericStats>()
+                + (numeric.sketch.positive.len() + numeric.sketch.negative.len())
+                    * (std::mem::size_of::<i32>() + std::mem::size_of::<u64>())
+        })
+    }
+
+    /// The type of every value, `None` when no value was recorded
+    pub fn variable_type(&self) -> Option<VariableType> {
+        self.variable_type
+    }
+
+    /// Number of values recorded, counting repeated values
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Number of values that are numbers
+    pub fn numeric_count(&self) -> u64 {
+        self.numeric.as_ref().map_or(0, |numeric| numeric.count)
+    }
+
+    /// The smallest number
+    pub fn min(&self) -> Option<f64> {
+        self.numeric.as_ref().map(|numeric| numeric.min)
+    }
+
+    /// The largest number
+    pub fn max(&self) -> Option<f64> {
+        self.numeric.as_ref().map(|numeric| numeric.max)
+    }
+
+    /// The mean of the numbers
+    pub fn mean(&self) -> Option<f64> {
+        self.numeric
+            .as_ref()
+            .map(|numeric| numeric.sum / numeric.count as f64)
+    }
+
+    /// The number below which `percentile` percent of the numbers are, e.g. 50.0 for the
+    /// median, within 2% of the actual value
+    pub fn percentile(&self, percentile: f64) -> Option<f64> {
+        let numeric = self.numeric.as_ref()?;
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * (numeric.count - 1) as f64) as u64;
+        if rank == 0 {
+            return Some(numeric.min);
+        }
+        if rank == numeric.count - 1 {
+            return Some(numeric.max);
+        }
+        let value = numeric.sketch.value_at(rank)?;
+        Some(value.clamp(numeric.min, numeric.max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClusterId, DrainTree};
+
+    // the type of the values of a single variable
+    fn variable_type(values: &[&str]) -> Option<VariableType> {
+        let mut drain = DrainTree::new().max_depth(2).variable_statistics();
+        for value in values {
+            drain.add_log_line(&format!("value of slot {}", value));
+        }
+        assert_eq!(drain.cluster_count(), 1);
+        let (position, stats) = drain.log_groups()[0].variable_stats()[0];
+        assert_eq!(position, 3);
+        assert_eq!(stats.count(), values.len() as u64);
+        stats.variable_type()
+    }
+
+    // durations of 10 to 40 ms, then of -5 and -15 s in a cluster of their own
+    fn durations() -> (DrainTree, ClusterId) {
+        let mut drain = DrainTree::new().max_depth(2).variable_statistics();
+        for i in 1..=4 {
+            drain.add_log_line(&format!("took {} ms for job {}", i * 10, i));
+        }
+        let seconds = drain.add_log_line_id("took -5 s for job 5").unwrap();
+        drain.add_log_line("took -15 s for job 6");
+        (drain, seconds)
+    }
+
+    type Summary = (
+        Option<VariableType>,
+        u64,
+        Option<f64>,
+        Option<f64>,
+        Option<f64>,
+    );
+
+    fn summary(drain: &DrainTree, id: ClusterId) -> Summary {
+        let (_, stats) = drain.cluster_by_id(id).unwrap().variable_stats()[0];
+        (
+            stats.variable_type(),
+            stats.numeric_count(),
+            stats.min(),
+            stats.max(),
+            stats.mean(),
+        )
+    }
+
+    #[test]
+    fn numbers_are_typed() {
+        assert_eq!(variable_type(&["1", "-42"]), Some(VariableType::Int));
+        assert_eq!(variable_type(&["1", "2.5"]), Some(VariableType::Float));
+        assert_eq!(
+            variable_type(&["0x1f", "12", "deadbeef01"]),
+            Some(VariableType::Hex)
+        );
+    }
+
+    #[test]
+    fn identifiers_are_typed() {
+        assert_eq!(
+            variable_type(&["10.0.0.1", "192.168.1.20"]),
+            Some(VariableType::Ip)
+        );
+        assert_eq!(
+            variable_type(&[
+                "123e4567-e89b-12d3-a456-426614174000",
+                "00000000-0000-0000-0000-000000000000"
+            ]),
+            Some(VariableType::Uuid)
+        );
+        assert_eq!(
+            variable_type(&["/var/log/syslog", "/tmp/x.txt"]),
+            Some(VariableType::Path)
+        );
+        assert_eq!(
+            variable_type(&["2021-04-01T10:00:00Z", "2021-04-02T11:30:00+02:00"]),
+            Some(VariableType::Timestamp)
+        );
+    }
+
+    #[test]
+    fn mixed_values_are_text() {
+        assert_eq!(variable_type(&["bob", "face"]), Some(VariableType::Text));
+        assert_eq!(variable_type(&["12", "10.0.0.1"]), Some(VariableType::Text));
+    }
+
+    #[test]
+    fn statistics_are_opt_in() {
+        let mut drain = DrainTree::new().max_depth(2);
+        drain.add_log_line("value of slot 1");
+        drain.add_log_line("value of slot 2");
+        assert!(drain.log_groups()[0].variable_stats().is_empty());
+    }
+
+    #[test]
+    fn numeric_values_are_summarized() {
+        let (drain, seconds) = durations();
+        assert_eq!(
+            summary(&drain, seconds),
+            (
+                Some(VariableType::Int),
+                2,
+                Some(-15.0),
+                Some(-5.0),
+                Some(-10.0)
+            )
+        );
+    }
+
+    #[test]
+    fn statistics_survive_serialization() {
+        let (drain, seconds) = durations();
+        let json = serde_json::to_string(&drain).unwrap();
+        let restored: DrainTree = serde_json::from_str(&json).unwrap();
+        assert_eq!(summary(&restored, seconds), summary(&drain, seconds));
+    }
+
+    #[test]
+    fn statistics_of_merged_clusters_are_merged() {
+        let (mut drain, seconds) = durations();
+        let first = drain.log_group("took 10 ms for job 1").unwrap().id();
+        assert_eq!(drain.merge_clusters(0.5), vec![(seconds, first)]);
+
+        let (_, merged) = drain.cluster_by_id(first).unwrap().variable_stats()[0];
+        assert_eq!(merged.numeric_count(), 6);
+        assert_eq!(merged.min(), Some(-15.0));
+        assert_eq!(merged.max(), Some(40.0));
+        assert_eq!(merged.percentile(0.0), Some(-15.0));
+        assert_eq!(merged.percentile(100.0), Some(40.0));
+        let median = merged.percentile(50.0).unwrap();
+        assert!((median - 10.0).abs() <= 0.2, "{}", median);
+    }
+}
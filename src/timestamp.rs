@@ -0,0 +1,167 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Time constant of the decaying rate of the clusters, in seconds, by default
+pub(crate) const DEFAULT_RATE_WINDOW: f64 = 60.0;
+
+pub(crate) fn default_rate_window() -> f64 {
+    DEFAULT_RATE_WINDOW
+}
+
+/// The extracted field holding the timestamp of the log lines, and its format
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct TimestampField {
+    pub field: String,
+    pub format: String,
+}
+
+impl TimestampField {
+    /// The timestamp of the log line, if the field is there and has the expected format
+    pub fn parse(&self, fields: &BTreeMap<String, String>) -> Option<DateTime<Utc>> {
+        parse_timestamp(fields.get(&self.field)?, &self.format)
+    }
+}
+
+/// Parse a timestamp with a `chrono` format, or `rfc3339`
+///
+/// Timestamps without an offset are taken as UTC, and dates alone as midnight UTC.
+pub(crate) fn parse_timestamp(value: &str, format: &str) -> Option<DateTime<Utc>> {
+    if format == "rfc3339" {
+        return DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|t| t.with_timezone(&Utc));
+    }
+    if let Ok(t) = DateTime::parse_from_str(value, format) {
+        return Some(t.with_timezone(&Utc));
+    }
+    if let Ok(t) = NaiveDateTime::parse_from_str(value, format) {
+        return Some(Utc.from_utc_datetime(&t));
+    }
+    let date = NaiveDate::parse_from_str(value, format).ok()?;
+    Some(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?))
+}
+
+/// The value of an exponentially decaying quantity once `elapsed` milliseconds passed
+pub(crate) fn decay(value: f64, elapsed: i64, rate_window: f64) -> f64 {
+    value * (-(elapsed as f64 / 1000.0) / rate_window).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DrainTree;
+    use float_cmp::approx_eq;
+    use std::time::Duration;
+
+    fn parse(value: &str, format: &str) -> Option<i64> {
+        parse_timestamp(value, format).map(|t| t.timestamp())
+    }
+
+    fn drain() -> DrainTree {
+        DrainTree::new()
+            .max_depth(2)
+            .log_pattern("%{NUMBER:ts} %{GREEDYDATA:content}", "content")
+            .timestamp_field("ts", "%s")
+            .rate_window(Duration::from_secs(10))
+            .build_patterns(&mut grok::Grok::with_patterns())
+    }
+
+    // one line per second for a minute
+    fn steady_drain() -> DrainTree {
+        let mut drain = drain();
+        for second in 0..60 {
+            drain.add_log_line(&format!("{} disk sda full", 1600000000 + second));
+        }
+        drain
+    }
+
+    #[test]
+    fn timestamps_are_parsed() {
+        assert_eq!(parse("2021-04-01T10:00:00Z", "rfc3339"), Some(1617271200));
+        assert_eq!(parse("081109 203615", "%y%m%d %H%M%S"), Some(1226262975));
+        assert_eq!(
+            parse("2021-04-01 12:00:00 +0200", "%Y-%m-%d %H:%M:%S %z"),
+            Some(1617271200)
+        );
+        assert_eq!(parse("2021-04-01", "%Y-%m-%d"), Some(1617235200));
+        assert_eq!(parse("1617271200", "%s"), Some(1617271200));
+        assert_eq!(parse("yesterday", "%Y-%m-%d"), None);
+    }
+
+    #[test]
+    fn clusters_span_the_timestamps_of_their_lines() {
+        let drain = steady_drain();
+        let cluster = drain.log_groups()[0];
+        assert_eq!(cluster.first_seen().unwrap().timestamp(), 1600000000);
+        assert_eq!(cluster.last_seen().unwrap().timestamp(), 1600000059);
+    }
+
+    #[test]
+    fn steady_rates_converge() {
+        let drain = steady_drain();
+        let cluster = drain.log_groups()[0];
+        assert!(approx_eq!(
+            f64,
+            cluster.events_per_second(),
+            1.0,
+            epsilon = 0.05
+        ));
+    }
+
+    #[test]
+    fn rates_decay_over_the_window() {
+        let drain = steady_drain();
+        let cluster = drain.log_groups()[0];
+        let later = Utc.timestamp_opt(1600000069, 0).unwrap();
+        assert!(approx_eq!(
+            f64,
+            drain.events_per_second(cluster, later),
+            cluster.events_per_second() / std::f64::consts::E,
+            epsilon = 0.001
+        ));
+    }
+
+    #[test]
+    fn bursts_are_faster_than_steady_streams() {
+        let mut drain = steady_drain();
+        for _ in 0..20 {
+            drain.add_log_line("1600000070 disk sda full");
+        }
+        let cluster = drain.log_groups()[0];
+        assert!(cluster.events_per_second() > 2.0);
+        assert_eq!(cluster.last_seen().unwrap().timestamp(), 1600000070);
+    }
+
+    #[test]
+    fn lines_without_a_timestamp_happen_when_inserted() {
+        let mut drain = drain();
+        let before = Utc::now().timestamp();
+        let cluster = drain.add_log_line("disk is gone").unwrap();
+        assert!(cluster.first_seen().unwrap().timestamp() >= before);
+    }
+
+    #[test]
+    fn merged_clusters_span_both_lifetimes() {
+        let mut drain = DrainTree::new()
+            .max_depth(3)
+            .max_children(1)
+            .log_pattern("%{NUMBER:ts} %{GREEDYDATA:content}", "content")
+            .timestamp_field("ts", "%s")
+            .build_patterns(&mut grok::Grok::with_patterns());
+        let alpha = drain
+            .add_log_line_id("1600000100 alpha task done now")
+            .unwrap();
+        drain.add_log_line("1600000000 beta task done now");
+        drain.merge_clusters(0.7);
+
+        let merged = drain.cluster_by_id(alpha).unwrap();
+        assert_eq!(merged.first_seen().unwrap().timestamp(), 1600000000);
+        assert_eq!(merged.last_seen().unwrap().timestamp(), 1600000100);
+        let json = serde_json::to_string(&drain).unwrap();
+        let restored: DrainTree = serde_json::from_str(&json).unwrap();
+        let restored = restored.cluster_by_id(alpha).unwrap();
+        assert_eq!(restored.first_seen(), merged.first_seen());
+        assert_eq!(restored.events_per_second(), merged.events_per_second());
+    }
+}
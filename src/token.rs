@@ -1,33 +1,44 @@
-use crate::grok_generator::GrokGenerator;
-use serde::de::{self, Visitor};
+use crate::sampling::{Sampling, Wildcard};
+use crate::statistics::VariableStats;
+use serde::de::{self, value::MapAccessDeserializer, MapAccess, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::collections::BTreeSet;
 use std::fmt;
 use std::fmt::Formatter;
 
 // A wildcard is used to say that this token can be of any values
-// For the purpose of the algorithm we store all encountered values
+// For the purpose of the algorithm we sample the encountered values
 // so they can be analyzed to propose the correct grok pattern
 #[derive(Eq, PartialEq, Hash, Debug)]
 pub enum Token {
-    WildCard(BTreeSet<String>),
+    WildCard(Wildcard),
     Val(String),
 }
 
+/// What a wildcard stands for, the prefix tree sending the tokens of a kind to their own branch
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) enum WildcardKind {
+    /// Any value
+    #[default]
+    Any,
+    /// Values containing a digit, see `MaskingRule::NumericPrefixes`
+    Number,
+}
+
+impl WildcardKind {
+    pub fn is_any(&self) -> bool {
+        *self == WildcardKind::Any
+    }
+}
+
 impl Token {
-    pub fn new_wildcard(init_string: String) -> Self {
-        let mut wildcard_values = BTreeSet::<String>::new();
-        wildcard_values.insert(init_string);
-        Token::WildCard(wildcard_values)
-    }
-    pub fn new_wildcard_from_token(init_token: Token) -> Self {
-        match init_token {
-            Token::Val(init_string) => Token::new_wildcard(init_string),
-            _ => unreachable!("This code should never be reached"),
-        }
+    pub fn new_typed_wildcard(kind: WildcardKind) -> Self {
+        Token::WildCard(Wildcard {
+            kind,
+            ..Wildcard::default()
+        })
     }
     pub fn new_empty_wildcard() -> Self {
-        Token::WildCard(BTreeSet::new())
+        Token::WildCard(Wildcard::default())
     }
     pub fn is_wildcard(&self) -> bool {
         match self {
@@ -35,21 +46,55 @@ impl Token {
             Self::Val(_) => false,
         }
     }
-    pub fn add_token_if_wildcard(&mut self, new_token: &Token) {
-        // add a new token to the wildcard
+    /// Whether both tokens are the same constant, or wildcards of the same kind whatever values
+    /// they saw
+    pub fn matches(&self, other: &Token) -> bool {
+        match (self, other) {
+            (Token::Val(a), Token::Val(b)) => a == b,
+            (Token::WildCard(a), Token::WildCard(b)) => a.kind == b.kind,
+            _ => false,
+        }
+    }
+    pub fn sample(&mut self, value: &str, count: u64, sampling: &Sampling) {
+        // record `count` occurrences of the value in the wildcard
         // this has no effect if this token (self) is not a wildcard
-        // also the number of stored token is of maximum 10
-        if let Token::WildCard(btreeset) = self {
-            if btreeset.len() < 10 {
-                btreeset.insert(new_token.to_string());
+        if let Token::WildCard(wildcard) = self {
+            wildcard.sample(value, count, sampling);
+        }
+    }
+    pub fn merge(&mut self, weight: u64, other: &Token, other_weight: u64, sampling: &Sampling) {
+        // generalize this token so that it also covers the other one
+        // the values of both tokens are sampled, a constant being seen `weight` times
+        match (&*self, other) {
+            (Token::Val(a), Token::Val(b)) if a == b => return,
+            (Token::Val(_), _) => {
+                if let Token::Val(previous) = std::mem::replace(self, Token::new_empty_wildcard()) {
+                    self.sample(&previous, weight, sampling);
+                }
+            }
+            _ => {}
+        }
+        match other {
+            Token::Val(value) => self.sample(value, other_weight, sampling),
+            Token::WildCard(other) => {
+                if let Token::WildCard(wildcard) = self {
+                    wildcard.merge(other, sampling);
+                }
             }
         }
     }
+    pub fn approximate_size(&self) -> usize {
+        std::mem::size_of::<Token>()
+            + match self {
+                Token::Val(s) => s.len(),
+                Token::WildCard(wildcard) => wildcard.approximate_size(),
+            }
+    }
     pub fn as_detailed_string(&self) -> String {
         match self {
-            Token::Val(s) => format!("{}", s.as_str()),
-            Token::WildCard(s) => {
-                let vec = s.iter().collect::<Vec<&String>>();
+            Token::Val(s) => s.to_string(),
+            Token::WildCard(wildcard) => {
+                let vec = wildcard.samples.iter().collect::<Vec<&String>>();
                 format!("<** {:?} **>", vec)
             }
         }
@@ -58,42 +103,60 @@ impl Token {
     pub fn as_string_vector(&self) -> Option<Vec<String>> {
         match self {
             Token::Val(_) => None,
-            Token::WildCard(s) => Some(s.clone().into_iter().collect::<Vec<String>>()),
+            Token::WildCard(wildcard) => Some(wildcard.samples.iter().cloned().collect()),
         }
     }
 
-    pub fn detect_best_grok(&self, grok_generator: &GrokGenerator) -> String {
+    pub fn variable_stats(&self) -> Option<&VariableStats> {
         match self {
-            Token::Val(constant_value) => constant_value.to_owned(),
-            _ => match &self.as_string_vector() {
-                Some(string_vector) => {
-                    grok_generator.detect_grok_for_a_list_of_string(&string_vector)
-                }
-                None => None,
-            }
-            .unwrap_or("<unknown>".to_string()),
+            Token::Val(_) => None,
+            Token::WildCard(wildcard) => Some(&wildcard.stats),
+        }
+    }
+
+    pub fn distinct_count(&self) -> Option<u64> {
+        match self {
+            Token::Val(_) => None,
+            Token::WildCard(wildcard) => Some(wildcard.distinct_count()),
         }
     }
 }
 
+// Constant tokens are serialized as strings, and so are wildcards that never saw a value.
+// Other wildcards are serialized as a struct, to keep their sampled values and statistics.
+// Older trees stored every wildcard as a bare "<*>" string, which is read as a wildcard
+// that never saw a value.
+const WILDCARD: &str = "<*>";
+// the key of the prefix tree branch taken by the tokens containing a digit
+const NUMBER: &str = "<NUM>";
+
 struct TokenVisitor;
 impl<'de> Visitor<'de> for TokenVisitor {
     type Value = Token;
 
     fn expecting(&self, formatter: &mut Formatter<'_>) -> fmt::Result {
-        formatter.write_str("a string")
+        formatter.write_str("a string or a wildcard")
     }
 
     fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
     where
         E: de::Error,
     {
-        if value == "<*>" {
+        if value == WILDCARD {
             Ok(Token::new_empty_wildcard())
         } else {
             Ok(Token::Val(String::from(value)))
         }
     }
+
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut wildcard = Wildcard::deserialize(MapAccessDeserializer::new(map))?;
+        wildcard.infer_type();
+        Ok(Token::WildCard(wildcard))
+    }
 }
 
 impl Serialize for Token {
@@ -101,7 +164,13 @@ impl Serialize for Token {
     where
         S: Serializer,
     {
-        serializer.serialize_str(self.to_string().as_str())
+        match self {
+            Token::Val(s) => serializer.serialize_str(s),
+            Token::WildCard(wildcard) if wildcard.is_empty() && wildcard.kind.is_any() => {
+                serializer.serialize_str(WILDCARD)
+            }
+            Token::WildCard(wildcard) => wildcard.serialize(serializer),
+        }
     }
 }
 
@@ -110,7 +179,88 @@ impl<'de> Deserialize<'de> for Token {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_str(TokenVisitor)
+        deserializer.deserialize_any(TokenVisitor)
+    }
+}
+
+/// Serialization of the children of a prefix tree node, keyed by the tokens as strings
+///
+/// Formats such as JSON only allow strings as map keys. The wildcard branches are `<*>` and
+/// `<NUM>`, the branch of the tokens containing a digit. The constants are kept as is, unless
+/// they read as a wildcard branch once stripped of their leading backslashes, which then get
+/// one more backslash, e.g. the constant `<NUM>` left by a masking rule is `\<NUM>`.
+pub(crate) mod prefix_keys {
+    use super::{Token, WildcardKind, NUMBER, WILDCARD};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::HashMap;
+
+    const ESCAPE: char = '\\';
+
+    // whether the constant reads as a wildcard branch once stripped of its leading backslashes
+    fn is_escaped(constant: &str) -> bool {
+        let tag = constant.trim_start_matches(ESCAPE);
+        tag == WILDCARD || tag == NUMBER
+    }
+
+    pub fn serialize<S, V>(children: &HashMap<Token, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        V: Serialize,
+    {
+        serializer.collect_map(children.iter().map(|(k, v)| (token_key(k), v)))
+    }
+
+    /// The key of a token
+    pub(super) fn token_key(token: &Token) -> String {
+        match token {
+            Token::Val(constant) if is_escaped(constant) => format!("{}{}", ESCAPE, constant),
+            token => token.to_string(),
+        }
+    }
+
+    pub fn deserialize<'de, D, V>(deserializer: D) -> Result<HashMap<Token, V>, D::Error>
+    where
+        D: Deserializer<'de>,
+        V: Deserialize<'de>,
+    {
+        HashMap::<String, V>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(k, v)| Ok((key_token::<D::Error>(&k)?, v)))
+            .collect()
+    }
+
+    /// The token of a key
+    pub(super) fn key_token<E: serde::de::Error>(key: &str) -> Result<Token, E> {
+        Ok(match key {
+            WILDCARD => Token::new_empty_wildcard(),
+            NUMBER => Token::new_typed_wildcard(WildcardKind::Number),
+            _ if is_escaped(key) => Token::Val(key[ESCAPE.len_utf8()..].to_string()),
+            _ => Token::Val(key.to_string()),
+        })
+    }
+}
+
+/// Serialization of the keys followed from a length bucket to a leaf, as in `prefix_keys`
+pub(crate) mod prefix_path {
+    use super::prefix_keys::{key_token, token_key};
+    use super::Token;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(path: &[Token], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(path.iter().map(token_key))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Token>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|key| key_token::<D::Error>(key))
+            .collect()
     }
 }
 
@@ -118,7 +268,10 @@ impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Token::Val(s) => write!(f, "{}", s.as_str()),
-            Token::WildCard(_) => write!(f, "<*>"),
+            Token::WildCard(wildcard) => match wildcard.kind {
+                WildcardKind::Any => write!(f, "{}", WILDCARD),
+                WildcardKind::Number => write!(f, "{}", NUMBER),
+            },
         }
     }
 }
@@ -131,3 +284,46 @@ impl std::clone::Clone for Token {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::prefix_keys::{key_token, token_key};
+    use super::*;
+
+    fn round_trip(token: Token) {
+        let key = token_key(&token);
+        assert_eq!(
+            key_token::<serde_json::Error>(&key).unwrap(),
+            token,
+            "{}",
+            key
+        );
+    }
+
+    #[test]
+    fn constants_do_not_collide_with_wildcard_keys() {
+        assert_eq!(token_key(&Token::Val("<NUM>".into())), r"\<NUM>");
+        for constant in ["<NUM>", "<*>", r"\<NUM>", r"\\<*>", r"\path", "<IP>", "a"] {
+            round_trip(Token::Val(constant.into()));
+        }
+        round_trip(Token::new_empty_wildcard());
+        round_trip(Token::new_typed_wildcard(WildcardKind::Number));
+    }
+
+    #[test]
+    fn bare_wildcards_are_deserialized() {
+        // older trees stored wildcards as bare strings
+        let mut drain = crate::DrainTree::new().max_depth(2);
+        let id = drain.add_log_line_id("login of user bob").unwrap();
+        drain.add_log_line("login of user eve");
+        let json = serde_json::to_string(&drain).unwrap();
+        let start = json.find(r#"{"samples""#).unwrap();
+        let end = start + json[start..].find('}').unwrap() + 1;
+        let old = format!("{}\"<*>\"{}", &json[..start], &json[end..]);
+
+        let restored: crate::DrainTree = serde_json::from_str(&old).unwrap();
+        let cluster = restored.cluster_by_id(id).unwrap();
+        assert_eq!(cluster.as_string(), "login of user <*>");
+        assert_eq!(cluster.tokens()[3], Token::new_empty_wildcard());
+    }
+}
@@ -0,0 +1,160 @@
+use std::fmt;
+
+/// Splits the clustered text of a log line into tokens
+///
+/// The number of tokens picks the length bucket of the tree, and every token is a candidate
+/// variable of the template, so the tokenizer should split where the log format changes values.
+pub trait Tokenizer: fmt::Debug + Send + Sync {
+    /// Split the text into tokens
+    fn tokenize<'a>(&self, text: &'a str) -> Vec<&'a str>;
+
+    /// Rebuild text from tokens, used to render templates
+    fn join(&self, tokens: &[String]) -> String {
+        tokens.join(" ")
+    }
+}
+
+pub(crate) fn default_tokenizer() -> Box<dyn Tokenizer> {
+    Box::new(SpaceTokenizer)
+}
+
+/// Splits on every single space and trims the tokens, this is the default tokenizer
+///
+/// Consecutive spaces result in empty tokens.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpaceTokenizer;
+
+impl Tokenizer for SpaceTokenizer {
+    fn tokenize<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        text.split(' ').map(|t| t.trim()).collect()
+    }
+}
+
+/// Splits on runs of any whitespace, including tabs
+/// ```
+/// use drain_rs::{Tokenizer, WhitespaceTokenizer};
+/// assert_eq!(WhitespaceTokenizer.tokenize("a \t b  c"), vec!["a", "b", "c"]);
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WhitespaceTokenizer;
+
+impl Tokenizer for WhitespaceTokenizer {
+    fn tokenize<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        text.split_whitespace().collect()
+    }
+}
+
+/// Splits on runs of any of the given delimiters, the delimiters are dropped
+///
+/// Templates are rendered with the first delimiter.
+/// ```
+/// use drain_rs::{DelimiterTokenizer, Tokenizer};
+/// let tokenizer = DelimiterTokenizer::new(" ,=");
+/// assert_eq!(tokenizer.tokenize("a=1, b=2"), vec!["a", "1", "b", "2"]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct DelimiterTokenizer {
+    delimiters: Vec<char>,
+}
+
+impl DelimiterTokenizer {
+    /// Create a tokenizer splitting on every character of `delimiters`
+    pub fn new(delimiters: &str) -> Self {
+        DelimiterTokenizer {
+            delimiters: delimiters.chars().collect(),
+        }
+    }
+}
+
+impl Tokenizer for DelimiterTokenizer {
+    fn tokenize<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        text.split(|c| self.delimiters.contains(&c))
+            .filter(|t| !t.is_empty())
+            .collect()
+    }
+
+    fn join(&self, tokens: &[String]) -> String {
+        let mut separator = [0; 4];
+        let separator = match self.delimiters.first() {
+            Some(c) => c.encode_utf8(&mut separator),
+            None => "",
+        };
+        tokens.join(separator)
+    }
+}
+
+/// Splits words from punctuation, keeping every separator as its own token
+///
+/// Each punctuation character is a token and each run of whitespace is a token, so joining
+/// the tokens gives back the original text.
+/// ```
+/// use drain_rs::{PunctuationTokenizer, Tokenizer};
+/// let tokenizer = PunctuationTokenizer::default();
+/// let tokens = tokenizer.tokenize("[thread-1] key=value");
+/// assert_eq!(tokens, vec!["[", "thread-1", "]", " ", "key", "=", "value"]);
+///
+/// let tokens = tokens.iter().map(|t| t.to_string()).collect::<Vec<String>>();
+/// assert_eq!(tokenizer.join(&tokens), "[thread-1] key=value");
+/// ```
+#[derive(Debug, Clone)]
+pub struct PunctuationTokenizer {
+    punctuation: Vec<char>,
+}
+
+impl PunctuationTokenizer {
+    /// Create a tokenizer treating every character of `punctuation` as a separator
+    pub fn new(punctuation: &str) -> Self {
+        PunctuationTokenizer {
+            punctuation: punctuation.chars().collect(),
+        }
+    }
+}
+
+impl Default for PunctuationTokenizer {
+    /// Separates brackets, quotes and the usual key/value and list separators
+    fn default() -> Self {
+        PunctuationTokenizer::new("[](){}=,;:|\"'")
+    }
+}
+
+#[derive(PartialEq)]
+enum CharClass {
+    Word,
+    Space,
+    Punctuation,
+}
+
+impl Tokenizer for PunctuationTokenizer {
+    fn tokenize<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        let class = |c: char| {
+            if c.is_whitespace() {
+                CharClass::Space
+            } else if self.punctuation.contains(&c) {
+                CharClass::Punctuation
+            } else {
+                CharClass::Word
+            }
+        };
+        let mut tokens = vec![];
+        let mut start = 0;
+        let mut previous: Option<CharClass> = None;
+        for (i, c) in text.char_indices() {
+            let current = class(c);
+            if let Some(previous) = previous {
+                if previous != current || current == CharClass::Punctuation {
+                    tokens.push(&text[start..i]);
+                    start = i;
+                }
+            }
+            previous = Some(current);
+        }
+        if start < text.len() {
+            tokens.push(&text[start..]);
+        }
+        tokens
+    }
+
+    fn join(&self, tokens: &[String]) -> String {
+        tokens.concat()
+    }
+}
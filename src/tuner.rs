@@ -0,0 +1,356 @@
+use crate::error::Error;
+use crate::evaluation::{evaluate, GroundTruth};
+use crate::DrainTree;
+use std::fmt;
+use std::fmt::Formatter;
+
+/// What the tuner maximizes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Objective {
+    /// `Evaluation::grouping_accuracy`, needs labeled log lines
+    GroupingAccuracy,
+    /// `Evaluation::parsing_accuracy`, needs labeled log lines
+    ParsingAccuracy,
+    /// `Evaluation::f_measure`, needs labeled log lines
+    FMeasure,
+    /// Harmonic mean of the compression, one minus the number of templates per log line,
+    /// and of the specificity, one minus the share of wildcards in the template of a log line
+    TemplateBalance,
+    /// Harmonic mean of one minus the normalized entropy of the cluster sizes, and of the
+    /// specificity as in `TemplateBalance`
+    ClusterEntropy,
+}
+
+impl Objective {
+    /// Whether the objective compares the clusters against labeled log lines
+    pub fn is_labeled(&self) -> bool {
+        matches!(
+            self,
+            Objective::GroupingAccuracy | Objective::ParsingAccuracy | Objective::FMeasure
+        )
+    }
+}
+
+/// Grid search over the parameters of a `DrainTree`
+///
+/// Every combination of the candidate values is tried on a sample of log lines.
+/// By default, `max_depth` goes from 3 to 6, `max_children` is 100 and `min_similarity` goes
+/// from 0.3 to 0.7 by steps of 0.1.
+/// ```
+/// use drain_rs::{DrainTree, Tuner};
+/// let lines = ["user 3 logged in", "user 4 logged in", "disk sda full", "disk sdb full"];
+/// let tuning = Tuner::new().max_depths(vec![2, 3]).tune(DrainTree::new, &lines, None).unwrap();
+/// println!("{}", tuning);
+///
+/// let mut drain = tuning.apply(DrainTree::new());
+/// drain.add_log_line("user 5 logged in");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Tuner {
+    max_depths: Vec<u16>,
+    max_children: Vec<u16>,
+    min_similarities: Vec<f32>,
+    objective: Option<Objective>,
+}
+
+impl Default for Tuner {
+    fn default() -> Self {
+        Tuner {
+            max_depths: vec![3, 4, 5, 6],
+            max_children: vec![100],
+            min_similarities: vec![0.3, 0.4, 0.5, 0.6, 0.7],
+            objective: None,
+        }
+    }
+}
+
+/// The score of one combination of parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trial {
+    /// The tried `max_depth`
+    pub max_depth: u16,
+    /// The tried `max_children`
+    pub max_children: u16,
+    /// The tried `min_similarity`
+    pub min_similarity: f32,
+    /// The number of clusters built from the sample
+    pub clusters: usize,
+    /// The value of the objective, the higher the better
+    pub score: f64,
+}
+
+/// The outcome of `Tuner::tune`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    /// The maximized objective
+    pub objective: Objective,
+    /// The best trial, the first one tried on ties
+    pub best: Trial,
+    /// Every trial, in the order they were tried
+    pub trials: Vec<Trial>,
+}
+
+impl Tuning {
+    /// Configure the tree with the chosen parameters
+    pub fn apply(&self, drain: DrainTree) -> DrainTree {
+        drain
+            .max_depth(self.best.max_depth)
+            .max_children(self.best.max_children)
+            .min_similarity(self.best.min_similarity)
+    }
+}
+
+impl fmt::Display for Tuning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "objective: {:?}", self.objective)?;
+        for trial in self.trials.iter() {
+            writeln!(
+                f,
+                "max_depth [{}] max_children [{}] min_similarity [{}]: score [{:.4}] clusters [{}]",
+                trial.max_depth,
+                trial.max_children,
+                trial.min_similarity,
+                trial.score,
+                trial.clusters
+            )?;
+        }
+        write!(
+            f,
+            "chosen: max_depth [{}] max_children [{}] min_similarity [{}]",
+            self.best.max_depth, self.best.max_children, self.best.min_similarity
+        )
+    }
+}
+
+/// Score the clusters of a tree without labels
+fn unsupervised_score(drain: &DrainTree, objective: Objective) -> f64 {
+    let clusters = drain.log_groups();
+    let lines: u64 = clusters.iter().map(|c| c.num_matched()).sum();
+    if lines == 0 {
+        return 0.0;
+    }
+    // share of wildcards in the template of a log line, on average over the log lines
+    let wildcards: f64 = clusters
+        .iter()
+        .filter(|c| !c.tokens().is_empty())
+        .map(|c| {
+            let count = c.tokens().iter().filter(|t| t.is_wildcard()).count();
+            c.num_matched() as f64 * count as f64 / c.tokens().len() as f64
+        })
+        .sum::<f64>()
+        / lines as f64;
+    let specificity = 1.0 - wildcards;
+    let concentration = match objective {
+        Objective::ClusterEntropy => {
+            if lines < 2 {
+                1.0
+            } else {
+                let entropy: f64 = clusters
+                    .iter()
+                    .map(|c| {
+                        let p = c.num_matched() as f64 / lines as f64;
+                        -p * p.ln()
+                    })
+                    .sum();
+                1.0 - entropy / (lines as f64).ln()
+            }
+        }
+        _ => 1.0 - clusters.len() as f64 / lines as f64,
+    };
+    if concentration + specificity > 0.0 {
+        2.0 * concentration * specificity / (concentration + specificity)
+    } else {
+        0.0
+    }
+}
+
+impl Tuner {
+    /// Create a tuner with the default candidate values
+    pub fn new() -> Self {
+        Tuner::default()
+    }
+
+    /// The candidate values of `max_depth`
+    pub fn max_depths(mut self, max_depths: Vec<u16>) -> Self {
+        self.max_depths = max_depths;
+        self
+    }
+
+    /// The candidate values of `max_children`
+    pub fn max_children(mut self, max_children: Vec<u16>) -> Self {
+        self.max_children = max_children;
+        self
+    }
+
+    /// The candidate values of `min_similarity`
+    pub fn min_similarities(mut self, min_similarities: Vec<f32>) -> Self {
+        self.min_similarities = min_similarities;
+        self
+    }
+
+    /// What to maximize, defaults to `GroupingAccuracy` when the lines are labeled and to
+    /// `TemplateBalance` otherwise
+    pub fn objective(mut self, objective: Objective) -> Self {
+        self.objective = Some(objective);
+        self
+    }
+
+    /// Try every combination of the candidate values on the log lines
+    ///
+    /// `base` creates the tree every trial starts from, with its patterns already built.
+    /// Labeled objectives need the ground truth of the log lines, see `evaluate`.
+    pub fn tune<F, S>(
+        &self,
+        base: F,
+        lines: &[S],
+        truth: Option<&GroundTruth>,
+    ) -> Result<Tuning, Error>
+    where
+        F: Fn() -> DrainTree,
+        S: AsRef<str>,
+    {
+        let objective = self.objective.unwrap_or(if truth.is_some() {
+            Objective::GroupingAccuracy
+        } else {
+            Objective::TemplateBalance
+        });
+        if objective.is_labeled() && truth.is_none() {
+            return Err(Error::InvalidGroundTruth(format!(
+                "missing, required by the [{:?}] objective",
+                objective
+            )));
+        }
+        let mut trials = vec![];
+        for &max_depth in self.max_depths.iter() {
+            for &max_children in self.max_children.iter() {
+                for &min_similarity in self.min_similarities.iter() {
+                    let mut drain = base()
+                        .max_depth(max_depth)
+                        .max_children(max_children)
+                        .min_similarity(min_similarity);
+                    let score = match (objective, truth) {
+                        (Objective::TemplateBalance, _) | (Objective::ClusterEntropy, _) => {
+                            for line in lines {
+                                drain.add_log_line(line.as_ref());
+                            }
+                            unsupervised_score(&drain, objective)
+                        }
+                        (_, Some(truth)) => {
+                            let evaluation =
+                                evaluate(&mut drain, lines.iter().map(|l| l.as_ref()), truth)?;
+                            match objective {
+                                Objective::ParsingAccuracy => evaluation.parsing_accuracy,
+                                Objective::FMeasure => evaluation.f_measure,
+                                _ => evaluation.grouping_accuracy,
+                            }
+                        }
+                        (_, None) => unreachable!("labeled objectives are checked above"),
+                    };
+                    trials.push(Trial {
+                        max_depth,
+                        max_children,
+                        min_similarity,
+                        clusters: drain.cluster_count(),
+                        score,
+                    });
+                }
+            }
+        }
+        let best = trials
+            .iter()
+            .fold(None, |best: Option<&Trial>, trial| match best {
+                Some(best) if best.score >= trial.score => Some(best),
+                _ => Some(trial),
+            })
+            .cloned()
+            .ok_or(Error::EmptySearchSpace)?;
+        Ok(Tuning {
+            objective,
+            best,
+            trials,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use float_cmp::approx_eq;
+
+    const LINES: [&str; 5] = [
+        "login accepted for alice",
+        "login accepted for bob",
+        "login accepted for carol",
+        "logout requested by dave",
+        "logout requested by erin",
+    ];
+
+    fn truth() -> GroundTruth {
+        GroundTruth::new(
+            LINES
+                .iter()
+                .map(|l| {
+                    if l.starts_with("login") {
+                        ("E1".to_string(), "login accepted for <*>".to_string())
+                    } else {
+                        ("E2".to_string(), "logout requested by <*>".to_string())
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    fn tuner() -> Tuner {
+        Tuner::new()
+            .max_depths(vec![4, 2])
+            .min_similarities(vec![0.5])
+    }
+
+    #[test]
+    fn labeled_lines_are_tuned_for_grouping_accuracy() {
+        let tuning = tuner()
+            .tune(DrainTree::new, &LINES, Some(&truth()))
+            .unwrap();
+        assert_eq!(tuning.objective, Objective::GroupingAccuracy);
+        assert_eq!(tuning.trials.len(), 2);
+        assert_eq!((tuning.best.max_depth, tuning.best.clusters), (2, 2));
+        assert!(approx_eq!(f64, tuning.best.score, 1.0));
+        assert!(approx_eq!(f64, tuning.trials[0].score, 0.0));
+    }
+
+    #[test]
+    fn unlabeled_objectives_pick_the_same_parameters() {
+        for objective in [Objective::TemplateBalance, Objective::ClusterEntropy] {
+            let tuning = tuner()
+                .objective(objective)
+                .tune(DrainTree::new, &LINES, None)
+                .unwrap();
+            assert_eq!(tuning.best.max_depth, 2);
+        }
+    }
+
+    #[test]
+    fn best_parameters_are_applied() {
+        let tuning = tuner()
+            .tune(DrainTree::new, &LINES, Some(&truth()))
+            .unwrap();
+        assert_eq!(tuning.apply(DrainTree::new()).max_depth, 2);
+    }
+
+    #[test]
+    fn labeled_objectives_need_a_ground_truth() {
+        let missing_truth =
+            tuner()
+                .objective(Objective::FMeasure)
+                .tune(DrainTree::new, &LINES, None);
+        assert!(matches!(missing_truth, Err(Error::InvalidGroundTruth(_))));
+    }
+
+    #[test]
+    fn empty_search_spaces_are_rejected() {
+        let empty = tuner()
+            .max_children(vec![])
+            .tune(DrainTree::new, &LINES, None);
+        assert!(matches!(empty.unwrap_err(), Error::EmptySearchSpace));
+    }
+}
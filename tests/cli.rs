@@ -0,0 +1,265 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+fn drain(args: &[&str], stdin: &str) -> (bool, String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_drain"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+const LOGS: &str = "1 [INFO] user 3 called 192.0.0.1
+2 [INFO] user 2 called 127.0.0.1
+3 [DEBUG] something, uninteresting happened
+4 [INFO] user 4 called 10.0.0.1
+";
+
+#[test]
+fn clusters_stdin_sorted_by_count() {
+    let args = [
+        "--log-pattern",
+        "%{NUMBER:id} \\[%{LOGLEVEL:level}\\] %{GREEDYDATA:content}",
+        "--field=content",
+        "--filter-pattern",
+        "%{IPV4:ip_address}",
+    ];
+    let (success, stdout, _) = drain(&args, LOGS);
+    assert!(success);
+    assert_eq!(
+        stdout,
+        "3\t[0] user <*> called <ip_address>\n1\t[1] something, uninteresting happened\n"
+    );
+
+    let (success, stdout, _) = drain(&[&args[..], &["--format", "csv"]].concat(), LOGS);
+    assert!(success);
+    assert_eq!(
+        stdout,
+        "id,count,template\n0,3,user <*> called <ip_address>\n1,1,\"something, uninteresting happened\"\n"
+    );
+
+    let (success, stdout, _) = drain(&[&args[..], &["--format", "json"]].concat(), LOGS);
+    assert!(success);
+    let clusters: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(clusters[0]["count"], 3);
+    assert_eq!(clusters[1]["template"], "something, uninteresting happened");
+}
+
+#[test]
+fn save_and_load_state() {
+    let state = std::env::temp_dir().join(format!("drain-cli-{}.snapshot", std::process::id()));
+    let state = state.to_str().unwrap();
+    let logs = "login accepted for alice\nlogin accepted for bob\nlogout requested by carol\n";
+    let (success, _, _) = drain(&["--max-depth", "2", "--save", state], logs);
+    assert!(success);
+
+    let (success, stdout, _) = drain(&["--load", state, "-"], "login accepted for dave\n");
+    std::fs::remove_file(state).unwrap();
+    assert!(success);
+    assert_eq!(
+        stdout,
+        "3\t[0] login accepted for <*>\n1\t[1] logout requested by carol\n"
+    );
+}
+
+#[test]
+fn rejects_invalid_arguments() {
+    let (success, _, stderr) = drain(&["--log-pattern", "%{GREEDYDATA:content}"], "");
+    assert!(!success);
+    assert!(stderr.contains("--log-pattern and --field must be given together"));
+
+    let (success, _, stderr) = drain(&["--max-depth", "deep"], "");
+    assert!(!success);
+    assert!(stderr.contains("invalid value [deep] for --max-depth"));
+
+    let (success, _, stderr) = drain(&["--load", "state.json", "--max-depth", "3"], "");
+    assert!(!success);
+    assert!(stderr.contains("--load"));
+}
+
+#[test]
+fn evaluate_against_structured_csv() {
+    let truth = std::env::temp_dir().join(format!("drain-truth-{}.csv", std::process::id()));
+    std::fs::write(
+        &truth,
+        "LineId,Content,EventId,EventTemplate
+1,login accepted for alice,E1,login accepted for <*>
+2,login accepted for bob,E1,login accepted for <*>
+3,\"logout requested, by carol\",E2,\"logout requested, by <*>\"
+",
+    )
+    .unwrap();
+    let logs = "login accepted for alice\nlogin accepted for bob\nlogout requested, by carol\n";
+    let truth = truth.to_str().unwrap();
+    let (success, stdout, stderr) = drain(
+        &[
+            "evaluate",
+            "--truth",
+            truth,
+            "--max-depth",
+            "2",
+            "--format=json",
+        ],
+        logs,
+    );
+    let (_, short, _) = drain(
+        &["evaluate", "--truth", truth],
+        "login accepted for alice\n",
+    );
+    std::fs::remove_file(truth).unwrap();
+
+    assert!(success, "{}", stderr);
+    let evaluation: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(evaluation["lines"], 3);
+    assert_eq!(evaluation["grouping_accuracy"], 1.0);
+    assert_eq!(evaluation["parsing_accuracy"], 2.0 / 3.0);
+    assert_eq!(evaluation["f_measure"], 1.0);
+    assert!(short.is_empty());
+}
+
+#[test]
+fn multiline_events() {
+    let logs = "request 1 failed
+java.io.IOException: closed
+\tat com.foo.Handler.run(Handler.java:12)
+\tat java.lang.Thread.run(Thread.java:748)
+request 2 failed
+java.io.IOException: reset
+\tat com.foo.Handler.run(Handler.java:12)
+";
+    let (success, stdout, _) = drain(&["--indentation", "--signature"], logs);
+    assert!(success);
+    assert_eq!(stdout, "2\t[0] request <*> failed java.io.IOException\n");
+
+    let (success, _, stderr) = drain(&["evaluate", "--truth", "t.csv", "--indentation"], "");
+    assert!(!success);
+    assert!(stderr.contains("multi-line"));
+}
+
+#[test]
+fn json_lines_partitioned() {
+    let logs = r#"{"service":"api","level":"INFO","msg":"served request 1","host":"a"}
+{"service":"api","level":"INFO","msg":"served request 2","host":"b"}
+{"service":"db","level":"INFO","msg":"served request 3","host":"c"}
+"#;
+    let args = [
+        "--json-message",
+        "/msg",
+        "--partition-field",
+        "/service",
+        "--max-depth",
+        "2",
+    ];
+    let (success, stdout, _) = drain(&args, logs);
+    assert!(success);
+    assert_eq!(
+        stdout,
+        "2\t[0] {/service=api} served request <*>\n1\t[1] {/service=db} served request 3\n"
+    );
+
+    let (success, stdout, _) = drain(&[&args[..], &["--format", "json"]].concat(), logs);
+    assert!(success);
+    let clusters: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(clusters[0]["partition"]["/service"], "api");
+    assert_eq!(
+        clusters[0]["metadata"]["/host"],
+        serde_json::json!(["a", "b"])
+    );
+
+    let (success, _, stderr) = drain(&["--partition-field", "/service"], "");
+    assert!(!success);
+    assert!(stderr.contains("--json-message"));
+}
+
+#[test]
+fn partition_by_log_pattern_fields() {
+    let args = [
+        "--log-pattern",
+        "%{NUMBER:id} \\[%{LOGLEVEL:level}\\] %{GREEDYDATA:content}",
+        "--field=content",
+        "--partition-field=level",
+        "--format=csv",
+    ];
+    let (success, stdout, _) = drain(&args, "1 [INFO] disk full\n2 [WARN] disk full\n");
+    assert!(success);
+    assert_eq!(
+        stdout,
+        "id,count,partition,template\n0,1,level=INFO,disk full\n1,1,level=WARN,disk full\n"
+    );
+}
+
+#[test]
+fn cluster_timestamps() {
+    let logs = r#"{"time":"2021-04-01T10:00:00Z","msg":"disk full"}
+{"time":"2021-04-01T10:05:00+02:00","msg":"disk full"}
+{"time":"2021-04-01T09:00:00Z","msg":"disk full"}
+"#;
+    let args = [
+        "--json-message=/msg",
+        "--timestamp-field=/time",
+        "--format=json",
+    ];
+    let (success, stdout, _) = drain(&args, logs);
+    assert!(success);
+    let clusters: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(clusters[0]["first_seen"], "2021-04-01T08:05:00+00:00");
+    assert_eq!(clusters[0]["last_seen"], "2021-04-01T10:00:00+00:00");
+    assert!(clusters[0]["events_per_second"].as_f64().unwrap() > 0.0);
+
+    let (success, _, stderr) = drain(&["--timestamp-format", "%s"], "");
+    assert!(!success);
+    assert!(stderr.contains("--timestamp-field"));
+}
+
+#[test]
+fn variable_stats() {
+    let logs = "Received block blk_1 of size 1024
+Received block blk_2 of size 2048
+Received block blk_3 of size 3072
+";
+    let args = ["--max-depth", "2", "--variable-stats", "--format", "json"];
+    let (success, stdout, _) = drain(&args, logs);
+    assert!(success);
+    let clusters: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(clusters[0]["template"], "Received block <*> of size <*>");
+    let size = &clusters[0]["variables"][1];
+    assert_eq!(size["position"], 5);
+    assert_eq!(size["type"], "Int");
+    assert_eq!(size["count"], 3);
+    assert_eq!(size["min"], 1024.0);
+    assert_eq!(size["max"], 3072.0);
+    assert_eq!(size["mean"], 2048.0);
+    assert_eq!(clusters[0]["variables"][0]["type"], "Text");
+    assert_eq!(clusters[0]["variables"][0]["min"], serde_json::Value::Null);
+}
+
+#[test]
+fn masks() {
+    let logs = "connect to 10.0.0.1 port 22 failed
+connect to 10.0.0.2 port 2222 failed
+";
+    let (success, stdout, _) = drain(&["--mask", "ADDR=%{IP} port %{INT}"], logs);
+    assert!(success);
+    assert_eq!(stdout, "2\t[0] connect to <ADDR> failed\n");
+
+    let (success, _, stderr) = drain(&["--mask", "%{IP}"], "");
+    assert!(!success);
+    assert!(stderr.contains("--mask"));
+    let (success, _, stderr) = drain(&["--mask=IP=%{NOT_A_PATTERN}"], "");
+    assert!(!success);
+    assert!(stderr.contains("masking rule"));
+}
@@ -35,14 +35,14 @@ fn dump_and_load() {
 
     assert_eq!(
         other
-            .log_group(&"10 [INFO] user 40 called 192.168.10.2")
+            .log_group("10 [INFO] user 40 called 192.168.10.2")
             .expect("missing expected log group")
             .as_string(),
         "user <user_id> called <ip_address>"
     );
     assert_eq!(
         other
-            .log_group(&"2 [INFO] something uninteresting happened")
+            .log_group("2 [INFO] something uninteresting happened")
             .expect("missing expected log group")
             .as_string(),
         "something uninteresting happened"
@@ -78,16 +78,261 @@ fn log_clustering() {
     }
     assert_eq!(
         drain
-            .log_group(&"10 [INFO] user 40 called 192.168.10.2")
+            .log_group("10 [INFO] user 40 called 192.168.10.2")
             .expect("missing expected log group")
             .as_string(),
         "user <user_id> called <ip_address>"
     );
     assert_eq!(
         drain
-            .log_group(&"2 [INFO] something uninteresting happened")
+            .log_group("2 [INFO] something uninteresting happened")
             .expect("missing expected log group")
             .as_string(),
         "something uninteresting happened"
     );
 }
+
+#[test]
+fn cluster_ids_survive_serialization() {
+    let mut drain = DrainTree::new().max_depth(2).min_similarity(0.5);
+
+    let login = drain.add_log_line_id("user login from 3").unwrap();
+    let logout = drain
+        .add_log_line_id("user logout requested by the system")
+        .unwrap();
+    assert_ne!(login, logout);
+    assert_eq!(drain.add_log_line_id("user login from 4"), Some(login));
+
+    let serialized = serde_json::to_string(&drain).expect("serialization failure");
+    let mut other: DrainTree = serde_json::from_str(serialized.as_str()).unwrap();
+
+    assert_eq!(
+        other.cluster_by_id(login).unwrap().as_string(),
+        "user login from <*>"
+    );
+    assert_eq!(other.add_log_line_id("user login from 5"), Some(login));
+    let new_id = other.add_log_line_id("disk is full").unwrap();
+    assert!(new_id != login && new_id != logout);
+    assert!(other.cluster_by_id(new_id + 1).is_none());
+}
+
+#[test]
+fn masked_constants_and_numeric_branches_survive_serialization() {
+    // the mask leaves the constant <NUM>, next to the branch of the tokens with a digit
+    let mut drain = DrainTree::new()
+        .max_depth(3)
+        .mask("#[0-9]+", "NUM")
+        .build_patterns(&mut grok::Grok::with_patterns());
+    let masked = drain.add_log_line_id("job #12 done").unwrap();
+    let numeric = drain.add_log_line_id("job a12 done").unwrap();
+    assert_ne!(masked, numeric);
+
+    let serialized = serde_json::to_string(&drain).expect("serialization failure");
+    let other: DrainTree = serde_json::from_str(serialized.as_str()).unwrap();
+    let mut other = other.build_patterns(&mut grok::Grok::with_patterns());
+    assert_eq!(other.add_log_line_id("job #13 done"), Some(masked));
+    assert_eq!(other.add_log_line_id("job b13 done"), Some(numeric));
+    assert_eq!(other.log_groups().len(), 2);
+}
+
+#[test]
+fn clusters_serialized_without_ids_get_ids() {
+    // a tree serialized before clusters had an identifier
+    let serialized = r#"{"root":{"2":{"Inner":{"children":{"disk":{"Inner":{"children":{
+        "full":{"Leaf":{"log_groups":[{"log_tokens":["disk","full"],"num_matched":1}]}},
+        "empty":{"Leaf":{"log_groups":[{"log_tokens":["disk","empty"],"num_matched":1}]}}},
+        "depth":1}}},"depth":0}}},"max_depth":4,"max_children":100,"min_similarity":0.5,
+        "overall_pattern_str":null,"drain_field":null,"filter_patterns_str":[]}"#;
+    let mut drain: DrainTree = serde_json::from_str(serialized).unwrap();
+
+    let mut ids = drain
+        .log_groups()
+        .iter()
+        .map(|c| c.id())
+        .collect::<Vec<_>>();
+    ids.sort_unstable();
+    assert_eq!(ids, vec![0, 1]);
+    let full = drain.log_group("disk full").unwrap().id();
+    assert_eq!(drain.add_log_line_id("disk full"), Some(full));
+    assert_eq!(drain.add_log_line_id("cpu is hot"), Some(2));
+}
+
+#[test]
+fn parse_variables() {
+    let logs = vec![
+        "1 [INFO] user 3 called 192.0.0.1",
+        "2 [INFO] user 2 called 127.0.0.1",
+        "3 [DEBUG] something uninteresting happened",
+    ];
+
+    let mut g = grok::Grok::with_patterns();
+    let mut drain = DrainTree::new()
+        .filter_patterns(vec!["%{IPV4:ip_address}", "%{NUMBER:user_id}"])
+        .max_depth(4)
+        .log_pattern(
+            "%{NUMBER:id} \\[%{LOGLEVEL:level}\\] %{GREEDYDATA:content}",
+            "content",
+        )
+        .build_patterns(&mut g);
+    for log in logs {
+        drain.add_log_line(log);
+    }
+
+    let parsed = drain
+        .parse("10 [INFO] user 40 called 192.168.10.2")
+        .expect("missing expected log group");
+    assert_eq!(parsed.template, "user <user_id> called <ip_address>");
+    assert_eq!(
+        parsed
+            .variables
+            .iter()
+            .map(|v| (v.position, v.name.as_deref(), v.value.as_str()))
+            .collect::<Vec<_>>(),
+        vec![
+            (1, Some("user_id"), "40"),
+            (3, Some("ip_address"), "192.168.10.2")
+        ]
+    );
+    assert_eq!(parsed.fields["id"], "10");
+    assert_eq!(parsed.fields["level"], "INFO");
+    assert!(drain.parse("4 [INFO] never seen before").is_none());
+}
+
+#[test]
+fn punctuation_tokenizer() {
+    let mut drain = DrainTree::new()
+        .max_depth(1)
+        .tokenizer(drain_rs::PunctuationTokenizer::default());
+
+    let id = drain.add_log_line_id("user=bob action=login").unwrap();
+    assert_eq!(drain.add_log_line_id("user=alice action=login"), Some(id));
+    assert_eq!(
+        drain.template(drain.cluster_by_id(id).unwrap()),
+        "user=<*> action=login"
+    );
+    let parsed = drain.parse("user=eve action=login").unwrap();
+    assert_eq!(parsed.variables[0].value, "eve");
+
+    let mut drain = DrainTree::new().tokenizer(drain_rs::WhitespaceTokenizer);
+    assert!(drain.add_log_line(" \t ").is_none());
+    assert!(drain.log_groups().is_empty());
+}
+
+#[test]
+fn generated_grok_matches_cluster_lines() {
+    use drain_rs::grok_generator::GrokGenerator;
+
+    let logs = [
+        "GET request from 10.0.0.1 for /index.html took 12 ms [cache=hit]",
+        "GET request from 10.0.0.2 for /about.html took 7 ms [cache=hit]",
+        "GET request from 192.168.1.20 for /index.html took 130 ms [cache=hit]",
+    ];
+    let mut drain = DrainTree::new().max_depth(2);
+    for log in logs.iter() {
+        drain.add_log_line(log);
+    }
+    assert_eq!(drain.cluster_count(), 1);
+    let cluster = drain.log_groups()[0];
+
+    let generator = GrokGenerator::new_with_base_patterns();
+    let pattern = cluster.detect_best_grok(&generator).unwrap();
+    assert_eq!(
+        pattern,
+        "^GET request from %{IP:ip} for %{PATH:path} took %{INT:int} ms \\[cache=hit\\]$"
+    );
+
+    let mut g = grok::Grok::with_patterns();
+    let mut reparsed = DrainTree::new()
+        .log_pattern(&pattern, "path")
+        .build_patterns(&mut g);
+    for log in logs.iter() {
+        reparsed.try_add_log_line(log).unwrap();
+    }
+    let parsed = reparsed.parse(logs[2]).unwrap();
+    assert_eq!(parsed.fields["ip"], "192.168.1.20");
+    assert_eq!(parsed.fields["int"], "130");
+}
+
+#[test]
+fn generated_grok_matches_filtered_and_masked_tokens() {
+    use drain_rs::grok_generator::GrokGenerator;
+
+    let logs = [
+        "peer 10.0.0.1 sent 0x1f to 10.0.0.9 as 123e4567-e89b-12d3-a456-426614174000",
+        "peer 10.0.0.2 sent 0x2a to 10.0.0.8 as 00112233-4455-6677-8899-aabbccddeeff",
+    ];
+    let mut g = grok::Grok::with_patterns();
+    let mut drain = DrainTree::new()
+        .filter_patterns(vec!["%{IPV4:ip_address}"])
+        .mask("%{UUID}", "UUID")
+        .mask("0x[0-9a-f]+", "HEX")
+        .build_patterns(&mut g);
+    for log in logs.iter() {
+        drain.add_log_line(log);
+    }
+    assert_eq!(drain.cluster_count(), 1);
+    let cluster = drain.log_groups()[0];
+    assert_eq!(
+        cluster.as_string(),
+        "peer <ip_address> sent <HEX> to <ip_address> as <UUID>"
+    );
+
+    let generator = GrokGenerator::new_with_base_patterns();
+    let pattern = drain.grok_pattern(cluster, &generator).unwrap();
+    assert_eq!(
+        pattern,
+        "^peer %{IPV4:ip_address} sent (?<hex>0x[0-9a-f]+) to %{IPV4:ip_address_2} as %{UUID:uuid}$"
+    );
+
+    let mut reparsed = DrainTree::new()
+        .log_pattern(&pattern, "uuid")
+        .build_patterns(&mut g);
+    for log in logs.iter() {
+        reparsed.try_add_log_line(log).unwrap();
+    }
+    let parsed = reparsed.parse(logs[1]).unwrap();
+    assert_eq!(parsed.fields["ip_address"], "10.0.0.2");
+    assert_eq!(parsed.fields["ip_address_2"], "10.0.0.8");
+}
+
+#[test]
+fn partition_by_log_pattern_fields() {
+    let logs = [
+        "081109 203615 148 INFO dfs.DataNode$PacketResponder: Received block blk_1 of size 67108864",
+        "081109 203807 222 INFO dfs.DataNode$PacketResponder: Received block blk_2 of size 3584",
+        "081109 204005 35 INFO dfs.FSNamesystem: Received block blk_3 of size 67108864",
+        "081109 204106 329 WARN dfs.DataNode$PacketResponder: Received block blk_4 of size 67108864",
+    ];
+    let mut g = grok::Grok::with_patterns();
+    let mut drain = DrainTree::new()
+        .log_pattern(
+            "%{NUMBER:date} %{NUMBER:time} %{NUMBER:proc} %{LOGLEVEL:level} %{DATA:component}: %{GREEDYDATA:content}",
+            "content",
+        )
+        .partition_fields(vec!["component", "level"])
+        .build_patterns(&mut g);
+    let ids = logs
+        .iter()
+        .map(|log| drain.add_log_line_id(log).unwrap())
+        .collect::<Vec<_>>();
+    // same shape, but different components or levels
+    assert_eq!(ids[0], ids[1]);
+    assert_ne!(ids[0], ids[2]);
+    assert_ne!(ids[0], ids[3]);
+    assert_eq!(drain.cluster_count(), 3);
+
+    let cluster = drain.cluster_by_id(ids[2]).unwrap();
+    assert_eq!(cluster.partition()["component"], "dfs.FSNamesystem");
+    assert_eq!(cluster.partition()["level"], "INFO");
+    assert_eq!(cluster.as_string(), "Received block blk_3 of size 67108864");
+    assert_eq!(
+        drain.cluster_by_id(ids[0]).unwrap().as_string(),
+        "Received block <*> of size <*>"
+    );
+    assert!(drain.cluster_by_id(ids[0]).unwrap().metadata().is_empty());
+
+    let parsed = drain
+        .parse("081110 101010 7 WARN dfs.DataNode$PacketResponder: Received block blk_9 of size 1")
+        .unwrap();
+    assert_eq!(parsed.cluster.id(), ids[3]);
+}